@@ -34,10 +34,89 @@ in {
         example = literalExpression ''5'';
         description = lib.mdDoc ''The number of generations to keep when rebuilding the system. Leaving as null or setting to 0 will keep all generations.'';
       };
+      database = mkOption {
+        type = with types; nullOr str;
+        default = null;
+        example = literalExpression ''"file:///srv/nix-data/database"'';
+        description = lib.mdDoc ''Where package databases are fetched from. Accepts an HTTP(S) URL, a `file://` URL or a local directory mirroring `xinux-org/database`. Leaving as null uses the upstream repository.'';
+      };
+      registry = mkOption {
+        type = with types; nullOr str;
+        default = null;
+        example = literalExpression ''"https://mirror.example.org/registry/data"'';
+        description = lib.mdDoc ''Where per-revision package snapshots are fetched from. Accepts an HTTP(S) URL, a `file://` URL or a local directory mirroring `xinux-org/registry/data`. Leaving as null uses the upstream repository.'';
+      };
+      trustedkeys = mkOption {
+        type = with types; nullOr (listOf str);
+        default = null;
+        example = literalExpression ''[ "RWQf6LRCGA9i53mlYecO4IzT51TGPpvWucNSCh1CBM0QTaLn73Y7GFO3" ]'';
+        description = lib.mdDoc ''Minisign public keys trusted to sign registry manifests. When set, package databases are only installed if their checksum matches a manifest signed by one of these keys.'';
+      };
+      retention = mkOption {
+        type = with types;
+          nullOr (submodule {
+            options = {
+              keeprevisions = mkOption {
+                type = nullOr int;
+                default = null;
+                description = lib.mdDoc ''The number of nixpkgs revisions to keep in the cache, newest first. Leaving as null keeps only the revisions in use.'';
+              };
+              maxbytes = mkOption {
+                type = nullOr int;
+                default = null;
+                description = lib.mdDoc ''The maximum total size of the cache in bytes. The oldest unused databases are removed first.'';
+              };
+              maxage = mkOption {
+                type = nullOr int;
+                default = null;
+                description = lib.mdDoc ''The maximum age of unused databases in days.'';
+              };
+            };
+          });
+        default = null;
+        example = literalExpression ''{ keeprevisions = 2; maxage = 30; }'';
+        description = lib.mdDoc ''Which cached databases are kept. Databases in use are never removed.'';
+      };
+      offline = mkOption {
+        type = with types; nullOr bool;
+        default = null;
+        example = literalExpression ''true'';
+        description = lib.mdDoc ''Whether programs using nix-data should only use cached databases and never access the network.'';
+      };
+      http = mkOption {
+        type = with types;
+          nullOr (submodule {
+            options = {
+              connecttimeout = mkOption {
+                type = nullOr int;
+                default = null;
+                description = lib.mdDoc ''Seconds to wait for a connection to be established. Leaving as null uses 30.'';
+              };
+              timeout = mkOption {
+                type = nullOr int;
+                default = null;
+                description = lib.mdDoc ''Seconds to wait for data before a download is aborted. Leaving as null uses 60.'';
+              };
+              retries = mkOption {
+                type = nullOr int;
+                default = null;
+                description = lib.mdDoc ''How often a request is retried on connection errors, timeouts and server errors. Leaving as null uses 3.'';
+              };
+              proxy = mkOption {
+                type = nullOr str;
+                default = null;
+                description = lib.mdDoc ''Proxy used for all requests. Leaving as null honours the `HTTP_PROXY`, `HTTPS_PROXY` and `NO_PROXY` environment variables.'';
+              };
+            };
+          });
+        default = null;
+        example = literalExpression ''{ timeout = 120; proxy = "http://proxy.example.org:3128"; }'';
+        description = lib.mdDoc ''Timeouts, retries and proxy used for downloads.'';
+      };
     };
   };
 
   config = mkIf cfg.enable {
-    environment.etc."nix-data/config.json".source = jsonFormat.generate "config.json" {inherit (cfg) systemconfig flake flakearg generations;};
+    environment.etc."nix-data/config.json".source = jsonFormat.generate "config.json" {inherit (cfg) systemconfig flake flakearg generations database registry trustedkeys retention offline http;};
   };
 }
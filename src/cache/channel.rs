@@ -1,59 +1,65 @@
-use crate::CACHEDIR;
+use crate::{
+    packages::PackageDb,
+    registry::client::HttpClient,
+    system::{NixosVersion, hostplatform},
+};
 use anyhow::{Context, Result, anyhow};
-use log::info;
+use log::{debug, info};
 use serde::Deserialize;
-use sqlx::SqlitePool;
 use std::{
     collections::{HashMap, HashSet},
-    fs::{self, File},
-    io::{BufReader, Read, Write},
+    fs::{self},
+    io::{BufReader, Read},
     path::Path,
     process::Command,
+    time::SystemTime,
 };
 
 use super::{
-    NixPkgList,
+    CacheStore, Cached, NixPkgList, gc, install,
+    metadata::{self, ArtifactKind, ArtifactMeta},
     nixos::{self, getnixospkgs, nixospkgs},
 };
 
 /// Gets a list of all packages in legacy NixOS systems with their name and version.
 /// Can be used to find what versions of system packages are currently installed.
+/// In offline mode the cached database is returned, marked as stale if the system has been upgraded since.
 /// Will only work on legacy NixOS systems.
-pub async fn legacypkgs() -> Result<String> {
-    let versionout = Command::new("nixos-version").arg("--json").output()?;
-    let version: HashMap<String, String> = serde_json::from_slice(&versionout.stdout)?;
-
-    let nixosversion = version
-        .get("nixosVersion")
-        .context("No NixOS version found")?;
-    let relver = if nixosversion[5..8].eq("pre") {
-        "unstable"
-    } else {
-        &nixosversion[0..5]
-    };
+pub async fn legacypkgs(store: &CacheStore) -> Result<Cached<String>> {
+    let version = NixosVersion::detect()?;
+    let nixosversion = &version.version;
+    let relver = version.channel();
 
     // If cache directory doesn't exist, create it
-    if !std::path::Path::new(&*CACHEDIR).exists() {
-        std::fs::create_dir_all(&*CACHEDIR)?;
-    }
+    store.create()?;
+    // Wait for refreshes by other processes, then check what they left
+    let _lock = store.lock().await?;
 
     // Check if latest version is already downloaded
-    if let Ok(prevver) = fs::read_to_string(format!("{}/legacypkgs.ver", &*CACHEDIR))
-        && prevver.eq(nixosversion)
-        && Path::new(&format!("{}/legacypkgs.db", &*CACHEDIR)).exists()
-    {
+    let kind = ArtifactKind::LegacyPkgs;
+    let dbpath = store.file(&kind.file());
+    let uptodate = metadata::read(store)?
+        .artifact(kind)
+        .and_then(|meta| meta.systemversion.as_ref())
+        == Some(nixosversion);
+    if Path::new(&dbpath).exists() && (uptodate || store.offline()) {
         info!("No new version of NixOS legacy found");
-        return Ok(format!("{}/legacypkgs.db", &*CACHEDIR));
+        return store.cached(kind, dbpath, !uptodate);
+    }
+    if store.offline() {
+        return Err(store.notcached(&kind.file()));
     }
 
-    async fn downloadrelease(relver: &str, nixosversion: &str) -> Result<HashMap<String, String>> {
+    async fn downloadrelease(
+        client: &HttpClient,
+        relver: &str,
+        nixosversion: &str,
+    ) -> Result<(HashMap<String, String>, String)> {
         let url = format!(
             "https://releases.nixos.org/nixos/{}/nixos-{}/packages.json.br",
             relver, nixosversion
         );
-        // Download file with reqwest
-        let client = reqwest::Client::builder().brotli(true).build()?;
-        let resp = client.get(url).send().await;
+        let resp = client.send(&url).await;
         let resp = if let Ok(r) = resp {
             r
         } else {
@@ -67,67 +73,85 @@ pub async fn legacypkgs() -> Result<String> {
                 .iter()
                 .map(|(k, v)| (k.to_string(), v.version.to_string()))
                 .collect::<HashMap<String, String>>();
-            Ok(pkgout)
+            Ok((pkgout, url))
         } else {
             Err(anyhow!("Failed to download legacy packages.json"))
         }
     }
 
     // Get list of packages
-    let pkgout = if let Some(rev) = version.get("nixpkgsRevision") {
-        let url = format!(
-            // https://raw.githubusercontent.com/xinux-org/registry/refs/heads/main/data/nixos-{}/{}.json.br
-            "https://raw.githubusercontent.com/xinux-org/registry/main/data/nixos-{}/{}.json.br",
-            relver, rev
-        );
-        println!("{}", url);
-        let resp = reqwest::get(&url).await?;
-        if resp.status().is_success() {
-            let r = resp.bytes().await?;
-            println!("Downloaded");
-            let mut br = brotli::Decompressor::new(r.as_ref(), 4096);
-            let mut pkgsout = Vec::new();
-            br.read_to_end(&mut pkgsout)?;
-            let pkgsjson: HashMap<String, String> = serde_json::from_slice(&pkgsout)?;
-            println!("Decompressed");
-            pkgsjson
+    let client = store.http()?;
+    let (pkgout, url) = if let Some(rev) = &version.revision {
+        if let Some((pkgs, _, url)) = fetchsnapshot(store, &client, relver, rev).await? {
+            (pkgs, url)
         } else {
-            let url = format!(
-                "https://raw.githubusercontent.com/xinux-org/registry/main/data/nixos-unstable/{}.json.br",
-                rev
-            );
-            println!("{}", url);
-            let resp = reqwest::get(&url).await?;
-            if resp.status().is_success() {
-                let r = resp.bytes().await?;
-                println!("Downloaded");
-                let mut br = brotli::Decompressor::new(r.as_ref(), 4096);
-                let mut pkgsout = Vec::new();
-                br.read_to_end(&mut pkgsout)?;
-                let pkgsjson: HashMap<String, String> = serde_json::from_slice(&pkgsout)?;
-                println!("Decompressed");
-                pkgsjson
-            } else {
-                downloadrelease(relver, nixosversion).await?
-            }
+            downloadrelease(&client, relver, nixosversion).await?
         }
     } else {
-        downloadrelease(relver, nixosversion).await?
+        downloadrelease(&client, relver, nixosversion).await?
     };
-    let dbfile = format!("{}/legacypkgs.db", &*CACHEDIR);
+    let dbfile = install::tempfile(store)?;
 
-    nixos::createdb(&dbfile, &pkgout).await?;
+    nixos::createdb(&dbfile.path().to_string_lossy(), &pkgout).await?;
 
-    // Write version downloaded to file
-    File::create(format!("{}/legacypkgs.ver", &*CACHEDIR))?.write_all(nixosversion.as_bytes())?;
+    // Replace the cached database, then record the version downloaded
+    let sha256 = install::installdb(store, dbfile, install::LEGACY_TABLES, &dbpath).await?;
+    metadata::update(store, |metadata| {
+        metadata.artifacts.insert(
+            kind,
+            ArtifactMeta {
+                kind,
+                revision: version.revision.clone(),
+                systemversion: Some(nixosversion.to_string()),
+                release: Some(relver.to_string()),
+                source: Some(url),
+                downloaded: SystemTime::now(),
+                sha256: Some(sha256),
+            },
+        );
+    })?;
+    gc::afterrefresh(store);
+
+    store.cached(kind, dbpath, false)
+}
 
-    Ok(format!("{}/legacypkgs.db", &*CACHEDIR))
+/// Fetches the versions of all packages in `revision` from `nixos-<release>/<revision>.json.br` in the registry,
+/// or from `nixos-unstable` if the release has no such snapshot.
+/// The snapshot is verified against the signed manifest if trusted keys are configured.
+/// Returns them keyed by attribute along with the release and location they were found at,
+/// or `None` if neither has the snapshot.
+pub(super) async fn fetchsnapshot(
+    store: &CacheStore,
+    client: &HttpClient,
+    release: &str,
+    revision: &str,
+) -> Result<Option<(HashMap<String, String>, String, String)>> {
+    let source = store.registry();
+    let mut releases = vec![release, "unstable"];
+    releases.dedup();
+    for release in releases {
+        let path = format!("nixos-{}/{}.json.br", release, revision);
+        if let Some(data) = source
+            .fetch_verified(client, &path, &store.trustedkeys()?, None)
+            .await?
+        {
+            debug!("Downloaded {}", source.url(&path));
+            let mut json = Vec::new();
+            brotli::Decompressor::new(data.as_slice(), 4096)
+                .read_to_end(&mut json)
+                .context("Failed to decompress brotli data")?;
+            let pkgs: HashMap<String, String> = serde_json::from_slice(&json)
+                .with_context(|| format!("Invalid snapshot {}", source.url(&path)))?;
+            return Ok(Some((pkgs, release.to_string(), source.url(&path))));
+        }
+    }
+    Ok(None)
 }
 
 /// Gets a list of all packages in NixOS systems with their attribute and version.
 /// The input `paths` should be the paths to the `configuration.nix` files containing `environment.systemPackages`
-pub async fn getlegacypkgs(paths: &[&str]) -> Result<HashMap<String, String>> {
-    getnixospkgs(paths, nixos::NixosType::Legacy).await
+pub async fn getlegacypkgs(store: &CacheStore, paths: &[&str]) -> Result<HashMap<String, String>> {
+    getnixospkgs(store, paths, nixos::NixosType::Legacy).await
 }
 
 #[derive(Debug, Deserialize)]
@@ -149,17 +173,31 @@ pub fn getenvpkgs() -> Result<HashMap<String, String>> {
     Ok(out)
 }
 
-pub fn uptodate() -> Result<Option<(String, String)>> {
-    let legacyver = fs::read_to_string(format!("{}/legacypkgs.ver", &*CACHEDIR))?;
-    let nixosver = fs::read_to_string(format!("{}/nixospkgs.ver", &*CACHEDIR))?;
-    if !nixosver.eq(&legacyver) {
+/// Compares the system version `legacypkgs.db` was refreshed for with the revision of `nixospkgs.db`.
+/// Returns both if the system is behind the latest revision.
+pub fn uptodate(store: &CacheStore) -> Result<Option<(String, String)>> {
+    let metadata = metadata::read(store)?;
+    let legacyver = metadata
+        .artifact(ArtifactKind::LegacyPkgs)
+        .and_then(|meta| meta.systemversion.clone())
+        .context("legacypkgs.db has not been refreshed")?;
+    let nixosver = metadata
+        .artifact(ArtifactKind::NixosPkgs)
+        .and_then(|meta| meta.revision.clone())
+        .context("nixospkgs.db has not been refreshed")?;
+    let legacylast = metadata::commit(&legacyver).context("Invalid version")?;
+    let nixoslast = metadata::commit(&nixosver).context("Invalid version")?;
+    if !nixoslast.starts_with(legacylast) {
         Ok(Some((legacyver, nixosver)))
     } else {
         Ok(None)
     }
 }
 
-pub async fn unavailablepkgs(paths: &[&str]) -> Result<HashMap<String, String>> {
+pub async fn unavailablepkgs(
+    store: &CacheStore,
+    paths: &[&str],
+) -> Result<HashMap<String, String>> {
     let aliases = Command::new("nix-instantiate")
         .arg("--eval")
         .arg("-E")
@@ -204,25 +242,30 @@ pub async fn unavailablepkgs(paths: &[&str]) -> Result<HashMap<String, String>>
         }
     }
 
-    let legacypkgs = getlegacypkgs(paths).await?;
-    let nixospkgs = nixospkgs().await?;
-    let pool = SqlitePool::connect(&format!("sqlite://{}", nixospkgs)).await?;
+    let legacypkgs = getlegacypkgs(store, paths).await?;
+    let nixospkgs = nixospkgs(store).await?;
+    let db = PackageDb::open(&nixospkgs.data).await?;
 
+    let platform = hostplatform();
+    let metas = db.meta_many(&legacypkgs.keys().collect::<Vec<_>>()).await?;
     for (pkg, _) in legacypkgs {
-        let (x, broken, insecure): (String, u8, u8) =
-            sqlx::query_as("SELECT attribute,broken,insecure FROM meta WHERE attribute = $1")
-                .bind(&pkg)
-                .fetch_one(&pool)
-                .await?;
-        if x != pkg {
-            unavailable.insert(
-                pkg,
-                String::from("Package not found in newer version of nixpkgs"),
-            );
-        } else if broken == 1 {
-            unavailable.insert(pkg, String::from("Package is marked as broken"));
-        } else if insecure == 1 {
-            unavailable.insert(pkg, String::from("Package is marked as insecure"));
+        match metas.get(&pkg) {
+            None => {
+                unavailable.insert(
+                    pkg,
+                    String::from("Package not found in newer version of nixpkgs"),
+                );
+            }
+            Some(meta) if meta.broken => {
+                unavailable.insert(pkg, String::from("Package is marked as broken"));
+            }
+            Some(meta) if meta.insecure => {
+                unavailable.insert(pkg, String::from("Package is marked as insecure"));
+            }
+            Some(meta) if !meta.availableon(&platform) => {
+                unavailable.insert(pkg, format!("Package is not available on {}", platform));
+            }
+            Some(_) => {}
         }
     }
     Ok(unavailable)
@@ -0,0 +1,556 @@
+use anyhow::{Context, Result, anyhow};
+use log::debug;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use sqlx::{
+    Connection,
+    sqlite::{SqliteArguments, SqliteConnectOptions, SqliteConnection},
+};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fs,
+    io::Read,
+};
+use tempfile::NamedTempFile;
+
+use crate::registry::manifest::Manifest;
+
+use super::{CacheStore, Phase, install};
+
+/// Longest chain of deltas applied before a full download is preferred.
+const MAX_CHAIN: usize = 32;
+
+/// `deltas/index.json`, listing the deltas published for a release directory.
+#[derive(Debug, Deserialize)]
+struct DeltaIndex {
+    deltas: Vec<DeltaRef>,
+}
+
+/// A single delta between two database revisions.
+#[derive(Debug, Deserialize, Clone)]
+struct DeltaRef {
+    from: String,
+    to: String,
+    /// Brotli compressed [Delta], relative to the `deltas` directory.
+    file: String,
+}
+
+/// Row-level changes turning the database at revision `from` into revision `to`.
+///
+/// ```json
+/// {
+///   "from": "25.11.1234.abcdef",
+///   "to": "25.11.1240.123456",
+///   "tables": {
+///     "pkgs": {
+///       "key": "attribute",
+///       "delete": ["oldpkg"],
+///       "upsert": [{ "attribute": "hello", "pname": "hello", "version": "2.13" }]
+///     }
+///   }
+/// }
+/// ```
+#[derive(Debug, Deserialize)]
+struct Delta {
+    from: String,
+    to: String,
+    tables: HashMap<String, TableDelta>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TableDelta {
+    key: String,
+    #[serde(default)]
+    delete: Vec<Value>,
+    #[serde(default)]
+    upsert: Vec<Map<String, Value>>,
+}
+
+/// Tries to bring `basefile`, the cached database at revision `from`, to revision `to`
+/// by applying the deltas published in `<dir>/deltas` of the database source.
+///
+/// The result is written to a temporary file, `basefile` itself is never modified.
+/// Every delta has to be between the revisions the index lists it for, and if trusted keys are configured,
+/// the signed manifest of `dir` has to be of revision `to`, as for a full download.
+/// Returns `None` if no chain of deltas leads from `from` to `to`.
+pub(super) async fn applydeltas(
+    store: &CacheStore,
+    dir: &str,
+    basefile: &str,
+    from: &str,
+    to: &str,
+) -> Result<Option<NamedTempFile>> {
+    let source = store.database();
+    let client = store.http()?;
+    let keys = store.trustedkeys()?;
+    let Some(index) = source
+        .fetch_verified(&client, &format!("{}/deltas/index.json", dir), &keys, None)
+        .await?
+    else {
+        debug!("No deltas published for {}", dir);
+        return Ok(None);
+    };
+    let index: DeltaIndex = serde_json::from_slice(&index)?;
+    let Some(chain) = findchain(&index.deltas, from, to) else {
+        debug!("No delta chain from {} to {} in {}", from, to, dir);
+        return Ok(None);
+    };
+    debug!("Applying {} deltas from {} to {}", chain.len(), from, to);
+
+    let out = install::tempfile(store)?;
+    fs::copy(basefile, out.path()).context("Failed to copy cached database")?;
+    let options = SqliteConnectOptions::new().filename(out.path());
+    let mut conn = SqliteConnection::connect_with(&options).await?;
+    for deltaref in chain {
+        let path = format!("{}/deltas/{}", dir, deltaref.file);
+        store.report(&path, Phase::Patching, 0, None);
+        let data = source
+            .fetch_verified(&client, &path, &keys, None)
+            .await?
+            .with_context(|| format!("Delta {} is listed but not published", deltaref.file))?;
+        let mut json = Vec::new();
+        brotli::Decompressor::new(data.as_slice(), 4096)
+            .read_to_end(&mut json)
+            .context("Failed to decompress brotli data")?;
+        let delta: Delta = serde_json::from_slice(&json)?;
+        // A delta signed for other revisions must not be applied to this base
+        if delta.from != deltaref.from || delta.to != deltaref.to {
+            return Err(anyhow!(
+                "Delta {} is from {} to {}, but listed from {} to {}",
+                deltaref.file,
+                delta.from,
+                delta.to,
+                deltaref.from,
+                deltaref.to
+            ));
+        }
+        applydelta(&mut conn, &delta).await?;
+    }
+    conn.close().await?;
+
+    // The patched database takes the place of a full download, which has to be of the signed revision
+    if !keys.is_empty() {
+        Manifest::fetch(&client, &source, dir, &keys)
+            .await?
+            .verify_revision(to)
+            .with_context(|| format!("Refusing to apply deltas of {} to {}", dir, to))?;
+        debug!("Verified revision {} of {}", to, dir);
+    }
+    Ok(Some(out))
+}
+
+/// Finds the shortest chain of deltas leading from `from` to `to`.
+fn findchain(deltas: &[DeltaRef], from: &str, to: &str) -> Option<Vec<DeltaRef>> {
+    let mut prev: HashMap<&str, &DeltaRef> = HashMap::new();
+    let mut seen = HashSet::from([from]);
+    let mut queue = VecDeque::from([(from, 0)]);
+    while let Some((rev, depth)) = queue.pop_front() {
+        if rev == to {
+            let mut chain = Vec::new();
+            let mut cur = to;
+            while cur != from {
+                let delta = prev[cur];
+                chain.push(delta.clone());
+                cur = &delta.from;
+            }
+            chain.reverse();
+            return Some(chain);
+        }
+        if depth == MAX_CHAIN {
+            continue;
+        }
+        for delta in deltas.iter().filter(|d| d.from == rev) {
+            if seen.insert(&delta.to) {
+                prev.insert(&delta.to, delta);
+                queue.push_back((&delta.to, depth + 1));
+            }
+        }
+    }
+    None
+}
+
+/// Applies a single delta inside one transaction.
+async fn applydelta(conn: &mut SqliteConnection, delta: &Delta) -> Result<()> {
+    debug!("Applying delta {} -> {}", delta.from, delta.to);
+    let mut tx = conn.begin().await?;
+    for (table, changes) in &delta.tables {
+        let columns: Vec<(String,)> = sqlx::query_as("SELECT name FROM pragma_table_info($1)")
+            .bind(table)
+            .fetch_all(&mut *tx)
+            .await?;
+        let columns: HashSet<String> = columns.into_iter().map(|(name,)| name).collect();
+        if columns.is_empty() {
+            return Err(anyhow!("Delta refers to unknown table `{}`", table));
+        }
+        if !columns.contains(&changes.key) {
+            return Err(anyhow!(
+                "Delta refers to unknown column `{}.{}`",
+                table,
+                changes.key
+            ));
+        }
+
+        let delete = format!(
+            "DELETE FROM {} WHERE {} = $1",
+            quote(table),
+            quote(&changes.key)
+        );
+        for key in &changes.delete {
+            let mut args = SqliteArguments::default();
+            bindvalue(&mut args, key)?;
+            sqlx::query_with(&delete, args).execute(&mut *tx).await?;
+        }
+
+        for row in &changes.upsert {
+            if let Some(column) = row.keys().find(|column| !columns.contains(*column)) {
+                return Err(anyhow!(
+                    "Delta refers to unknown column `{}.{}`",
+                    table,
+                    column
+                ));
+            }
+            let names = row.keys().map(|c| quote(c)).collect::<Vec<_>>();
+            let params = (1..=row.len())
+                .map(|i| format!("${}", i))
+                .collect::<Vec<_>>();
+            let upsert = format!(
+                "INSERT OR REPLACE INTO {} ({}) VALUES ({})",
+                quote(table),
+                names.join(", "),
+                params.join(", ")
+            );
+            let mut args = SqliteArguments::default();
+            for value in row.values() {
+                bindvalue(&mut args, value)?;
+            }
+            sqlx::query_with(&upsert, args).execute(&mut *tx).await?;
+        }
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Quotes an SQL identifier.
+fn quote(ident: &str) -> String {
+    format!("\"{}\"", ident.replace('"', "\"\""))
+}
+
+/// Binds a JSON value as the closest SQLite type.
+/// Arrays and objects are stored as JSON text, like in the published databases.
+fn bindvalue<'q>(args: &mut SqliteArguments<'q>, value: &Value) -> Result<()> {
+    use sqlx::Arguments;
+    match value {
+        Value::Null => args.add(None::<String>),
+        Value::Bool(b) => args.add(*b as i64),
+        Value::Number(n) => match n.as_i64() {
+            Some(i) => args.add(i),
+            None => args.add(n.as_f64()),
+        },
+        Value::String(s) => args.add(s.clone()),
+        other => args.add(other.to_string()),
+    }
+    .map_err(|e| anyhow!("Failed to bind delta value: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn refs(pairs: &[(&str, &str)]) -> Vec<DeltaRef> {
+        pairs
+            .iter()
+            .map(|(from, to)| DeltaRef {
+                from: from.to_string(),
+                to: to.to_string(),
+                file: format!("{}-{}.json.br", from, to),
+            })
+            .collect()
+    }
+
+    fn files(chain: Option<Vec<DeltaRef>>) -> Option<Vec<String>> {
+        chain.map(|chain| chain.into_iter().map(|delta| delta.file).collect())
+    }
+
+    #[test]
+    fn findchain_follows_consecutive_deltas() {
+        let deltas = refs(&[("b", "c"), ("a", "b"), ("c", "d")]);
+        assert_eq!(
+            files(findchain(&deltas, "a", "d")),
+            Some(vec![
+                String::from("a-b.json.br"),
+                String::from("b-c.json.br"),
+                String::from("c-d.json.br"),
+            ])
+        );
+    }
+
+    #[test]
+    fn findchain_stops_at_target() {
+        let deltas = refs(&[("a", "b"), ("b", "c"), ("c", "d")]);
+        assert_eq!(
+            files(findchain(&deltas, "a", "c")),
+            Some(vec![
+                String::from("a-b.json.br"),
+                String::from("b-c.json.br")
+            ])
+        );
+    }
+
+    #[test]
+    fn findchain_prefers_shortest_chain() {
+        let deltas = refs(&[("a", "b"), ("b", "c"), ("a", "c")]);
+        assert_eq!(
+            files(findchain(&deltas, "a", "c")),
+            Some(vec![String::from("a-c.json.br")])
+        );
+    }
+
+    #[test]
+    fn findchain_rejects_gap() {
+        let deltas = refs(&[("a", "b"), ("c", "d")]);
+        assert_eq!(files(findchain(&deltas, "a", "d")), None);
+    }
+
+    #[test]
+    fn findchain_rejects_unknown_base() {
+        let deltas = refs(&[("a", "b"), ("b", "c")]);
+        assert_eq!(files(findchain(&deltas, "x", "c")), None);
+        assert_eq!(files(findchain(&[], "a", "b")), None);
+    }
+
+    #[test]
+    fn findchain_ignores_cycles_and_backwards_deltas() {
+        let deltas = refs(&[("a", "b"), ("b", "a"), ("b", "c")]);
+        assert_eq!(
+            files(findchain(&deltas, "a", "c")),
+            Some(vec![
+                String::from("a-b.json.br"),
+                String::from("b-c.json.br")
+            ])
+        );
+        assert_eq!(files(findchain(&deltas, "c", "a")), None);
+    }
+
+    #[test]
+    fn findchain_limits_chain_length() {
+        let revisions = (0..=MAX_CHAIN + 1)
+            .map(|i| i.to_string())
+            .collect::<Vec<_>>();
+        let pairs = revisions
+            .windows(2)
+            .map(|pair| (pair[0].as_str(), pair[1].as_str()))
+            .collect::<Vec<_>>();
+        let deltas = refs(&pairs);
+        let last = MAX_CHAIN.to_string();
+        assert_eq!(
+            findchain(&deltas, "0", &last).map(|chain| chain.len()),
+            Some(MAX_CHAIN)
+        );
+        let beyond = (MAX_CHAIN + 1).to_string();
+        assert!(findchain(&deltas, "0", &beyond).is_none());
+    }
+
+    async fn pkgsdb() -> Result<SqliteConnection> {
+        let mut conn = SqliteConnection::connect("sqlite::memory:").await?;
+        sqlx::query(
+            "CREATE TABLE pkgs (attribute TEXT PRIMARY KEY, pname TEXT, version TEXT, meta TEXT)",
+        )
+        .execute(&mut conn)
+        .await?;
+        for (attr, version) in [("hello", "2.12"), ("oldpkg", "1.0"), ("jq", "1.7")] {
+            sqlx::query("INSERT INTO pkgs (attribute, pname, version) VALUES ($1, $1, $2)")
+                .bind(attr)
+                .bind(version)
+                .execute(&mut conn)
+                .await?;
+        }
+        Ok(conn)
+    }
+
+    async fn versions(conn: &mut SqliteConnection) -> Result<Vec<(String, Option<String>)>> {
+        Ok(
+            sqlx::query_as("SELECT attribute, version FROM pkgs ORDER BY attribute")
+                .fetch_all(conn)
+                .await?,
+        )
+    }
+
+    fn delta(json: Value) -> Delta {
+        serde_json::from_value(json).expect("invalid delta")
+    }
+
+    #[tokio::test]
+    async fn applydelta_deletes_then_upserts() -> Result<()> {
+        let mut conn = pkgsdb().await?;
+        let change = delta(serde_json::json!({
+            "from": "a",
+            "to": "b",
+            "tables": {
+                "pkgs": {
+                    "key": "attribute",
+                    "delete": ["oldpkg", "hello"],
+                    "upsert": [
+                        { "attribute": "hello", "pname": "hello", "version": "2.13" },
+                        { "attribute": "ripgrep", "pname": "ripgrep", "version": "14.1",
+                          "meta": { "mainProgram": "rg" } }
+                    ]
+                }
+            }
+        }));
+        applydelta(&mut conn, &change).await?;
+        assert_eq!(
+            versions(&mut conn).await?,
+            vec![
+                (String::from("hello"), Some(String::from("2.13"))),
+                (String::from("jq"), Some(String::from("1.7"))),
+                (String::from("ripgrep"), Some(String::from("14.1"))),
+            ]
+        );
+        let (meta,): (String,) =
+            sqlx::query_as("SELECT meta FROM pkgs WHERE attribute = 'ripgrep'")
+                .fetch_one(&mut conn)
+                .await?;
+        assert_eq!(meta, r#"{"mainProgram":"rg"}"#);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn applydelta_rolls_back_on_unknown_column() -> Result<()> {
+        let mut conn = pkgsdb().await?;
+        let change = delta(serde_json::json!({
+            "from": "a",
+            "to": "b",
+            "tables": {
+                "pkgs": {
+                    "key": "attribute",
+                    "delete": ["jq"],
+                    "upsert": [{ "attribute": "hello", "nosuchcolumn": 1 }]
+                }
+            }
+        }));
+        assert!(applydelta(&mut conn, &change).await.is_err());
+        assert_eq!(versions(&mut conn).await?.len(), 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn applydelta_rejects_unknown_table() -> Result<()> {
+        let mut conn = pkgsdb().await?;
+        let change = delta(serde_json::json!({
+            "from": "a",
+            "to": "b",
+            "tables": { "nosuchtable": { "key": "attribute", "delete": ["hello"] } }
+        }));
+        assert!(applydelta(&mut conn, &change).await.is_err());
+        Ok(())
+    }
+
+    /// Publishes `deltas` as `(file, delta)` pairs in `nixos-25.11/deltas` of the source at `dir`,
+    /// listing each under the revisions in its file name.
+    fn publish(dir: &std::path::Path, deltas: &[(&str, Value)]) -> Result<()> {
+        use std::io::Write;
+
+        let deltadir = dir.join("nixos-25.11/deltas");
+        fs::create_dir_all(&deltadir)?;
+        let mut index = Vec::new();
+        for (file, delta) in deltas {
+            let (from, to) = file.split_once('-').context("invalid delta name")?;
+            let mut data = Vec::new();
+            {
+                let mut writer = brotli::CompressorWriter::new(&mut data, 4096, 5, 22);
+                writer.write_all(&serde_json::to_vec(delta)?)?;
+            }
+            fs::write(deltadir.join(file), data)?;
+            index.push(serde_json::json!({ "from": from, "to": to, "file": file }));
+        }
+        fs::write(
+            deltadir.join("index.json"),
+            serde_json::to_vec(&serde_json::json!({ "deltas": index }))?,
+        )?;
+        Ok(())
+    }
+
+    async fn applychain(deltas: &[(&str, Value)]) -> Result<Option<Vec<(String, Option<String>)>>> {
+        use crate::{config::configfile::NixDataConfig, registry::RegistrySource};
+
+        let source = tempfile::tempdir()?;
+        let cache = tempfile::tempdir()?;
+        publish(source.path(), deltas)?;
+        let store = CacheStore::new(cache.path())?.with_config(NixDataConfig {
+            database: Some(RegistrySource::Local(source.path().to_path_buf())),
+            ..Default::default()
+        });
+        let basefile = cache.path().join("base.db");
+        let options = SqliteConnectOptions::new()
+            .filename(&basefile)
+            .create_if_missing(true);
+        let mut conn = SqliteConnection::connect_with(&options).await?;
+        sqlx::query("CREATE TABLE pkgs (attribute TEXT PRIMARY KEY, version TEXT)")
+            .execute(&mut conn)
+            .await?;
+        sqlx::query("INSERT INTO pkgs VALUES ('hello', '2.12')")
+            .execute(&mut conn)
+            .await?;
+        conn.close().await?;
+
+        let Some(out) =
+            applydeltas(&store, "nixos-25.11", &basefile.to_string_lossy(), "a", "c").await?
+        else {
+            return Ok(None);
+        };
+        let options = SqliteConnectOptions::new().filename(out.path());
+        let mut conn = SqliteConnection::connect_with(&options).await?;
+        let rows = versions(&mut conn).await?;
+        conn.close().await?;
+        Ok(Some(rows))
+    }
+
+    fn upsert(from: &str, to: &str, version: &str) -> Value {
+        serde_json::json!({
+            "from": from,
+            "to": to,
+            "tables": {
+                "pkgs": { "key": "attribute", "upsert": [{ "attribute": "hello", "version": version }] }
+            }
+        })
+    }
+
+    #[tokio::test]
+    async fn applydeltas_follows_chain() -> Result<()> {
+        let rows = applychain(&[
+            ("a-b", upsert("a", "b", "2.13")),
+            ("b-c", upsert("b", "c", "2.14")),
+        ])
+        .await?;
+        assert_eq!(
+            rows,
+            Some(vec![(String::from("hello"), Some(String::from("2.14")))])
+        );
+        assert_eq!(
+            applychain(&[("a-b", upsert("a", "b", "2.13"))]).await?,
+            None
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn applydeltas_rejects_mislabeled_delta() -> Result<()> {
+        // Listed from b to c, but made for another base
+        let err = applychain(&[
+            ("a-b", upsert("a", "b", "2.13")),
+            ("b-c", upsert("x", "c", "2.14")),
+        ])
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("listed from b to c"), "{:#}", err);
+        assert!(
+            applychain(&[
+                ("a-b", upsert("a", "b", "2.13")),
+                ("b-c", upsert("b", "d", "2.14"))
+            ])
+            .await
+            .is_err()
+        );
+        Ok(())
+    }
+}
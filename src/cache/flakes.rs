@@ -1,134 +1,111 @@
-use crate::CACHEDIR;
+use crate::{
+    packages::PackageDb,
+    system::{NixosVersion, hostplatform},
+    utils::get_full_ver,
+};
 use anyhow::{Context, Result};
 use log::debug;
-use sqlx::SqlitePool;
 use std::{
     collections::{HashMap, HashSet},
-    fs::{self, File},
-    io::{Read, Write},
-    path::Path,
+    fs::{self},
     process::Command,
 };
 
 use super::{
+    CacheStore,
+    Cached,
+    install,
+    metadata::{self, ArtifactKind},
     nixos::{self, getnixospkgs, nixospkgs},
     // NixPkg,
 };
 
 /// Gets a list of all packages in the NixOS system with their name and version.
 /// Can be used to find what versions of system packages are currently installed.
+/// The database is kept in the revision store and `flakespkgs.db` in the cache directory links to it.
+/// In offline mode the cached database is returned, marked as stale if the system has been upgraded since.
 /// Will only work on NixOS systems.
-pub async fn flakespkgs() -> Result<String> {
+pub async fn flakespkgs(store: &CacheStore) -> Result<Cached<String>> {
     // If cache directory doesn't exist, create it
-    if !std::path::Path::new(&*CACHEDIR).exists() {
-        std::fs::create_dir_all(&*CACHEDIR)?;
-    }
-
-    // we will have internet before install something
-    // returns 2x.xx
-    let ver = std::process::Command::new("sh")
-        .arg("-c")
-        .arg(r"nixos-version | grep -oP '^\d+\.\d+'")
-        .output()
-        .expect("failed to get nixos-version");
-    let ver_string = String::from_utf8(ver.stdout)?;
+    store.create()?;
+    // Wait for refreshes by other processes, then check what they left
+    let _lock = store.lock().await?;
 
     // Check if system version is already downloaded
-    // update flakespkgs.ver
-    // Write SYSTEM nixos version and it will be used as
-    // an old system version on comparing nixospkgs.ver
-    let versionout = Command::new("nixos-version").arg("--json").output()?;
-    let version: HashMap<String, String> = serde_json::from_slice(&versionout.stdout)?;
-    let nixosversion = version
-        .get("nixosVersion")
-        .context("No NixOS version found")?;
-
-    if let Ok(prevver) = fs::read_to_string(format!("{}/flakespkgs.ver", &*CACHEDIR))
-        && prevver == nixosversion.clone()
-        && Path::new(&format!("{}/flakespkgs.db", &*CACHEDIR)).exists()
+    // The SYSTEM nixos version is recorded in the metadata index
+    // and compared with the revision of nixospkgs.db by uptodate()
+    let version = NixosVersion::detect()?;
+    let nixosversion = &version.version;
+
+    let kind = ArtifactKind::FlakesPkgs;
+    let prev = metadata::read(store)?.artifact(kind).cloned();
+    let uptodate = prev.as_ref().and_then(|meta| meta.systemversion.as_ref()) == Some(nixosversion);
+    if let Some(db) = store.view(kind.name())
+        && (uptodate || store.offline())
     {
         debug!("No new version of flakespkgs found");
-        return Ok(format!("{}/flakespkgs.db", &*CACHEDIR));
-    }
-    let mut url = format!(
-        "https://raw.githubusercontent.com/xinux-org/database/main/nixos-{}/nixpkgs.db.br",
-        ver_string.trim(),
-    );
-    // println!("{}", url);
-    let mut resp = reqwest::get(&url).await?;
-    let mut pkgsout: Vec<u8> = Vec::new();
-
-    if resp.status().is_success() {
-        debug!(
-            "response getting {:?} pkgs: {:?}",
-            ver_string.trim(),
-            resp.status()
-        );
-        let r = resp.bytes().await?;
-        // println!("Downloaded");
-        let mut br = brotli::Decompressor::new(r.as_ref(), 4096);
-
-        br.read_to_end(&mut pkgsout)
-            .context("Failed to decompress brotli data")?;
-        debug!("Decompressed");
-    } else {
-        url = "https://raw.githubusercontent.com/xinux-org/database/main/nixos-unstable/nixpkgs.db.br".to_string();
-        debug!("{}", url);
-        resp = reqwest::get(url).await?;
-        debug!("response getting latest unstable pkgs: {:?}", resp.status());
-        if resp.status().is_success() {
-            let r = resp.bytes().await?;
-            debug!("Downloaded");
-            let mut br = brotli::Decompressor::new(r.as_ref(), 4096);
-            br.read_to_end(&mut pkgsout)?;
-            debug!("Decompressed");
+        if let Some(revision) = prev.as_ref().and_then(|meta| meta.revision.as_deref()) {
+            install::indexrevision(store, revision).await?;
         }
+        return store.cached(kind, db, !uptodate);
+    }
+    if store.offline() {
+        return Err(store.notcached(&kind.file()));
     }
 
-    let dbfile = format!("{}/flakespkgs.db", &*CACHEDIR);
-    let mut out = File::create(&dbfile).context("Failed to create database file")?;
-    out.write_all(&pkgsout)
-        .context("Failed to write decompressed database to file")?;
-
-    debug!("Writing flakespkgs.ver your nixos system version");
-    File::create(format!("{}/flakespkgs.ver", &*CACHEDIR))?.write_all(nixosversion.as_bytes())?;
-
-    Ok(format!("{}/flakespkgs.db", &*CACHEDIR))
+    // Point flakespkgs.db at the latest revision of the release,
+    // shared with nixospkgs.db and nixpkgs.db if they resolve to the same one
+    let latestnixpkgsver = get_full_ver(store).await?;
+    let prevrev = prev.and_then(|meta| meta.revision);
+    let db = nixos::revisiondb(
+        store,
+        &version.release,
+        &latestnixpkgsver,
+        prevrev.as_deref(),
+    )
+    .await?;
+    install::linkview(store, kind, &latestnixpkgsver, Some(nixosversion))?;
+
+    store.cached(kind, db, false)
 }
 
 /// Returns a list of all installed system packages with their attribute and version
 /// The input `paths` should be the paths to the `configuration.nix` files containing `environment.systemPackages`
-pub async fn getflakepkgs(paths: &[&str]) -> Result<HashMap<String, String>> {
-    getnixospkgs(paths, nixos::NixosType::Flake).await
+pub async fn getflakepkgs(store: &CacheStore, paths: &[&str]) -> Result<HashMap<String, String>> {
+    getnixospkgs(store, paths, nixos::NixosType::Flake).await
 }
 
-pub fn uptodate() -> Result<Option<(String, String)>> {
+/// Compares the system version `flakespkgs.db` was refreshed for with the revision of `nixospkgs.db`.
+/// Returns both if the system is behind the latest revision.
+pub fn uptodate(store: &CacheStore) -> Result<Option<(String, String)>> {
     // returns old and new flake versions.
-    let flakesver = fs::read_to_string(format!("{}/flakespkgs.ver", &*CACHEDIR))?;
-    let nixosver = fs::read_to_string(format!("{}/nixospkgs.ver", &*CACHEDIR))?;
-    let flakeslast = flakesver
-        .split('.')
-        .collect::<Vec<_>>()
-        .last()
-        .context("Invalid version")?
-        .to_string();
-    let nixoslast = nixosver
-        .split('.')
-        .collect::<Vec<_>>()
-        .last()
-        .context("Invalid version")?
-        .to_string();
-    if !nixoslast.starts_with(&flakeslast) {
+    let metadata = metadata::read(store)?;
+    let flakesver = metadata
+        .artifact(ArtifactKind::FlakesPkgs)
+        .and_then(|meta| meta.systemversion.clone())
+        .context("flakespkgs.db has not been refreshed")?;
+    let nixosver = metadata
+        .artifact(ArtifactKind::NixosPkgs)
+        .and_then(|meta| meta.revision.clone())
+        .context("nixospkgs.db has not been refreshed")?;
+    let flakeslast = metadata::commit(&flakesver).context("Invalid version")?;
+    let nixoslast = metadata::commit(&nixosver).context("Invalid version")?;
+    if !nixoslast.starts_with(flakeslast) {
         Ok(Some((flakesver, nixosver)))
     } else {
         Ok(None)
     }
 }
 
-pub async fn unavailablepkgs(paths: &[&str]) -> Result<HashMap<String, String>> {
-    let versionout = Command::new("nixos-version").arg("--json").output()?;
-    let version: HashMap<String, String> = serde_json::from_slice(&versionout.stdout)?;
-    let nixpath = if let Some(rev) = version.get("nixpkgsRevision") {
+pub async fn unavailablepkgs(
+    store: &CacheStore,
+    paths: &[&str],
+) -> Result<HashMap<String, String>> {
+    // Flake references only accept full commit hashes
+    let revision = NixosVersion::detect()?
+        .revision
+        .filter(|rev| rev.len() == 40);
+    let nixpath = if let Some(rev) = revision {
         Command::new("nix")
             .arg("eval")
             .arg(format!("nixpkgs/{}#path", rev))
@@ -188,25 +165,32 @@ pub async fn unavailablepkgs(paths: &[&str]) -> Result<HashMap<String, String>>
         }
     }
 
-    let profilepkgs = getflakepkgs(paths).await?;
-    let nixospkgs = nixospkgs().await?;
-    let pool = SqlitePool::connect(&format!("sqlite://{}", nixospkgs)).await?;
+    let profilepkgs = getflakepkgs(store, paths).await?;
+    let nixospkgs = nixospkgs(store).await?;
+    let db = PackageDb::open(&nixospkgs.data).await?;
 
+    let platform = hostplatform();
+    let metas = db
+        .meta_many(&profilepkgs.keys().collect::<Vec<_>>())
+        .await?;
     for (pkg, _) in profilepkgs {
-        let (x, broken, insecure): (String, u8, u8) =
-            sqlx::query_as("SELECT attribute,broken,insecure FROM meta WHERE attribute = $1")
-                .bind(&pkg)
-                .fetch_one(&pool)
-                .await?;
-        if x != pkg {
-            unavailable.insert(
-                pkg,
-                String::from("Package not found in newer version of nixpkgs"),
-            );
-        } else if broken == 1 {
-            unavailable.insert(pkg, String::from("Package is marked as broken"));
-        } else if insecure == 1 {
-            unavailable.insert(pkg, String::from("Package is marked as insecure"));
+        match metas.get(&pkg) {
+            None => {
+                unavailable.insert(
+                    pkg,
+                    String::from("Package not found in newer version of nixpkgs"),
+                );
+            }
+            Some(meta) if meta.broken => {
+                unavailable.insert(pkg, String::from("Package is marked as broken"));
+            }
+            Some(meta) if meta.insecure => {
+                unavailable.insert(pkg, String::from("Package is marked as insecure"));
+            }
+            Some(meta) if !meta.availableon(&platform) => {
+                unavailable.insert(pkg, format!("Package is not available on {}", platform));
+            }
+            Some(_) => {}
         }
     }
     Ok(unavailable)
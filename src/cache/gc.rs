@@ -0,0 +1,248 @@
+use crate::config::configfile::RetentionPolicy;
+use anyhow::Result;
+use log::{debug, warn};
+use std::{
+    collections::HashSet,
+    fs,
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+use super::{
+    CacheStore,
+    metadata::{self, ArtifactKind, METADATA},
+    store::{LOCKFILE, REVISIONS},
+};
+
+/// Temporary files older than this are left over from an interrupted refresh.
+const STALE_TEMP: Duration = Duration::from_secs(60 * 60);
+
+/// Kind of a file in the cache directory.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum EntryKind {
+    /// A nixpkgs database in the revision store, shared by the views pointing to it.
+    Revision,
+    /// A symlink such as `nixospkgs.db` pointing to a [Revision](EntryKind::Revision).
+    View,
+    /// Any other cached file, such as `legacypkgs.db` or `nixosoptions.json`,
+    /// or one no longer known to the metadata index.
+    File,
+    /// A leftover of an interrupted download.
+    Temporary,
+}
+
+/// A file in the cache directory, as returned by [entries()].
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct CacheEntry {
+    /// Path of the file.
+    pub path: PathBuf,
+    /// Kind of the file.
+    pub kind: EntryKind,
+    /// Size in bytes. Views are symlinks and take up no space of their own.
+    pub size: u64,
+    /// Time the file was last written.
+    pub modified: SystemTime,
+    /// Revision of the data, if known: the revision of a database or the one a view points to,
+    /// or the one recorded in the metadata index for other files.
+    pub revision: Option<String>,
+    /// Artifact stored in this file, for views and other files known to the metadata index.
+    pub artifact: Option<ArtifactKind>,
+    /// Whether a view points to this entry, or for other files, whether the metadata index records
+    /// the artifact stored in it. Entries in use are never removed.
+    pub inuse: bool,
+}
+
+impl CacheEntry {
+    /// Time since the entry was last written.
+    pub fn age(&self) -> Duration {
+        self.modified.elapsed().unwrap_or_default()
+    }
+}
+
+/// Lists all files in the cache directory, newest first.
+pub fn entries(store: &CacheStore) -> Result<Vec<CacheEntry>> {
+    let mut out = Vec::new();
+    if !store.root().exists() {
+        return Ok(out);
+    }
+
+    let metadata = metadata::read(store)?;
+    let mut views = HashSet::new();
+    for file in fs::read_dir(store.root())? {
+        let file = file?;
+        let path = file.path();
+        let name = file.file_name().to_string_lossy().to_string();
+        let meta = file.metadata()?;
+        if meta.is_dir() || name == LOCKFILE || name == METADATA {
+            continue;
+        }
+        let artifact = ArtifactKind::ALL
+            .into_iter()
+            .find(|kind| kind.file() == name);
+        let recorded = artifact.and_then(|kind| metadata.artifact(kind));
+        let (kind, revision) = if name.starts_with(".download-") || name.ends_with(".link") {
+            (EntryKind::Temporary, None)
+        } else if meta.is_symlink() {
+            let view = name.strip_suffix(".db").unwrap_or(&name);
+            let revision = store.viewrevision(view);
+            if let Some(target) = store.view(view) {
+                views.insert(PathBuf::from(target));
+            }
+            (EntryKind::View, revision)
+        } else {
+            let revision = recorded.and_then(|meta| meta.revision.clone());
+            (EntryKind::File, revision)
+        };
+        out.push(CacheEntry {
+            path,
+            kind,
+            size: if meta.is_symlink() { 0 } else { meta.len() },
+            modified: meta.modified()?,
+            revision,
+            artifact,
+            // Recorded artifacts are opened directly, orphaned ones expire
+            inuse: kind == EntryKind::File && recorded.is_some(),
+        });
+    }
+
+    let revisions = store.root().join(REVISIONS);
+    if revisions.exists() {
+        for file in fs::read_dir(revisions)? {
+            let file = file?;
+            let path = file.path();
+            let meta = file.metadata()?;
+            // SQLite journals next to a database belong to it
+            if !meta.is_file() || path.extension().is_none_or(|ext| ext != "db") {
+                continue;
+            }
+            out.push(CacheEntry {
+                revision: path
+                    .file_stem()
+                    .map(|stem| stem.to_string_lossy().to_string()),
+                artifact: None,
+                inuse: views.contains(&path),
+                kind: EntryKind::Revision,
+                size: meta.len(),
+                modified: meta.modified()?,
+                path,
+            });
+        }
+    }
+
+    out.sort_by_key(|entry| std::cmp::Reverse(entry.modified));
+    Ok(out)
+}
+
+/// Applies the retention policy from the store's config, see [collectwith()].
+pub fn collect(store: &CacheStore) -> Result<Vec<CacheEntry>> {
+    let policy = store.config().retention.clone().unwrap_or_default();
+    collectwith(store, &policy)
+}
+
+/// Removes cached files not allowed by `policy` and returns the removed entries.
+/// Waits for refreshes in progress, as their new databases aren't in use yet.
+///
+/// Revisions beyond [keeprevisions](RetentionPolicy::keeprevisions) and unused databases older than
+/// [maxage](RetentionPolicy::maxage) are removed, then the oldest unused databases until the cache
+/// fits in [maxbytes](RetentionPolicy::maxbytes). Views, the databases views point to and files
+/// recorded in the metadata index are never removed. Removed files are also dropped from the metadata index.
+pub fn collectwith(store: &CacheStore, policy: &RetentionPolicy) -> Result<Vec<CacheEntry>> {
+    let _lock = store.lockblocking()?;
+    collectlocked(store, policy)
+}
+
+/// Applies the retention policy after a refresh, logging instead of failing.
+/// Called with the cache lock already held.
+pub(super) fn afterrefresh(store: &CacheStore) {
+    let policy = store.config().retention.clone().unwrap_or_default();
+    if let Err(e) = collectlocked(store, &policy) {
+        warn!("Failed to clean up cache: {:#}", e);
+    }
+}
+
+fn collectlocked(store: &CacheStore, policy: &RetentionPolicy) -> Result<Vec<CacheEntry>> {
+    let entries = entries(store)?;
+    let maxage = policy
+        .maxage
+        .map(|days| Duration::from_secs(days * 24 * 60 * 60));
+
+    let mut remove = HashSet::new();
+    let mut revisions = 0;
+    for (i, entry) in entries.iter().enumerate() {
+        let expired = maxage.is_some_and(|maxage| entry.age() > maxage);
+        let drop = match entry.kind {
+            EntryKind::Temporary => entry.age() > STALE_TEMP,
+            EntryKind::Revision => {
+                revisions += 1;
+                !entry.inuse && (revisions > policy.keeprevisions.unwrap_or(0) || expired)
+            }
+            EntryKind::File => !entry.inuse && expired,
+            EntryKind::View => false,
+        };
+        if drop {
+            remove.insert(i);
+        }
+    }
+
+    if let Some(maxbytes) = policy.maxbytes {
+        let mut total: u64 = entries
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| !remove.contains(i))
+            .map(|(_, entry)| entry.size)
+            .sum();
+        // Entries are sorted newest first
+        for (i, entry) in entries.iter().enumerate().rev() {
+            if total <= maxbytes {
+                break;
+            }
+            let candidate = match entry.kind {
+                EntryKind::Revision | EntryKind::File => !entry.inuse,
+                _ => false,
+            };
+            if candidate && remove.insert(i) {
+                total = total.saturating_sub(entry.size);
+            }
+        }
+        if total > maxbytes {
+            debug!(
+                "Cache is {} bytes after cleanup, above the limit of {} bytes",
+                total, maxbytes
+            );
+        }
+    }
+
+    let mut removed = Vec::new();
+    for (i, entry) in entries.into_iter().enumerate() {
+        if !remove.contains(&i) {
+            continue;
+        }
+        match fs::remove_file(&entry.path) {
+            Ok(()) => {
+                debug!("Removed {}", entry.path.display());
+                removed.push(entry);
+            }
+            Err(e) => warn!("Failed to remove {}: {}", entry.path.display(), e),
+        }
+    }
+
+    // Forget what was removed, so it is downloaded again when needed
+    let forget = removed
+        .iter()
+        .any(|entry| entry.artifact.is_some() || entry.kind == EntryKind::Revision);
+    if forget {
+        metadata::update(store, |metadata| {
+            for entry in &removed {
+                if let Some(kind) = entry.artifact {
+                    metadata.artifacts.remove(&kind);
+                }
+                if entry.kind == EntryKind::Revision
+                    && let Some(revision) = &entry.revision
+                {
+                    metadata.revisions.remove(revision);
+                }
+            }
+        })?;
+    }
+    Ok(removed)
+}
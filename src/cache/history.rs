@@ -0,0 +1,150 @@
+use crate::history;
+use anyhow::{Result, anyhow};
+use log::debug;
+use std::{fs, path::Path, time::SystemTime};
+
+use super::{
+    CacheStore, Cached, channel, gc, install,
+    metadata::{self, ArtifactKind, ArtifactMeta},
+};
+
+/// Adds the package snapshots of `revisions` of `release` to the history store and returns its path,
+/// to be opened with [HistoryDb](crate::history::HistoryDb).
+///
+/// `revisions` are pairs of a nixpkgs commit hash and its date as `YYYYMMDD`, such as the commit date,
+/// by which the store orders them. Revisions already in the store are skipped, the others are downloaded from
+/// `nixos-<release>/<revision>.json.br` in the registry, falling back to `nixos-unstable`.
+/// In offline mode the store is returned as is, marked as stale.
+pub async fn historydb(
+    store: &CacheStore,
+    release: &str,
+    revisions: &[(&str, &str)],
+) -> Result<Cached<String>> {
+    let kind = ArtifactKind::HistoryDb;
+    let dbfile = store.file(&kind.file());
+    if store.offline() {
+        if !Path::new(&dbfile).exists() {
+            return Err(store.notcached(&kind.file()));
+        }
+        return store.cached(kind, dbfile, true);
+    }
+
+    // If cache directory doesn't exist, create it
+    store.create()?;
+    // Wait for refreshes by other processes, then check what they left
+    let _lock = store.lock().await?;
+
+    let mut ingested = history::ingestedrevisions(Path::new(&dbfile)).await;
+    let missing = revisions
+        .iter()
+        .filter(|(revision, _)| ingested.insert(revision.to_string()))
+        .collect::<Vec<_>>();
+    if missing.is_empty() {
+        debug!("All revisions are already in {}", kind.file());
+        return store.cached(kind, dbfile, false);
+    }
+
+    let client = store.http()?;
+    let out = install::tempfile(store)?;
+    if Path::new(&dbfile).exists() {
+        fs::copy(&dbfile, out.path())?;
+    }
+    let mut source = None;
+    for (revision, date) in &missing {
+        let (pkgs, found, url) = channel::fetchsnapshot(store, &client, release, revision)
+            .await?
+            .ok_or_else(|| {
+                anyhow!(
+                    "No snapshot of revision {} published in {}",
+                    revision,
+                    store.registry().url(&format!("nixos-{}", release))
+                )
+            })?;
+        history::ingest(out.path(), &found, revision, date, &pkgs).await?;
+        source = Some(url);
+    }
+    // Older revisions may have been backfilled, so the newest one is looked up among all of them
+    let latest = history::latestrevision(out.path()).await?;
+    let sha256 = install::installdb(store, out, install::HISTORY_TABLES, &dbfile).await?;
+    metadata::update(store, |metadata| {
+        metadata.artifacts.insert(
+            kind,
+            ArtifactMeta {
+                kind,
+                revision: latest,
+                systemversion: None,
+                release: Some(release.to_string()),
+                source,
+                downloaded: SystemTime::now(),
+                sha256: Some(sha256),
+            },
+        );
+    })?;
+    gc::afterrefresh(store);
+    store.cached(kind, dbfile, false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::configfile::NixDataConfig, history::HistoryDb, registry::RegistrySource};
+    use std::{collections::HashMap, io::Write};
+
+    /// Publishes a snapshot of `revision` with the given packages in the registry at `dir`.
+    fn publish(dir: &Path, revision: &str, pkgs: &[(&str, &str)]) -> Result<()> {
+        let dir = dir.join("nixos-25.11");
+        fs::create_dir_all(&dir)?;
+        let json = serde_json::to_vec(&pkgs.iter().copied().collect::<HashMap<_, _>>())?;
+        let mut data = Vec::new();
+        {
+            let mut writer = brotli::CompressorWriter::new(&mut data, 4096, 5, 22);
+            writer.write_all(&json)?;
+        }
+        fs::write(dir.join(format!("{}.json.br", revision)), data)?;
+        Ok(())
+    }
+
+    fn recorded(store: &CacheStore) -> Result<Option<String>> {
+        Ok(metadata::read(store)?
+            .artifact(ArtifactKind::HistoryDb)
+            .and_then(|meta| meta.revision.clone()))
+    }
+
+    #[tokio::test]
+    async fn backfill_keeps_newest_revision() -> Result<()> {
+        let registry = tempfile::tempdir()?;
+        let cache = tempfile::tempdir()?;
+        publish(registry.path(), "bbbbbbb", &[("hello", "2.12")])?;
+        publish(registry.path(), "aaaaaaa", &[("hello", "2.10")])?;
+        publish(registry.path(), "ccccccc", &[("hello", "2.12")])?;
+        let store = CacheStore::new(cache.path().join("nix-data"))?.with_config(NixDataConfig {
+            registry: Some(RegistrySource::Local(registry.path().to_path_buf())),
+            ..Default::default()
+        });
+
+        let db = historydb(&store, "25.11", &[("bbbbbbb", "20251020")]).await?;
+        assert_eq!(recorded(&store)?.as_deref(), Some("bbbbbbb"));
+
+        // An older revision added later doesn't move the recorded revision back
+        historydb(&store, "25.11", &[("aaaaaaa", "20251001")]).await?;
+        assert_eq!(recorded(&store)?.as_deref(), Some("bbbbbbb"));
+
+        historydb(
+            &store,
+            "25.11",
+            &[("aaaaaaa", "20251001"), ("ccccccc", "20251101")],
+        )
+        .await?;
+        assert_eq!(recorded(&store)?.as_deref(), Some("ccccccc"));
+
+        let history = HistoryDb::open(&db.data).await?;
+        let revisions = history
+            .revisions()
+            .await?
+            .into_iter()
+            .map(|revision| revision.revision)
+            .collect::<Vec<_>>();
+        assert_eq!(revisions, vec!["aaaaaaa", "bbbbbbb", "ccccccc"]);
+        Ok(())
+    }
+}
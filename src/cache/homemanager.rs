@@ -0,0 +1,88 @@
+use crate::options::OptionSource;
+use anyhow::Result;
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use super::{
+    CacheStore, Cached,
+    install::{self, Published},
+    metadata::ArtifactKind,
+};
+
+/// Where Home Manager installs its options inside a profile when `manual.json.enable` is set.
+const PROFILE_OPTIONS: &str = "share/doc/home-manager/options.json";
+/// Home Manager options published in the database source.
+const REGISTRY_OPTIONS: &str = "home-manager/options.json.br";
+/// Revision of the published Home Manager options.
+const REGISTRY_VERSION: &str = "home-manager/options.ver";
+
+/// Returns the `options.json` installed by the local Home Manager, resolved to its store path.
+/// Home Manager only installs it with `manual.json.enable`, which is the default.
+pub fn localoptions(store: &CacheStore) -> Option<PathBuf> {
+    let mut profiles = Vec::new();
+    if let Ok(home) = store.home() {
+        profiles.push(home.join(".nix-profile"));
+        profiles.push(home.join(".local/state/nix/profiles/home-manager/home-path"));
+    }
+    // Home Manager as a NixOS module with `useUserPackages`
+    if let Ok(user) = std::env::var("USER") {
+        profiles.push(Path::new("/etc/profiles/per-user").join(user));
+    }
+    profiles
+        .into_iter()
+        .find_map(|profile| fs::canonicalize(profile.join(PROFILE_OPTIONS)).ok())
+}
+
+/// Caches the Home Manager `options.json` and returns its path.
+///
+/// The options of the local Home Manager installation are used if it installed them, see [localoptions()].
+/// Otherwise the latest options are downloaded from `home-manager/options.json.br` in the database source,
+/// along with their revision from `home-manager/options.ver`.
+/// Without a network connection or in offline mode the cached file is returned as stale.
+pub async fn homemanageroptions(store: &CacheStore) -> Result<Cached<String>> {
+    let kind = ArtifactKind::HomeManagerOptions;
+    let published = [Published {
+        version: String::from(REGISTRY_VERSION),
+        path: String::from(REGISTRY_OPTIONS),
+        release: None,
+    }];
+    install::installartifact(
+        store,
+        kind,
+        localoptions(store),
+        &published,
+        async |published, revision| {
+            let source = store.database();
+            let Some(data) = source
+                .fetch_verified(
+                    &store.http()?,
+                    &published.path,
+                    &store.trustedkeys()?,
+                    Some(revision),
+                )
+                .await?
+            else {
+                return Ok(None);
+            };
+            let mut out = install::tempfile(store)?;
+            io::copy(&mut data.as_slice(), &mut out)?;
+            Ok(Some(out))
+        },
+        async |temp| {
+            let sha256 = install::sha256file(temp.path())?;
+            install::persist(temp, &store.file(&kind.file()))?;
+            Ok(sha256)
+        },
+    )
+    .await
+}
+
+/// Caches the Home Manager options with [homemanageroptions()] and indexes them into the options database,
+/// next to the NixOS options from [nixosoptionsdb()](super::nixos::nixosoptionsdb).
+/// Returns the path of the database, to be opened with [OptionsDb](crate::options::OptionsDb).
+pub async fn homemanageroptionsdb(store: &CacheStore) -> Result<Cached<String>> {
+    let options = homemanageroptions(store).await?;
+    install::installoptions(store, OptionSource::HomeManager, options).await
+}
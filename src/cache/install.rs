@@ -0,0 +1,371 @@
+use anyhow::{Context, Result, anyhow};
+use log::{debug, info};
+use sha2::{Digest, Sha256};
+use sqlx::{
+    Connection,
+    sqlite::{SqliteConnectOptions, SqliteConnection},
+};
+use std::{
+    fs::{self, File},
+    io,
+    os::unix::fs::{PermissionsExt, symlink},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+use tempfile::NamedTempFile;
+
+use crate::{
+    options::{self, OptionSource},
+    packages,
+};
+
+use super::{
+    CacheStore, Cached, Phase, gc,
+    metadata::{self, ArtifactKind, ArtifactMeta},
+};
+
+/// Tables a nixpkgs database from the registry has to contain.
+pub(super) const NIXPKGS_TABLES: &[&str] = &["pkgs", "meta"];
+/// Tables a nixpkgs database in the revision store has to contain once [indexdb()] built its search index.
+pub(super) const INDEXED_TABLES: &[&str] = &["pkgs", "meta", "pkgs_fts", "pkgs_trigrams"];
+/// Tables a database built by [createdb()](super::nixos::createdb) has to contain.
+pub(super) const LEGACY_TABLES: &[&str] = &["pkgs"];
+/// Tables a database built by [updatedb()](crate::options::updatedb) has to contain.
+pub(super) const OPTIONS_TABLES: &[&str] = &["options", "options_fts"];
+/// Tables a database built by [ingest()](crate::history::ingest) has to contain.
+pub(super) const HISTORY_TABLES: &[&str] = &["revisions", "versions"];
+/// Tables a programs index has to contain once [updatedb()](crate::programs::updatedb) added the main programs.
+pub(super) const PROGRAMS_TABLES: &[&str] = &["Programs", "mainprograms"];
+
+/// Creates a temporary file inside the cache directory.
+/// Downloads are written here first, so they can be renamed over the cached file in one step.
+pub(super) fn tempfile(store: &CacheStore) -> Result<NamedTempFile> {
+    store.create()?;
+    tempfile::Builder::new()
+        .prefix(".download-")
+        .tempfile_in(store.root())
+        .context("Failed to create temporary file in cache directory")
+}
+
+/// Checks that `path` is an SQLite database containing all of `tables`.
+pub(super) async fn validatedb(path: &Path, tables: &[&str]) -> Result<()> {
+    let options = SqliteConnectOptions::new().filename(path).read_only(true);
+    let mut conn = SqliteConnection::connect_with(&options)
+        .await
+        .context("Downloaded file is not an SQLite database")?;
+    let found: Vec<(String,)> =
+        sqlx::query_as("SELECT name FROM sqlite_master WHERE type = 'table'")
+            .fetch_all(&mut conn)
+            .await
+            .context("Downloaded file is not an SQLite database")?;
+    conn.close().await?;
+    for table in tables {
+        if !found.iter().any(|(name,)| name == table) {
+            return Err(anyhow!(
+                "Downloaded database is missing the `{}` table",
+                table
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Makes `temp` world readable and renames it to `target`.
+pub(super) fn persist(temp: NamedTempFile, target: &str) -> Result<()> {
+    temp.as_file().sync_all()?;
+    // Temporary files are created private, cached files are meant to be shared
+    temp.as_file()
+        .set_permissions(fs::Permissions::from_mode(0o644))?;
+    temp.persist(target)
+        .with_context(|| format!("Failed to replace {}", target))?;
+    debug!("Installed {}", target);
+    Ok(())
+}
+
+/// Returns the lowercase hex encoded SHA-256 of the file at `path`.
+pub(super) fn sha256file(path: &Path) -> Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Validates the database in `temp` and installs it as `target`.
+/// On any error the previously cached database is left untouched.
+/// Returns the SHA-256 of the installed database.
+pub(super) async fn installdb(
+    store: &CacheStore,
+    temp: NamedTempFile,
+    tables: &[&str],
+    target: &str,
+) -> Result<String> {
+    let name = Path::new(target)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    store.report(&name, Phase::Installing, 0, None);
+    validatedb(temp.path(), tables).await?;
+    let sha256 = sha256file(temp.path())?;
+    persist(temp, target)?;
+    Ok(sha256)
+}
+
+/// Checks that the nixpkgs database in `temp` is complete and builds its search index,
+/// so it can be installed with [INDEXED_TABLES].
+pub(super) async fn indexdb(store: &CacheStore, temp: &NamedTempFile, target: &str) -> Result<()> {
+    validatedb(temp.path(), NIXPKGS_TABLES).await?;
+    let name = Path::new(target)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    store.report(&name, Phase::Indexing, 0, None);
+    packages::createindex(temp.path()).await
+}
+
+/// Builds the search index of the database of `revision` if it was stored before databases had one.
+/// The index is built in a copy, which then replaces the stored database.
+pub(super) async fn indexrevision(store: &CacheStore, revision: &str) -> Result<()> {
+    let dbfile = store.revisionfile(revision);
+    if packages::hasindex(Path::new(&dbfile)).await? {
+        return Ok(());
+    }
+    debug!("Revision {} has no search index", revision);
+    let out = tempfile(store)?;
+    fs::copy(&dbfile, out.path())?;
+    indexdb(store, &out, &dbfile).await?;
+    let sha256 = installdb(store, out, INDEXED_TABLES, &dbfile).await?;
+    metadata::update(store, |metadata| {
+        if let Some(stored) = metadata.revisions.get_mut(revision) {
+            stored.sha256 = sha256.clone();
+        }
+        for meta in metadata.artifacts.values_mut() {
+            if meta.revision.as_deref() == Some(revision) {
+                meta.sha256 = Some(sha256.clone());
+            }
+        }
+    })?;
+    Ok(())
+}
+
+/// Points the view of `kind`, such as `nixospkgs.db`, at the database of `revision` in the revision store
+/// and records it in the metadata index, along with the version of the installed system if given.
+/// The view is a relative symlink, replaced atomically so readers never see it missing.
+/// Revisions no longer needed are then cleaned up according to the retention policy.
+pub(super) fn linkview(
+    store: &CacheStore,
+    kind: ArtifactKind,
+    revision: &str,
+    systemversion: Option<&str>,
+) -> Result<()> {
+    let target = store.revisionfile(revision);
+    let relative = Path::new(&target)
+        .strip_prefix(store.root())
+        .unwrap_or(Path::new(&target));
+    let link = store.root().join(format!(".{}.link", kind.file()));
+    if link.symlink_metadata().is_ok() {
+        fs::remove_file(&link)?;
+    }
+    symlink(relative, &link)?;
+    let viewfile = store.file(&kind.file());
+    fs::rename(&link, &viewfile).with_context(|| format!("Failed to replace {}", viewfile))?;
+    metadata::update(store, |metadata| {
+        let stored = metadata.revision(revision).cloned();
+        metadata.artifacts.insert(
+            kind,
+            ArtifactMeta {
+                kind,
+                revision: Some(revision.to_string()),
+                systemversion: systemversion.map(str::to_string),
+                release: stored.as_ref().map(|rev| rev.release.clone()),
+                source: stored.as_ref().map(|rev| rev.source.clone()),
+                downloaded: stored
+                    .as_ref()
+                    .map(|rev| rev.downloaded)
+                    .unwrap_or_else(SystemTime::now),
+                sha256: stored.map(|rev| rev.sha256),
+            },
+        );
+    })?;
+    debug!("Pointed {} at {}", viewfile, target);
+    gc::afterrefresh(store);
+    Ok(())
+}
+
+/// Indexes the cached `options.json` of `source` into the options database, replacing the options
+/// previously indexed for it, unless the database already holds the same revision.
+/// The database is updated in a copy, so readers never see it half written.
+/// Returns the path of the database, stale if `options` is.
+pub(super) async fn installoptions(
+    store: &CacheStore,
+    source: OptionSource,
+    options: Cached<String>,
+) -> Result<Cached<String>> {
+    let kind = ArtifactKind::OptionsDb;
+    let dbfile = store.file(&kind.file());
+    let revision = options
+        .meta
+        .as_ref()
+        .and_then(|meta| meta.revision.clone())
+        .unwrap_or_else(|| sha256file(Path::new(&options.data)).unwrap_or_default());
+    let _lock = store.lock().await?;
+
+    if options::indexedrevision(Path::new(&dbfile), source)
+        .await
+        .as_ref()
+        == Some(&revision)
+    {
+        debug!("{} options are up to date in {}", source.name(), dbfile);
+        return store.cached(kind, dbfile, options.stale);
+    }
+
+    store.report(&kind.file(), Phase::Installing, 0, None);
+    let out = tempfile(store)?;
+    if Path::new(&dbfile).exists() {
+        fs::copy(&dbfile, out.path())?;
+    }
+    options::updatedb(
+        out.path(),
+        source,
+        &revision,
+        options::readoptions(Path::new(&options.data))?,
+    )
+    .await?;
+    let sha256 = installdb(store, out, OPTIONS_TABLES, &dbfile).await?;
+    metadata::update(store, |metadata| {
+        metadata.artifacts.insert(
+            kind,
+            ArtifactMeta {
+                kind,
+                revision: None,
+                systemversion: None,
+                release: None,
+                source: None,
+                downloaded: SystemTime::now(),
+                sha256: Some(sha256),
+            },
+        );
+    })?;
+    store.cached(kind, dbfile, options.stale)
+}
+
+/// Where an artifact is published in the database source, see [installartifact()].
+pub(super) struct Published {
+    /// File holding the latest revision, such as `nixos-25.11/nixpkgs.ver`.
+    pub version: String,
+    /// The artifact itself, such as `nixos-25.11/programs.sqlite.br`.
+    pub path: String,
+    /// Release recorded for the artifact, if it belongs to one.
+    pub release: Option<String>,
+}
+
+/// Caches the artifact `kind` and returns its path, copied from the file `local` if there is one,
+/// otherwise downloaded from the first of `published` that has a revision.
+///
+/// A local file, such as one in a Nix profile, is recorded with its store path as revision,
+/// so it is copied again whenever that changes. This also works offline.
+/// A published artifact is only downloaded with `download` if its revision isn't cached yet.
+/// If the latest revision can't be checked, such as without a network connection or in offline mode,
+/// the cached artifact is returned as stale.
+/// `install` installs the copied or downloaded file and returns its SHA-256.
+pub(super) async fn installartifact(
+    store: &CacheStore,
+    kind: ArtifactKind,
+    local: Option<PathBuf>,
+    published: &[Published],
+    download: impl AsyncFn(&Published, &str) -> Result<Option<NamedTempFile>>,
+    install: impl AsyncFn(NamedTempFile) -> Result<String>,
+) -> Result<Cached<String>> {
+    let file = store.file(&kind.file());
+    let uptodate = |revision: &str| -> Result<bool> {
+        Ok(Path::new(&file).exists()
+            && metadata::read(store)?
+                .artifact(kind)
+                .and_then(|meta| meta.revision.as_deref())
+                == Some(revision))
+    };
+    let record = |sha256: String, revision: String, release: Option<String>, source: String| {
+        metadata::update(store, |metadata| {
+            metadata.artifacts.insert(
+                kind,
+                ArtifactMeta {
+                    kind,
+                    revision: Some(revision),
+                    systemversion: None,
+                    release,
+                    source: Some(source),
+                    downloaded: SystemTime::now(),
+                    sha256: Some(sha256),
+                },
+            );
+        })?;
+        gc::afterrefresh(store);
+        Ok::<_, anyhow::Error>(())
+    };
+
+    if let Some(local) = local {
+        let revision = local.to_string_lossy().to_string();
+        store.create()?;
+        let _lock = store.lock().await?;
+        if !uptodate(&revision)? {
+            debug!("Copying {} from {}", kind.file(), revision);
+            let out = tempfile(store)?;
+            fs::copy(&local, out.path())?;
+            let sha256 = install(out).await?;
+            record(sha256, revision.clone(), None, revision)?;
+        }
+        return store.cached(kind, file, false);
+    }
+
+    if store.offline() {
+        if !Path::new(&file).exists() {
+            return Err(store.notcached(&kind.file()));
+        }
+        return store.cached(kind, file, true);
+    }
+
+    // If cache directory doesn't exist, create it
+    store.create()?;
+    // Wait for refreshes by other processes, then check what they left
+    let _lock = store.lock().await?;
+
+    let source = store.database();
+    let client = store.http()?;
+    let keys = store.trustedkeys()?;
+    for published in published {
+        debug!("Checking version of {}", source.url(&published.path));
+        let latest = match source
+            .fetch_revision(&client, &published.version, &keys)
+            .await
+        {
+            Ok(Some(latest)) => latest,
+            Ok(None) => continue,
+            Err(e) if Path::new(&file).exists() => {
+                info!("Using old {}: {:#}", kind.file(), e);
+                return store.cached(kind, file, true);
+            }
+            Err(e) => return Err(e),
+        };
+        debug!("Latest version of {}: {}", published.path, latest);
+        if uptodate(&latest)? {
+            debug!("No new version of {} found", kind.file());
+            return store.cached(kind, file, false);
+        }
+        if let Some(out) = download(published, &latest).await? {
+            let sha256 = install(out).await?;
+            record(
+                sha256,
+                latest,
+                published.release.clone(),
+                source.url(&published.path),
+            )?;
+            return store.cached(kind, file, false);
+        }
+    }
+    Err(anyhow!(
+        "No {} published in {}",
+        kind.file(),
+        published
+            .first()
+            .map(|published| source.url(&published.path))
+            .unwrap_or_else(|| source.to_string())
+    ))
+}
@@ -0,0 +1,244 @@
+use anyhow::{Context, Result};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{self, BufReader, Write},
+    path::Path,
+    time::SystemTime,
+};
+
+use super::{CacheStore, install};
+
+/// File inside the cache directory holding the [Metadata] index.
+pub const METADATA: &str = "metadata.json";
+/// Current layout of [Metadata], stored in [Metadata::schema].
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// A cached artifact, named after the file it is stored in.
+#[derive(Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum ArtifactKind {
+    /// `nixospkgs.db`, the latest packages of the system release.
+    NixosPkgs,
+    /// `flakespkgs.db`, the packages of the release a flake based system was last seen on.
+    FlakesPkgs,
+    /// `nixpkgs.db`, the latest packages available to `nix profile`.
+    Nixpkgs,
+    /// `legacypkgs.db`, the packages of the running legacy NixOS system.
+    LegacyPkgs,
+    /// `nonnixospkgs.db`, the latest packages of nixos-unstable on non-NixOS systems.
+    NonNixosPkgs,
+    /// `nixosoptions.json`, the options of the system release.
+    NixosOptions,
+    /// `homemanageroptions.json`, the options of Home Manager.
+    HomeManagerOptions,
+    /// `options.db`, the NixOS and Home Manager options indexed for [OptionsDb](crate::options::OptionsDb).
+    #[serde(rename = "options")]
+    OptionsDb,
+    /// `history.db`, the package versions of past nixpkgs revisions for [HistoryDb](crate::history::HistoryDb).
+    #[serde(rename = "history")]
+    HistoryDb,
+    /// `programs.db`, the commands provided by each package for [ProgramsDb](crate::programs::ProgramsDb).
+    #[serde(rename = "programs")]
+    ProgramsDb,
+}
+
+impl ArtifactKind {
+    /// All kinds of artifacts.
+    pub const ALL: [ArtifactKind; 10] = [
+        ArtifactKind::NixosPkgs,
+        ArtifactKind::FlakesPkgs,
+        ArtifactKind::Nixpkgs,
+        ArtifactKind::LegacyPkgs,
+        ArtifactKind::NonNixosPkgs,
+        ArtifactKind::NixosOptions,
+        ArtifactKind::HomeManagerOptions,
+        ArtifactKind::OptionsDb,
+        ArtifactKind::HistoryDb,
+        ArtifactKind::ProgramsDb,
+    ];
+
+    /// Name of the artifact, such as `nixospkgs`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ArtifactKind::NixosPkgs => "nixospkgs",
+            ArtifactKind::FlakesPkgs => "flakespkgs",
+            ArtifactKind::Nixpkgs => "nixpkgs",
+            ArtifactKind::LegacyPkgs => "legacypkgs",
+            ArtifactKind::NonNixosPkgs => "nonnixospkgs",
+            ArtifactKind::NixosOptions => "nixosoptions",
+            ArtifactKind::HomeManagerOptions => "homemanageroptions",
+            ArtifactKind::OptionsDb => "options",
+            ArtifactKind::HistoryDb => "history",
+            ArtifactKind::ProgramsDb => "programs",
+        }
+    }
+
+    /// Name of the file the artifact is stored in, such as `nixospkgs.db`.
+    pub fn file(&self) -> String {
+        match self {
+            ArtifactKind::NixosOptions | ArtifactKind::HomeManagerOptions => {
+                format!("{}.json", self.name())
+            }
+            _ => format!("{}.db", self.name()),
+        }
+    }
+}
+
+/// What is known about a cached artifact, recorded whenever it is refreshed.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct ArtifactMeta {
+    /// Kind of the artifact.
+    pub kind: ArtifactKind,
+    /// Nixpkgs revision of the data, such as `25.11.1234.abcdef`.
+    pub revision: Option<String>,
+    /// NixOS version of the system when the artifact was refreshed, for artifacts following the installed system.
+    pub systemversion: Option<String>,
+    /// Release the data was fetched for, such as `25.11` or `unstable`.
+    pub release: Option<String>,
+    /// Where the data was downloaded from.
+    pub source: Option<String>,
+    /// Time the data was downloaded.
+    pub downloaded: SystemTime,
+    /// Lowercase hex encoded SHA-256 of the cached file.
+    pub sha256: Option<String>,
+}
+
+impl ArtifactMeta {
+    /// Short commit hash of the nixpkgs revision, the last component of [revision](ArtifactMeta::revision).
+    pub fn commit(&self) -> Option<&str> {
+        commit(self.revision.as_deref()?)
+    }
+}
+
+/// Returns the commit hash at the end of a NixOS or nixpkgs version, such as `abcdef` in `25.11.1234.abcdef`.
+pub(super) fn commit(version: &str) -> Option<&str> {
+    version
+        .trim()
+        .rsplit('.')
+        .next()
+        .filter(|commit| !commit.is_empty())
+}
+
+/// A nixpkgs database in the revision store.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct RevisionMeta {
+    /// Release the database was fetched for, such as `25.11` or `unstable`.
+    pub release: String,
+    /// Where the database was downloaded from.
+    pub source: String,
+    /// Time the database was downloaded.
+    pub downloaded: SystemTime,
+    /// Lowercase hex encoded SHA-256 of the database file.
+    pub sha256: String,
+}
+
+/// Index of everything in a cache directory, stored in [METADATA].
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct Metadata {
+    /// Layout version of the index, see [SCHEMA_VERSION].
+    pub schema: u32,
+    /// Cached artifacts.
+    #[serde(default)]
+    pub artifacts: BTreeMap<ArtifactKind, ArtifactMeta>,
+    /// Databases in the revision store, keyed by revision.
+    #[serde(default)]
+    pub revisions: BTreeMap<String, RevisionMeta>,
+}
+
+impl Default for Metadata {
+    fn default() -> Self {
+        Metadata {
+            schema: SCHEMA_VERSION,
+            artifacts: BTreeMap::new(),
+            revisions: BTreeMap::new(),
+        }
+    }
+}
+
+impl Metadata {
+    /// Returns what is known about the artifact `kind`, if it was ever refreshed.
+    pub fn artifact(&self, kind: ArtifactKind) -> Option<&ArtifactMeta> {
+        self.artifacts.get(&kind)
+    }
+
+    /// Returns what is known about the database of `revision` in the revision store.
+    pub fn revision(&self, revision: &str) -> Option<&RevisionMeta> {
+        self.revisions.get(revision)
+    }
+}
+
+/// Reads the metadata index of `store`.
+///
+/// Caches written before the index existed kept a bare version string in a `.ver` file per artifact,
+/// which is imported as [revision](ArtifactMeta::revision) or [systemversion](ArtifactMeta::systemversion).
+/// An empty index is returned for a new cache.
+pub fn read(store: &CacheStore) -> Result<Metadata> {
+    let path = store.root().join(METADATA);
+    match File::open(&path) {
+        Ok(file) => {
+            let metadata: Metadata = serde_json::from_reader(BufReader::new(file))
+                .with_context(|| format!("Invalid cache metadata in {}", path.display()))?;
+            if metadata.schema > SCHEMA_VERSION {
+                debug!(
+                    "Cache metadata has schema {}, newer than {}",
+                    metadata.schema, SCHEMA_VERSION
+                );
+            }
+            Ok(metadata)
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(importlegacy(store)),
+        Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    }
+}
+
+/// Reads, modifies and atomically replaces the metadata index.
+/// Must be called with the cache lock held.
+pub(super) fn update(store: &CacheStore, f: impl FnOnce(&mut Metadata)) -> Result<()> {
+    let mut metadata = read(store)?;
+    f(&mut metadata);
+    metadata.schema = SCHEMA_VERSION;
+    let mut out = install::tempfile(store)?;
+    out.write_all(&serde_json::to_vec_pretty(&metadata)?)?;
+    install::persist(out, &store.file(METADATA))?;
+    // The index replaces the loose version files
+    for kind in ArtifactKind::ALL {
+        let verfile = store.root().join(format!("{}.ver", kind.name()));
+        if verfile.exists() {
+            fs::remove_file(verfile)?;
+        }
+    }
+    Ok(())
+}
+
+/// Builds the index from the `.ver` files of an older cache.
+fn importlegacy(store: &CacheStore) -> Metadata {
+    let mut metadata = Metadata::default();
+    for kind in ArtifactKind::ALL {
+        let verfile = store.root().join(format!("{}.ver", kind.name()));
+        let Ok(ver) = fs::read_to_string(&verfile) else {
+            continue;
+        };
+        // Both of these tracked the version of the installed system
+        let systemartifact = matches!(kind, ArtifactKind::FlakesPkgs | ArtifactKind::LegacyPkgs);
+        metadata.artifacts.insert(
+            kind,
+            ArtifactMeta {
+                kind,
+                revision: (!systemartifact).then(|| ver.clone()),
+                systemversion: systemartifact.then_some(ver),
+                release: None,
+                source: None,
+                downloaded: modified(&verfile).unwrap_or(SystemTime::UNIX_EPOCH),
+                sha256: None,
+            },
+        );
+    }
+    metadata
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|meta| meta.modified()).ok()
+}
@@ -7,12 +7,45 @@ use serde::{Deserialize, Serialize};
 pub mod channel;
 /// Cache and determine packages installed on flakes enabled NixOS
 pub mod flakes;
+/// List and clean up cached databases
+pub mod gc;
+/// Record package versions across nixpkgs revisions
+pub mod history;
+/// Cache Home Manager options
+pub mod homemanager;
+/// Typed index of the cached artifacts and their versions
+pub mod metadata;
 /// Cache latest NixOS `packages.json` and `options.json`
 pub mod nixos;
-/// Cache and determine packages installed with `nix profile`
-pub mod profile;
 /// Nixpkgs cache on non-NixOS
 pub mod nonnixos;
+/// Cache and determine packages installed with `nix profile`
+pub mod profile;
+/// Cache the index of commands provided by packages
+pub mod programs;
+/// Progress events emitted while refreshing the cache
+pub mod progress;
+/// Location and settings of a cache directory
+pub mod store;
+
+mod delta;
+mod install;
+
+pub use metadata::{ArtifactKind, ArtifactMeta};
+pub use progress::{Phase, Progress};
+pub use store::CacheStore;
+
+/// Data returned by the cache functions, together with how current it is.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Cached<T> {
+    /// The cached data, usually the path to a cached file.
+    pub data: T,
+    /// Whether the data may be outdated because it could not be checked against the latest version,
+    /// such as in [offline](CacheStore::offline) mode.
+    pub stale: bool,
+    /// What the [metadata index](metadata::read) records about the data, such as its revision and download time.
+    pub meta: Option<ArtifactMeta>,
+}
 
 #[derive(Debug, Deserialize)]
 struct NixPkgList {
@@ -1,107 +1,103 @@
-use crate::CACHEDIR;
-use crate::utils::get_full_ver;
+use crate::{
+    options::OptionSource, packages::PackageDb, system::NixosVersion, utils::get_full_ver,
+};
 use anyhow::{Context, Result, anyhow};
-use log::debug;
-use sqlx::{Row, Sqlite, SqlitePool, migrate::MigrateDatabase};
+use log::{debug, warn};
+use sqlx::{Connection, Sqlite, SqliteConnection, migrate::MigrateDatabase};
 use std::{
     collections::{HashMap, HashSet},
-    fs::{self, File},
-    io::{Read, Write},
+    fs::{self},
+    io::Write,
     path::Path,
-    process::{Command, Stdio},
+    time::SystemTime,
 };
+use tempfile::NamedTempFile;
+
+use crate::registry::{client::found, manifest::Manifest};
 
-use super::{channel, flakes};
+use super::{
+    CacheStore, Cached, Phase, channel, delta, flakes, gc, install,
+    metadata::{self, ArtifactKind, ArtifactMeta, RevisionMeta},
+};
 
-/// Downloads the latest `packages.json` for the system from the NixOS cache and returns the path to an SQLite database `nixospkgs.db` which contains package data.
+/// Downloads the latest `packages.json` for the system from the NixOS cache and returns the path to an SQLite database which contains package data.
+/// The database is kept in the revision store and `nixospkgs.db` in the cache directory links to it.
+/// In offline mode the cached database is returned as stale.
 /// Will only work on NixOS systems.
-pub async fn nixospkgs() -> Result<String> {
-    // If cache directory doesn't exist, create it
-    if !std::path::Path::new(&*CACHEDIR).exists() {
-        std::fs::create_dir_all(&*CACHEDIR)?;
+pub async fn nixospkgs(store: &CacheStore) -> Result<Cached<String>> {
+    latestdb(store, ArtifactKind::NixosPkgs).await
+}
+
+/// Resolves the view of `kind` to the latest nixpkgs revision of the system release,
+/// downloading that revision into the revision store if it isn't there yet.
+pub(super) async fn latestdb(store: &CacheStore, kind: ArtifactKind) -> Result<Cached<String>> {
+    let view = kind.name();
+    if store.offline() {
+        let db = store
+            .view(view)
+            .ok_or_else(|| store.notcached(&kind.file()))?;
+        return store.cached(kind, db, true);
     }
 
-    // we will have internet before install something
-    // returns 2x.xx
-    let ver = std::process::Command::new("sh")
-        .arg("-c")
-        .arg(r"nixos-version | grep -oP '^\d+\.\d+'")
-        .output()
-        .expect("failed to get nixos-version");
-    let ver_string = String::from_utf8(ver.stdout)?;
+    // If cache directory doesn't exist, create it
+    store.create()?;
+    // Wait for refreshes by other processes, then check what they left
+    let _lock = store.lock().await?;
+
+    let version = NixosVersion::detect()?;
 
     // hash of commit like: 25.11.asdasd.asd
-    let latestnixpkgsver = get_full_ver().await?;
+    let latestnixpkgsver = get_full_ver(store).await?;
 
-    if let Ok(prevver) = fs::read_to_string(format!("{}/nixospkgs.ver", &*CACHEDIR))
-        && prevver == latestnixpkgsver.clone()
-        && Path::new(&format!("{}/nixospkgs.db", &*CACHEDIR)).exists()
+    let prevver = metadata::read(store)?
+        .artifact(kind)
+        .and_then(|meta| meta.revision.clone());
+    if prevver.as_deref() == Some(latestnixpkgsver.as_str())
+        && let Some(db) = store.view(view)
     {
-        debug!("No new version of flakespkgs found");
-        return Ok(format!("{}/nixospkgs.db", &*CACHEDIR));
-    }
-    let mut url = format!(
-        "https://raw.githubusercontent.com/xinux-org/database/main/nixos-{}/nixpkgs.db.br",
-        ver_string.trim(),
-    );
-    // println!("{}", url);
-    let mut resp = reqwest::get(&url).await?;
-    let mut pkgsout: Vec<u8> = Vec::new();
-
-    if resp.status().is_success() {
-        debug!(
-            "response getting {:?} pkgs: {:?}",
-            ver_string.trim(),
-            resp.status()
-        );
-        let r = resp.bytes().await?;
-        // println!("Downloaded");
-        let mut br = brotli::Decompressor::new(r.as_ref(), 4096);
-
-        br.read_to_end(&mut pkgsout)
-            .context("Failed to decompress brotli data")?;
-        debug!("Decompressed");
-    } else {
-        url = "https://raw.githubusercontent.com/xinux-org/database/main/nixos-unstable/nixpkgs.db.br".to_string();
-        debug!("{}", url);
-        resp = reqwest::get(url).await?;
-        debug!("response getting latest unstable pkgs: {:?}", resp.status());
-        if resp.status().is_success() {
-            let r = resp.bytes().await?;
-            debug!("Downloaded");
-            let mut br = brotli::Decompressor::new(r.as_ref(), 4096);
-            br.read_to_end(&mut pkgsout)?;
-            debug!("Decompressed");
-        }
+        debug!("No new version of {} found", view);
+        install::indexrevision(store, &latestnixpkgsver).await?;
+        return store.cached(kind, db, false);
     }
 
-    let dbfile = format!("{}/nixospkgs.db", &*CACHEDIR);
-    let mut out = File::create(&dbfile).context("Failed to create database file")?;
-    out.write_all(&pkgsout)
-        .context("Failed to write decompressed nixospkgs.db to file")?;
-
-    debug!("Writing nixospkgs.db latest version");
-    File::create(format!("{}/nixospkgs.ver", &*CACHEDIR))?
-        .write_all(latestnixpkgsver.as_bytes())?;
-
-    Ok(format!("{}/nixospkgs.db", &*CACHEDIR))
+    let db = revisiondb(
+        store,
+        &version.release,
+        &latestnixpkgsver,
+        prevver.as_deref(),
+    )
+    .await?;
+    install::linkview(store, kind, &latestnixpkgsver, None)?;
+    store.cached(kind, db, false)
 }
 
 /// Downloads the latest 'options.json' for the system from the NixOS cache and returns the path to the file.
+/// In offline mode the cached file is returned as stale.
 /// Will only work on NixOS systems.
-pub fn nixosoptions() -> Result<String> {
-    let versionout = Command::new("nixos-version").output()?;
-    let mut version = &String::from_utf8(versionout.stdout)?[0..5];
+pub async fn nixosoptions(store: &CacheStore) -> Result<Cached<String>> {
+    let kind = ArtifactKind::NixosOptions;
+    let optionsfile = store.file(&kind.file());
+    if store.offline() {
+        if !Path::new(&optionsfile).exists() {
+            return Err(store.notcached(&kind.file()));
+        }
+        return store.cached(kind, optionsfile, true);
+    }
+
+    let nixosversion = NixosVersion::detect()?;
+    let mut version = nixosversion.channel();
 
     // If cache directory doesn't exist, create it
-    if !std::path::Path::new(&*CACHEDIR).exists() {
-        std::fs::create_dir_all(&*CACHEDIR)?;
-    }
+    store.create()?;
+    // Wait for refreshes by other processes, then check what they left
+    let _lock = store.lock().await?;
 
+    let client = store.http()?;
     let verurl = format!("https://channels.nixos.org/nixos-{}", version);
     debug!("Checking NixOS version");
-    let resp = reqwest::blocking::get(&verurl)?;
-    let latestnixosver = if resp.status().is_success() {
+    // Only a missing release channel falls back to unstable, not an unavailable server
+    let resp = found(client.send(&verurl).await?)?;
+    let latestnixosver = if let Some(resp) = resp {
         resp.url()
             .path_segments()
             .context("No path segments found")?
@@ -109,8 +105,12 @@ pub fn nixosoptions() -> Result<String> {
             .context("Last element not found")?
             .to_string()
     } else {
-        let resp = reqwest::blocking::get("https://channels.nixos.org/nixos-unstable")?;
-        if resp.status().is_success() {
+        let resp = found(
+            client
+                .send("https://channels.nixos.org/nixos-unstable")
+                .await?,
+        )?;
+        if let Some(resp) = resp {
             version = "unstable";
             resp.url()
                 .path_segments()
@@ -124,25 +124,188 @@ pub fn nixosoptions() -> Result<String> {
     };
     debug!("Latest NixOS version: {}", latestnixosver);
 
+    if metadata::read(store)?
+        .artifact(kind)
+        .and_then(|meta| meta.revision.as_ref())
+        == Some(&latestnixosver)
+        && Path::new(&optionsfile).exists()
+    {
+        debug!("No new version of options.json found");
+        return store.cached(kind, optionsfile, false);
+    }
+
     let url = format!(
         "https://channels.nixos.org/nixos-{}/options.json.br",
         version
     );
 
-    // Download file with reqwest blocking
-    let client = reqwest::blocking::Client::builder().brotli(true).build()?;
-    let mut resp = client.get(url).send()?;
-    if resp.status().is_success() {
-        let mut out = File::create(format!("{}/nixosoptions.json", &*CACHEDIR))?;
-        resp.copy_to(&mut out)?;
-        // Write version downloaded to file
-        File::create(format!("{}/nixosoptions.ver", &*CACHEDIR))?
-            .write_all(latestnixosver.as_bytes())?;
+    if let Some(mut resp) = found(client.send(&url).await?)? {
+        let mut out = install::tempfile(store)?;
+        while let Some(chunk) = resp.chunk().await? {
+            out.write_all(&chunk)?;
+        }
+        // Replace the cached file, then record the version downloaded
+        let sha256 = install::sha256file(out.path())?;
+        install::persist(out, &optionsfile)?;
+        metadata::update(store, |metadata| {
+            metadata.artifacts.insert(
+                kind,
+                ArtifactMeta {
+                    kind,
+                    revision: Some(latestnixosver),
+                    systemversion: None,
+                    release: Some(version.to_string()),
+                    source: Some(url),
+                    downloaded: SystemTime::now(),
+                    sha256: Some(sha256),
+                },
+            );
+        })?;
+        gc::afterrefresh(store);
     } else {
         return Err(anyhow!("Failed to download latest options.json"));
     }
 
-    Ok(format!("{}/nixosoptions.json", &*CACHEDIR))
+    store.cached(kind, optionsfile, false)
+}
+
+/// Indexes the latest `options.json` from [nixosoptions()] into the options database and returns its path,
+/// to be opened with [OptionsDb](crate::options::OptionsDb).
+/// The NixOS options are indexed again whenever a new `options.json` is downloaded.
+/// In offline mode they are indexed from the cached `options.json` if needed and returned as stale.
+/// Will only work on NixOS systems.
+pub async fn nixosoptionsdb(store: &CacheStore) -> Result<Cached<String>> {
+    let options = nixosoptions(store).await?;
+    install::installoptions(store, OptionSource::Nixos, options).await
+}
+
+/// Streams `path` from the configured database source through brotli into a temporary file,
+/// reporting [Downloading](Phase::Downloading) progress along the way.
+/// The download is verified against the signed manifest if trusted keys are configured,
+/// which also has to be of `revision`.
+/// Returns `None` if the source doesn't publish `path`.
+pub(super) async fn fetchdb(
+    store: &CacheStore,
+    path: &str,
+    revision: &str,
+) -> Result<Option<NamedTempFile>> {
+    let source = store.database();
+    let client = store.http()?;
+    let mut br = brotli::DecompressorWriter::new(install::tempfile(store)?, 4096);
+    let Some(fetched) = source
+        .fetch_stream(&client, path, &mut br, |received, total| {
+            store.report(path, Phase::Downloading, received, total)
+        })
+        .await
+        .with_context(|| format!("Failed to download {}", path))?
+    else {
+        return Ok(None);
+    };
+    let out = br
+        .into_inner()
+        .map_err(|_| anyhow!("Failed to decompress brotli data: {} is truncated", path))?;
+    debug!("Downloaded and decompressed {}", path);
+
+    let keys = store.trustedkeys()?;
+    if !keys.is_empty() {
+        store.report(path, Phase::Verifying, fetched.size, Some(fetched.size));
+        let (dir, name) = path.rsplit_once('/').unwrap_or(("", path));
+        let manifest = Manifest::fetch(&client, &source, dir, &keys).await?;
+        manifest
+            .verify_digest(name, fetched.size, &fetched.sha256)
+            .and_then(|_| manifest.verify_revision(revision))
+            .with_context(|| format!("Refusing to use {}", source.url(path)))?;
+    }
+    Ok(Some(out))
+}
+
+/// Downloads and decompresses `nixos-<release>/nixpkgs.db.br` of `revision` from the configured database source,
+/// falling back to `nixos-unstable` if the release is not published.
+/// Returns the database and the release it was found for.
+pub(super) async fn downloaddb(
+    store: &CacheStore,
+    release: &str,
+    revision: &str,
+) -> Result<(NamedTempFile, String)> {
+    if let Some(out) = fetchdb(store, &format!("nixos-{}/nixpkgs.db.br", release), revision).await?
+    {
+        return Ok((out, release.to_string()));
+    }
+    if release == "unstable" {
+        return Err(anyhow!(
+            "Failed to download nixpkgs.db from the unstable channel"
+        ));
+    }
+    debug!("No database for nixos-{}, trying unstable", release);
+    let out = fetchdb(store, "nixos-unstable/nixpkgs.db.br", revision)
+        .await?
+        .context("Failed to download nixpkgs.db from both release and unstable channels")?;
+    Ok((out, String::from("unstable")))
+}
+
+/// Returns the path of the database for `revision` in the revision store, fetching it if it is missing.
+/// If the database of revision `base` is stored, deltas from it are tried before a full download
+/// of `nixos-<release>/nixpkgs.db.br`. New databases are recorded in the metadata index.
+pub(super) async fn revisiondb(
+    store: &CacheStore,
+    release: &str,
+    revision: &str,
+    base: Option<&str>,
+) -> Result<String> {
+    let dbfile = store.revisionfile(revision);
+    if Path::new(&dbfile).exists() {
+        debug!("Revision {} is already stored", revision);
+        install::indexrevision(store, revision).await?;
+        return Ok(dbfile);
+    }
+    let base = base
+        .map(|base| (store.revisionfile(base), base))
+        .filter(|(basefile, _)| Path::new(basefile).exists());
+    let (pkgsout, release) = updatedb(store, release, base, revision).await?;
+    install::indexdb(store, &pkgsout, &dbfile).await?;
+    let sha256 = install::installdb(store, pkgsout, install::INDEXED_TABLES, &dbfile).await?;
+    metadata::update(store, |metadata| {
+        metadata.revisions.insert(
+            revision.to_string(),
+            RevisionMeta {
+                source: store.database().url(&format!("nixos-{}", release)),
+                release,
+                downloaded: SystemTime::now(),
+                sha256,
+            },
+        );
+    })?;
+    Ok(dbfile)
+}
+
+/// Brings the database `base`, a `(path, revision)` pair, to revision `latest`.
+/// Published deltas are applied to a copy of the base database if a chain of them exists,
+/// otherwise `nixos-<release>/nixpkgs.db.br` is downloaded in full.
+/// Returns the database and the release it was found for.
+pub(super) async fn updatedb(
+    store: &CacheStore,
+    release: &str,
+    base: Option<(String, &str)>,
+    latest: &str,
+) -> Result<(NamedTempFile, String)> {
+    if let Some((basefile, basever)) = base {
+        let mut dirs = vec![format!("nixos-{}", release), String::from("nixos-unstable")];
+        dirs.dedup();
+        for dir in dirs {
+            match delta::applydeltas(store, &dir, &basefile, basever, latest).await {
+                Ok(Some(out)) => {
+                    let release = dir.strip_prefix("nixos-").unwrap_or(&dir).to_string();
+                    return Ok((out, release));
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    warn!("Failed to apply deltas, downloading full database: {:#}", e);
+                    break;
+                }
+            }
+        }
+    }
+    downloaddb(store, release, latest).await
 }
 
 pub(super) enum NixosType {
@@ -151,6 +314,7 @@ pub(super) enum NixosType {
 }
 
 pub(super) async fn getnixospkgs(
+    store: &CacheStore,
     paths: &[&str],
     nixos: NixosType,
 ) -> Result<HashMap<String, String>> {
@@ -172,36 +336,30 @@ pub(super) async fn getnixospkgs(
     };
     debug!("getnixospkgs: {:?}", pkgs);
     let pkgsdb = match nixos {
-        NixosType::Flake => flakes::flakespkgs().await?,
-        NixosType::Legacy => channel::legacypkgs().await?,
+        NixosType::Flake => flakes::flakespkgs(store).await?.data,
+        NixosType::Legacy => channel::legacypkgs(store).await?.data,
     };
-    let mut out = HashMap::new();
-    let pool = SqlitePool::connect(&format!("sqlite://{}", pkgsdb)).await?;
-    for pkg in pkgs {
-        let mut sqlout = sqlx::query(
-            r#"
-            SELECT version FROM pkgs WHERE attribute = $1
-            "#,
-        )
-        .bind(&pkg)
-        .fetch_all(&pool)
-        .await?;
-        if sqlout.len() == 1 {
-            let row = sqlout.pop().unwrap();
-            let version: String = row.get("version");
-            out.insert(pkg, version);
-        }
-    }
+    let attrs = pkgs.into_iter().collect::<Vec<_>>();
+    let out = PackageDb::open(&pkgsdb)
+        .await?
+        .get_many(&attrs)
+        .await?
+        .into_iter()
+        .filter_map(|(attr, pkg)| Some((attr, pkg.version?)))
+        .collect();
     Ok(out)
 }
 
+/// Creates the database `dbfile` with a `pkgs` table holding the attribute and version of every package in `pkgjson`.
+/// All rows are inserted in a single transaction, so an error never leaves a partially filled database behind.
 pub(super) async fn createdb(dbfile: &str, pkgjson: &HashMap<String, String>) -> Result<()> {
     let db = format!("sqlite://{}", dbfile);
     if Path::new(dbfile).exists() {
         fs::remove_file(dbfile)?;
     }
     Sqlite::create_database(&db).await?;
-    let pool = SqlitePool::connect(&db).await?;
+    let mut conn = SqliteConnection::connect(&db).await?;
+    let mut tx = conn.begin().await?;
     sqlx::query(
         r#"
             CREATE TABLE "pkgs" (
@@ -211,29 +369,26 @@ pub(super) async fn createdb(dbfile: &str, pkgjson: &HashMap<String, String>) ->
             )
             "#,
     )
-    .execute(&pool)
+    .execute(&mut *tx)
     .await?;
     sqlx::query(
         r#"
         CREATE UNIQUE INDEX "attributes" ON "pkgs" ("attribute")
         "#,
     )
-    .execute(&pool)
+    .execute(&mut *tx)
     .await?;
 
-    let mut wtr = csv::Writer::from_writer(vec![]);
     for (pkg, version) in pkgjson {
-        wtr.serialize((pkg.to_string(), version.to_string()))?;
+        sqlx::query(r#"INSERT INTO "pkgs" ("attribute", "version") VALUES ($1, $2)"#)
+            .bind(pkg)
+            .bind(version)
+            .execute(&mut *tx)
+            .await
+            .with_context(|| format!("Failed to insert {} into {}", pkg, dbfile))?;
     }
-    let data = String::from_utf8(wtr.into_inner()?)?;
-    let mut cmd = Command::new("sqlite3")
-        .arg("-csv")
-        .arg(dbfile)
-        .arg(".import '|cat -' pkgs")
-        .stdin(Stdio::piped())
-        .spawn()?;
-    let cmd_stdin = cmd.stdin.as_mut().unwrap();
-    cmd_stdin.write_all(data.as_bytes())?;
-    let _status = cmd.wait()?;
+    tx.commit().await?;
+    conn.close().await?;
+    debug!("Created {} with {} packages", dbfile, pkgjson.len());
     Ok(())
 }
@@ -1,40 +1,54 @@
-use crate::CACHEDIR;
 use anyhow::{Result, anyhow};
 use log::{debug, info};
-use std::{
-    fs::{self, File},
-    io::{Read, Write},
-    path::Path,
+
+use super::{
+    CacheStore, Cached, install,
+    metadata::{self, ArtifactKind},
+    nixos,
 };
 
 /// Downloads the latest `packages.json` for the system from the Nix cache and returns the path to an SQLite database `nonnixospkgs.db` which contains package data.
+/// If the latest version can't be checked, such as without a network connection or in offline mode,
+/// the cached database is returned as stale.
 /// Mean for non-NixOS systems.
-pub async fn nixpkgs() -> Result<String> {
-    // If cache directory doesn't exist, create it
-    if !std::path::Path::new(&*CACHEDIR).exists() {
-        std::fs::create_dir_all(&*CACHEDIR)?;
+pub async fn nixpkgs(store: &CacheStore) -> Result<Cached<String>> {
+    let kind = ArtifactKind::NonNixosPkgs;
+    if store.offline() {
+        let db = store
+            .view(kind.name())
+            .ok_or_else(|| store.notcached(&kind.file()))?;
+        return store.cached(kind, db, true);
     }
 
-    let verurl = String::from(
-        "https://raw.githubusercontent.com/xinux-org/database/main/nixos-unstable/nixpkgs.ver",
-    );
+    // If cache directory doesn't exist, create it
+    store.create()?;
+    // Wait for refreshes by other processes, then check what they left
+    let _lock = store.lock().await?;
+
+    let source = store.database();
     debug!("Checking nixpkgs version");
-    let resp = reqwest::get(&verurl).await;
-    let resp = if let Ok(r) = resp {
-        r
-    } else {
-        // Internet connection failed
+    let resp = source
+        .fetch_revision(
+            &store.http()?,
+            "nixos-unstable/nixpkgs.ver",
+            &store.trustedkeys()?,
+        )
+        .await;
+    let resp = match resp {
+        Ok(r) => r,
+        // Internet connection or verification failed
         // Check if we can use the old database
-        let dbpath = format!("{}/nonnixospkgs.db", &*CACHEDIR);
-        if Path::new(&dbpath).exists() {
-            info!("Using old database");
-            return Ok(dbpath);
-        } else {
-            return Err(anyhow!("Could not find latest nixpkgs version"));
+        Err(e) => {
+            if let Some(dbpath) = store.view(kind.name()) {
+                info!("Using old database: {:#}", e);
+                return store.cached(kind, dbpath, true);
+            } else {
+                return Err(e.context("Could not find latest nixpkgs version"));
+            }
         }
     };
-    let latestnixpkgsver = if resp.status().is_success() {
-        resp.text().await?
+    let latestnixpkgsver = if let Some(ver) = resp {
+        ver
     } else {
         return Err(anyhow!("Could not find latest nixpkgs version"));
     };
@@ -45,55 +59,19 @@ pub async fn nixpkgs() -> Result<String> {
         .unwrap_or(&latestnixpkgsver);
     info!("latestnixosver: {}", latestnixpkgsver);
     // Check if latest version is already downloaded
-    if let Ok(prevver) = fs::read_to_string(format!("{}/nonnixospkgs.ver", &*CACHEDIR))
-        && prevver == latestnixpkgsver
-        && Path::new(&format!("{}/nonnixospkgs.db", &*CACHEDIR)).exists()
+    let prevver = metadata::read(store)?
+        .artifact(kind)
+        .and_then(|meta| meta.revision.clone());
+    if prevver.as_deref() == Some(latestnixpkgsver)
+        && let Some(db) = store.view(kind.name())
     {
         debug!("No new version of nixpkgs found");
-        return Ok(format!("{}/nonnixospkgs.db", &*CACHEDIR));
+        install::indexrevision(store, latestnixpkgsver).await?;
+        return store.cached(kind, db, false);
     }
 
-    let url = String::from(
-        "https://raw.githubusercontent.com/xinux-org/database/main/nixos-unstable/nixpkgs.db.br",
-    );
     debug!("Downloading nix-data database");
-    let client = reqwest::Client::builder().brotli(true).build()?;
-    let resp = client.get(url).send().await?;
-    if resp.status().is_success() {
-        debug!("Writing nix-data database");
-        let mut out = File::create(format!("{}/nonnixospkgs.db", &*CACHEDIR))?;
-        {
-            let bytes = resp.bytes().await?;
-            let mut reader = brotli::Decompressor::new(
-                bytes.as_ref(),
-                4096, // buffer size
-            );
-            let mut buf = [0u8; 4096];
-            loop {
-                match reader.read(&mut buf[..]) {
-                    Err(e) => {
-                        if let std::io::ErrorKind::Interrupted = e.kind() {
-                            continue;
-                        }
-                        panic!("{}", e);
-                    }
-                    Ok(size) => {
-                        if size == 0 {
-                            break;
-                        }
-                        if let Err(e) = out.write_all(&buf[..size]) {
-                            panic!("{}", e)
-                        }
-                    }
-                }
-            }
-        }
-        debug!("Writing nix-data version");
-        // Write version downloaded to file
-        File::create(format!("{}/nonnixospkgs.ver", &*CACHEDIR))?
-            .write_all(latestnixpkgsver.as_bytes())?;
-    } else {
-        return Err(anyhow!("Failed to download latest nonnixospkgs.db.br"));
-    }
-    Ok(format!("{}/nonnixospkgs.db", &*CACHEDIR))
+    let db = nixos::revisiondb(store, "unstable", latestnixpkgsver, prevver.as_deref()).await?;
+    install::linkview(store, kind, latestnixpkgsver, None)?;
+    store.cached(kind, db, false)
 }
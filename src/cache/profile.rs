@@ -1,18 +1,17 @@
-use crate::CACHEDIR;
-use crate::utils::get_full_ver;
+use crate::{packages::PackageDb, system::hostplatform};
 use anyhow::{Context, Result};
-use log::debug;
 use serde::Deserialize;
-use sqlx::SqlitePool;
 use std::{
     collections::{HashMap, HashSet},
-    fs::{self, File},
-    io::{Read, Write},
-    path::Path,
+    fs::File,
     process::Command,
 };
 
-use super::nixos::nixospkgs;
+use super::{
+    CacheStore, Cached,
+    metadata::ArtifactKind,
+    nixos::{self, nixospkgs},
+};
 
 #[derive(Debug, Deserialize)]
 struct ProfilePkgsRoot {
@@ -38,19 +37,12 @@ pub struct ProfilePkg {
 
 /// Returns a list of all packages installed with `nix profile` with their name.
 /// Does not include individual version.
-pub fn getprofilepkgs() -> Result<HashMap<String, ProfilePkg>> {
-    if !Path::new(&format!(
-        "{}/.nix-profile/manifest.json",
-        std::env::var("HOME")?
-    ))
-    .exists()
-    {
+pub fn getprofilepkgs(store: &CacheStore) -> Result<HashMap<String, ProfilePkg>> {
+    let manifest = store.home()?.join(".nix-profile/manifest.json");
+    if !manifest.exists() {
         return Ok(HashMap::new());
     }
-    let file = File::open(format!(
-        "{}/.nix-profile/manifest.json",
-        std::env::var("HOME")?
-    ))?;
+    let file = File::open(manifest)?;
     let profileroot: ProfilePkgsRoot = serde_json::from_reader(file)?;
 
     let mut out = HashMap::new();
@@ -86,121 +78,40 @@ pub fn getprofilepkgs() -> Result<HashMap<String, ProfilePkg>> {
 
 /// Returns a list of all packages installed with `nix profile` with their name and version.
 /// Takes a bit longer than [getprofilepkgs()].
-pub async fn getprofilepkgs_versioned() -> Result<HashMap<String, String>> {
-    if !Path::new(&format!(
-        "{}/.nix-profile/manifest.json",
-        std::env::var("HOME")?
-    ))
-    .exists()
-    {
+pub async fn getprofilepkgs_versioned(store: &CacheStore) -> Result<HashMap<String, String>> {
+    if !store.home()?.join(".nix-profile/manifest.json").exists() {
         return Ok(HashMap::new());
     }
-    let profilepkgs = getprofilepkgs()?;
+    let profilepkgs = getprofilepkgs(store)?;
 
     // println!("{profilepkgs:?}");
 
-    let latestpkgs = if Path::new(&format!("{}/nixpkgs.db", &*CACHEDIR)).exists() {
-        format!("{}/nixpkgs.db", &*CACHEDIR)
+    let latestpkgs = if let Some(db) = store.view("nixpkgs") {
+        db
     } else {
         // Change to something else if overridden
-        nixpkgslatest().await?
+        nixpkgslatest(store).await?.data
     };
-    let mut out = HashMap::new();
-    let pool = SqlitePool::connect(&format!("sqlite://{}", latestpkgs)).await?;
-    for (pkg, _v) in profilepkgs {
-        let versions: Vec<(String,)> = sqlx::query_as(
-            r#"
-            SELECT version FROM pkgs WHERE attribute = $1
-            "#,
-        )
-        .bind(&pkg)
-        .fetch_all(&pool)
-        .await?;
-        if !versions.is_empty() {
-            out.insert(pkg, versions.first().unwrap().0.to_string());
-        }
-    }
+    let attrs = profilepkgs.into_keys().collect::<Vec<_>>();
+    let out = PackageDb::open(&latestpkgs)
+        .await?
+        .get_many(&attrs)
+        .await?
+        .into_iter()
+        .filter_map(|(attr, pkg)| Some((attr, pkg.version?)))
+        .collect();
     Ok(out)
 }
 
 /// Downloads a list of available package versions `packages.db`
 /// and returns the path to the file.
-pub async fn nixpkgslatest() -> Result<String> {
-    // If cache directory doesn't exist, create it
-    if !std::path::Path::new(&*CACHEDIR).exists() {
-        std::fs::create_dir_all(&*CACHEDIR)?;
-    }
-
-    // we will have internet before install something
-    // returns 2x.xx
-    let ver = std::process::Command::new("sh")
-        .arg("-c")
-        .arg(r"nixos-version | grep -oP '^\d+\.\d+'")
-        .output()
-        .expect("failed to get nixos-version");
-    let ver_string = String::from_utf8(ver.stdout)?;
-
-    // hash of commit like: 25.11.asdasd.asd
-    let latestnixpkgsver = get_full_ver().await?;
-
-    if let Ok(prevver) = fs::read_to_string(format!("{}/nixpkgs.ver", &*CACHEDIR))
-        && prevver == latestnixpkgsver.clone()
-        && Path::new(&format!("{}/nixpkgs.db", &*CACHEDIR)).exists()
-    {
-        debug!("No new version of nixpkgs.db found");
-        return Ok(format!("{}/nixpkgs.db", &*CACHEDIR));
-    }
-
-    let mut url = format!(
-        "https://raw.githubusercontent.com/xinux-org/database/main/nixos-{}/nixpkgs.db.br",
-        ver_string.trim(),
-    );
-    // println!("{}", url);
-    let mut resp = reqwest::get(&url).await?;
-    let mut pkgsout: Vec<u8> = Vec::new();
-
-    if resp.status().is_success() {
-        debug!(
-            "response getting {:?} pkgs: {:?}",
-            ver_string.trim(),
-            resp.status()
-        );
-        let r = resp.bytes().await?;
-        // println!("Downloaded");
-        let mut br = brotli::Decompressor::new(r.as_ref(), 4096);
-
-        br.read_to_end(&mut pkgsout)
-            .context("Failed to decompress brotli data")?;
-        debug!("Decompressed");
-    } else {
-        url = "https://raw.githubusercontent.com/xinux-org/database/main/nixos-unstable/nixpkgs.db.br".to_string();
-        debug!("{}", url);
-        resp = reqwest::get(url).await?;
-        debug!(
-            "response getting latest nixpkgs-unstable pkgs: {:?}",
-            resp.status()
-        );
-        if resp.status().is_success() {
-            let r = resp.bytes().await?;
-            debug!("Downloaded");
-            let mut br = brotli::Decompressor::new(r.as_ref(), 4096);
-            br.read_to_end(&mut pkgsout)?;
-            debug!("Decompressed");
-        }
-    }
-
-    let dbfile = format!("{}/nixpkgs.db", &*CACHEDIR);
-    let mut out = File::create(&dbfile).context("Failed to create database file")?;
-    out.write_all(&pkgsout)
-        .context("Failed to write decompressed nixpkgs.db to file")?;
-
-    debug!("Writing nixpkgs.db latest version");
-    File::create(format!("{}/nixpkgs.ver", &*CACHEDIR))?.write_all(latestnixpkgsver.as_bytes())?;
-
-    Ok(format!("{}/nixpkgs.db", &*CACHEDIR))
+/// The database is kept in the revision store and `nixpkgs.db` in the cache directory links to it.
+/// In offline mode the cached database is returned as stale.
+pub async fn nixpkgslatest(store: &CacheStore) -> Result<Cached<String>> {
+    nixos::latestdb(store, ArtifactKind::Nixpkgs).await
 }
 
-pub async fn unavailablepkgs() -> Result<HashMap<String, String>> {
+pub async fn unavailablepkgs(store: &CacheStore) -> Result<HashMap<String, String>> {
     let nixpath = Command::new("nix")
         .arg("eval")
         .arg("nixpkgs#path")
@@ -218,7 +129,7 @@ pub async fn unavailablepkgs() -> Result<HashMap<String, String>> {
     let aliasstr = String::from_utf8(aliases.stdout)?;
     let aliasesout: HashSet<String> = serde_json::from_str(&aliasstr)?;
 
-    let flakespkgs = getprofilepkgs()?;
+    let flakespkgs = getprofilepkgs(store)?;
     let mut unavailable = HashMap::new();
     for pkg in flakespkgs.keys() {
         if aliasesout.contains(pkg) && Command::new("nix-instantiate")
@@ -237,27 +148,35 @@ pub async fn unavailablepkgs() -> Result<HashMap<String, String>> {
         }
     }
 
-    let nixospkgs = nixospkgs().await?;
-    let pool = SqlitePool::connect(&format!("sqlite://{}", nixospkgs)).await?;
+    let nixospkgs = nixospkgs(store).await?;
+    let db = PackageDb::open(&nixospkgs.data).await?;
 
+    let platform = hostplatform();
+    let metas = db.meta_many(&flakespkgs.keys().collect::<Vec<_>>()).await?;
     for pkg in flakespkgs.keys() {
-        let (x, broken, insecure): (String, u8, u8) =
-            sqlx::query_as("SELECT attribute,broken,insecure FROM meta WHERE attribute = $1")
-                .bind(pkg)
-                .fetch_one(&pool)
-                .await?;
-        if &x != pkg {
-            unavailable.insert(
-                pkg.to_string(),
-                String::from("Package not found in newer version of nixpkgs"),
-            );
-        } else if broken == 1 {
-            unavailable.insert(pkg.to_string(), String::from("Package is marked as broken"));
-        } else if insecure == 1 {
-            unavailable.insert(
-                pkg.to_string(),
-                String::from("Package is marked as insecure"),
-            );
+        match metas.get(pkg) {
+            None => {
+                unavailable.insert(
+                    pkg.to_string(),
+                    String::from("Package not found in newer version of nixpkgs"),
+                );
+            }
+            Some(meta) if meta.broken => {
+                unavailable.insert(pkg.to_string(), String::from("Package is marked as broken"));
+            }
+            Some(meta) if meta.insecure => {
+                unavailable.insert(
+                    pkg.to_string(),
+                    String::from("Package is marked as insecure"),
+                );
+            }
+            Some(meta) if !meta.availableon(&platform) => {
+                unavailable.insert(
+                    pkg.to_string(),
+                    format!("Package is not available on {}", platform),
+                );
+            }
+            Some(_) => {}
         }
     }
     Ok(unavailable)
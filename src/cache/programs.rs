@@ -0,0 +1,80 @@
+use crate::{programs, system::NixosVersion};
+use anyhow::Result;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use super::{
+    CacheStore, Cached, Phase,
+    install::{self, Published},
+    metadata::ArtifactKind,
+    nixos,
+};
+
+/// `programs.sqlite` of the nixos channel of root, as used by `command-not-found`.
+const CHANNEL_PROGRAMS: &str = "/nix/var/nix/profiles/per-user/root/channels/nixos/programs.sqlite";
+
+/// Returns the `programs.sqlite` of the local nixos channel, resolved to its store path.
+/// Only systems using channels have one.
+pub fn localprograms(store: &CacheStore) -> Option<PathBuf> {
+    let mut files = vec![PathBuf::from(CHANNEL_PROGRAMS)];
+    if let Ok(home) = store.home() {
+        files.push(home.join(".nix-defexpr/channels/nixos/programs.sqlite"));
+    }
+    files
+        .into_iter()
+        .find_map(|file| fs::canonicalize(file).ok())
+}
+
+/// Caches the index of which packages provide which commands and returns its path,
+/// to be opened with [ProgramsDb](crate::programs::ProgramsDb).
+///
+/// The index is copied from the local channel if it has one, see [localprograms()], which also works offline.
+/// Otherwise `nixos-<release>/programs.sqlite.br` is downloaded from the database source
+/// along with the revision in `nixos-<release>/nixpkgs.ver`, falling back to `nixos-unstable`.
+/// The `mainProgram` of every package is added from the cached package database, if there is one.
+/// If the latest version can't be checked, such as without a network connection or in offline mode,
+/// the cached index is returned as stale.
+pub async fn programsdb(store: &CacheStore) -> Result<Cached<String>> {
+    let kind = ArtifactKind::ProgramsDb;
+    let release = NixosVersion::detect()
+        .map(|version| version.channel().to_string())
+        .unwrap_or_else(|_| String::from("unstable"));
+    let mut releases = vec![release.as_str(), "unstable"];
+    releases.dedup();
+    let published = releases
+        .into_iter()
+        .map(|release| Published {
+            version: format!("nixos-{}/nixpkgs.ver", release),
+            path: format!("nixos-{}/programs.sqlite.br", release),
+            release: Some(release.to_string()),
+        })
+        .collect::<Vec<_>>();
+    install::installartifact(
+        store,
+        kind,
+        localprograms(store),
+        &published,
+        async |published, revision| nixos::fetchdb(store, &published.path, revision).await,
+        async |temp| {
+            store.report(&kind.file(), Phase::Indexing, 0, None);
+            let pkgsdb = [
+                ArtifactKind::NixosPkgs,
+                ArtifactKind::NonNixosPkgs,
+                ArtifactKind::Nixpkgs,
+            ]
+            .into_iter()
+            .find_map(|kind| store.view(kind.name()));
+            programs::updatedb(temp.path(), pkgsdb.as_deref().map(Path::new)).await?;
+            install::installdb(
+                store,
+                temp,
+                install::PROGRAMS_TABLES,
+                &store.file(&kind.file()),
+            )
+            .await
+        },
+    )
+    .await
+}
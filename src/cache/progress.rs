@@ -0,0 +1,40 @@
+use std::{fmt, sync::Arc};
+
+/// Stage of a cache refresh reported through [Progress].
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Phase {
+    /// Downloading and decompressing an artifact.
+    Downloading,
+    /// Applying deltas to a copy of the cached database.
+    Patching,
+    /// Checking the download against the signed manifest.
+    Verifying,
+    /// Building the search index of a new database.
+    Indexing,
+    /// Validating the new database and moving it into place.
+    Installing,
+}
+
+/// Progress of a cache refresh, passed to the handler set with [CacheStore::with_progress()](super::CacheStore::with_progress).
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Progress {
+    /// Artifact being refreshed, such as `nixos-25.11/nixpkgs.db.br` while downloading
+    /// or `nixospkgs.db` while installing.
+    pub artifact: String,
+    /// Current stage.
+    pub phase: Phase,
+    /// Compressed bytes received so far. Only increases while [Downloading](Phase::Downloading).
+    pub received: u64,
+    /// Total compressed size, if the source reported it.
+    pub total: Option<u64>,
+}
+
+/// Callback receiving [Progress] events.
+#[derive(Clone)]
+pub(super) struct ProgressHandler(pub(super) Arc<dyn Fn(&Progress) + Send + Sync>);
+
+impl fmt::Debug for ProgressHandler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ProgressHandler")
+    }
+}
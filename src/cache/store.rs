@@ -0,0 +1,290 @@
+use crate::{
+    config::configfile::{NixDataConfig, readconfig},
+    registry::{DEFAULT_DATABASE, DEFAULT_REGISTRY, RegistrySource, client::HttpClient, manifest},
+};
+use anyhow::{Context, Result, anyhow};
+use log::debug;
+use minisign_verify::PublicKey;
+use std::{
+    fs::{self, File, TryLockError},
+    path::{Path, PathBuf},
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+
+use super::{
+    Cached,
+    metadata::{self, ArtifactKind},
+    progress::{Phase, Progress, ProgressHandler},
+};
+
+/// System-wide cache directory used by [CacheStore::system()].
+pub const SYSTEM_CACHE: &str = "/var/cache/nix-data";
+/// Directory inside the cache holding one nixpkgs database per revision.
+pub const REVISIONS: &str = "nixpkgs";
+/// File inside the cache locked while it is refreshed.
+pub const LOCKFILE: &str = ".lock";
+/// How often a locked cache is checked again.
+const LOCK_POLL: Duration = Duration::from_millis(100);
+
+/// A directory holding cached package databases, together with the settings used to fill it.
+///
+/// Every function in [cache](crate::cache) takes a `CacheStore`,
+/// so a single process can manage several independent caches.
+#[derive(Clone, Debug)]
+pub struct CacheStore {
+    root: PathBuf,
+    home: Option<PathBuf>,
+    config: NixDataConfig,
+    progress: Option<ProgressHandler>,
+    offline: Option<bool>,
+    http: Arc<OnceLock<HttpClient>>,
+}
+
+impl CacheStore {
+    /// Creates a store at `root`, using the config file from the usual locations if there is one.
+    /// No home directory is set, see [with_home()](CacheStore::with_home).
+    ///
+    /// Returns an error if a config file exists but can't be read or parsed,
+    /// rather than silently dropping settings such as its trusted keys.
+    pub fn new(root: impl Into<PathBuf>) -> Result<Self> {
+        Ok(CacheStore {
+            root: root.into(),
+            home: None,
+            config: readconfig()?.unwrap_or_default(),
+            progress: None,
+            offline: None,
+            http: Arc::default(),
+        })
+    }
+
+    /// Creates the per-user store at `$XDG_CACHE_HOME/nix-data`, or `~/.cache/nix-data` if it is not set.
+    /// The home directory is taken from `$HOME`. Returns an error if the config file can't be parsed.
+    pub fn user() -> Result<Self> {
+        let home = std::env::var_os("HOME")
+            .filter(|home| !home.is_empty())
+            .map(PathBuf::from);
+        let root = match std::env::var_os("XDG_CACHE_HOME").filter(|dir| !dir.is_empty()) {
+            Some(dir) => PathBuf::from(dir),
+            None => home
+                .as_ref()
+                .context("Neither XDG_CACHE_HOME nor HOME is set")?
+                .join(".cache"),
+        };
+        let mut store = CacheStore::new(root.join("nix-data"))?;
+        store.home = home;
+        Ok(store)
+    }
+
+    /// Creates the system-wide store at [SYSTEM_CACHE], meant for services running without a home directory.
+    /// Returns an error if the config file can't be parsed, see [new()](CacheStore::new).
+    pub fn system() -> Result<Self> {
+        CacheStore::new(SYSTEM_CACHE)
+    }
+
+    /// Sets the home directory used to find the user's Nix profile.
+    pub fn with_home(mut self, home: impl Into<PathBuf>) -> Self {
+        self.home = Some(home.into());
+        self
+    }
+
+    /// Replaces the config read from the config file.
+    pub fn with_config(mut self, config: NixDataConfig) -> Self {
+        self.config = config;
+        self.http = Arc::default();
+        self
+    }
+
+    /// Sets a handler receiving [Progress] events while databases are downloaded and installed.
+    /// To consume them elsewhere, such as on a GTK main loop, send them through a channel from the handler.
+    pub fn with_progress(mut self, handler: impl Fn(&Progress) + Send + Sync + 'static) -> Self {
+        self.progress = Some(ProgressHandler(Arc::new(handler)));
+        self
+    }
+
+    /// Sets whether the network is accessed, overriding `offline` from the config.
+    /// To make a single call offline, pass a modified clone: `nixospkgs(&store.clone().with_offline(true))`.
+    pub fn with_offline(mut self, offline: bool) -> Self {
+        self.offline = Some(offline);
+        self
+    }
+
+    /// Directory the cache is stored in.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Home directory of the user whose profile is inspected.
+    /// Returns an error if the store was created without one.
+    pub fn home(&self) -> Result<&Path> {
+        self.home
+            .as_deref()
+            .context("No home directory set for this cache store")
+    }
+
+    /// Config used by this store.
+    pub fn config(&self) -> &NixDataConfig {
+        &self.config
+    }
+
+    /// Whether the store is in offline mode, see [with_offline()](CacheStore::with_offline).
+    /// Cache functions then only return cached data and fail if there is none.
+    pub fn offline(&self) -> bool {
+        self.offline.or(self.config.offline).unwrap_or(false)
+    }
+
+    /// Source of the package databases, as set by `database` in the config or [DEFAULT_DATABASE].
+    pub fn database(&self) -> RegistrySource {
+        self.config
+            .database
+            .clone()
+            .unwrap_or_else(|| RegistrySource::Http(DEFAULT_DATABASE.to_string()))
+    }
+
+    /// Source of the per-revision package snapshots, as set by `registry` in the config or [DEFAULT_REGISTRY].
+    pub fn registry(&self) -> RegistrySource {
+        self.config
+            .registry
+            .clone()
+            .unwrap_or_else(|| RegistrySource::Http(DEFAULT_REGISTRY.to_string()))
+    }
+
+    /// HTTP client built from `http` in the config, shared by all clones of this store.
+    /// Validators of fetched artifacts are kept in the `http` directory of the cache.
+    pub fn http(&self) -> Result<HttpClient> {
+        if let Some(client) = self.http.get() {
+            return Ok(client.clone());
+        }
+        let client = HttpClient::new(&self.config.http.clone().unwrap_or_default())?
+            .with_cache(self.root.join("http"));
+        Ok(self.http.get_or_init(|| client).clone())
+    }
+
+    /// Public keys downloads have to be signed with, as set by `trustedkeys` in the config.
+    /// Verification is disabled if no keys are configured.
+    pub(crate) fn trustedkeys(&self) -> Result<Vec<PublicKey>> {
+        manifest::parsekeys(self.config.trustedkeys.as_deref().unwrap_or_default())
+    }
+
+    /// Returns the path of `name` inside the cache directory.
+    pub fn file(&self, name: &str) -> String {
+        self.root.join(name).to_string_lossy().to_string()
+    }
+
+    /// Wraps `data` with what the metadata index records about the artifact `kind`.
+    pub(crate) fn cached<T>(&self, kind: ArtifactKind, data: T, stale: bool) -> Result<Cached<T>> {
+        Ok(Cached {
+            data,
+            stale,
+            meta: metadata::read(self)?.artifact(kind).cloned(),
+        })
+    }
+
+    /// Sends a [Progress] event to the handler, if one is set.
+    pub(crate) fn report(&self, artifact: &str, phase: Phase, received: u64, total: Option<u64>) {
+        if let Some(ProgressHandler(handler)) = &self.progress {
+            handler(&Progress {
+                artifact: artifact.to_string(),
+                phase,
+                received,
+                total,
+            });
+        }
+    }
+
+    /// Returns the path of the nixpkgs database for `revision` in the revision store.
+    /// Databases are shared by every view (`nixospkgs.db`, `flakespkgs.db`, `nixpkgs.db`, `nonnixospkgs.db`)
+    /// resolving to the same revision, so each revision is downloaded and stored only once.
+    pub fn revisionfile(&self, revision: &str) -> String {
+        let name = revision.trim().replace(['/', '\\'], "_");
+        self.root
+            .join(REVISIONS)
+            .join(format!("{}.db", name))
+            .to_string_lossy()
+            .to_string()
+    }
+
+    /// Returns the revision database the view `<view>.db` points to,
+    /// or `None` if the view doesn't exist or doesn't point into the revision store.
+    pub fn view(&self, view: &str) -> Option<String> {
+        let target = fs::read_link(self.root.join(format!("{}.db", view))).ok()?;
+        let target = self.root.join(target);
+        if target.starts_with(self.root.join(REVISIONS)) && target.exists() {
+            Some(target.to_string_lossy().to_string())
+        } else {
+            None
+        }
+    }
+
+    /// Returns the revision the view `<view>.db` points to, see [view()](CacheStore::view).
+    pub fn viewrevision(&self, view: &str) -> Option<String> {
+        let db = self.view(view)?;
+        Path::new(&db)
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+    }
+
+    /// Returns an error for `name` having no cached data to fall back to in offline mode.
+    pub(crate) fn notcached(&self, name: &str) -> anyhow::Error {
+        anyhow!("No cached {} available in offline mode", name)
+    }
+
+    /// Takes the advisory lock of the cache directory, waiting while another process or task holds it.
+    /// Refreshes hold the lock from checking the cached version until the new database is in place,
+    /// so concurrent callers wait for a refresh in progress and then reuse its result.
+    /// The lock is released when the returned guard is dropped and must not be taken twice by one caller.
+    pub(crate) async fn lock(&self) -> Result<CacheLock> {
+        let file = self.lockfile()?;
+        let mut waiting = false;
+        loop {
+            match file.try_lock() {
+                Ok(()) => return Ok(CacheLock { _file: file }),
+                Err(TryLockError::WouldBlock) => {
+                    if !waiting {
+                        debug!(
+                            "Waiting for a refresh of {} in progress",
+                            self.root.display()
+                        );
+                        waiting = true;
+                    }
+                    tokio::time::sleep(LOCK_POLL).await;
+                }
+                Err(TryLockError::Error(e)) => {
+                    return Err(e).context("Failed to lock cache directory");
+                }
+            }
+        }
+    }
+
+    /// Blocking version of [lock()](CacheStore::lock) for synchronous callers.
+    pub(crate) fn lockblocking(&self) -> Result<CacheLock> {
+        let file = self.lockfile()?;
+        file.lock().context("Failed to lock cache directory")?;
+        Ok(CacheLock { _file: file })
+    }
+
+    fn lockfile(&self) -> Result<File> {
+        self.create()?;
+        File::options()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(self.root.join(LOCKFILE))
+            .context("Failed to open cache lock")
+    }
+
+    /// Creates the cache directory if it doesn't exist.
+    pub(crate) fn create(&self) -> Result<()> {
+        let revisions = self.root.join(REVISIONS);
+        if !revisions.exists() {
+            fs::create_dir_all(&revisions)?;
+        }
+        Ok(())
+    }
+}
+
+/// Guard of the cache lock taken with [CacheStore::lock()], released on drop.
+#[derive(Debug)]
+pub(crate) struct CacheLock {
+    _file: File,
+}
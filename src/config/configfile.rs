@@ -1,11 +1,11 @@
 
-use crate::{CONFIG, SYSCONFIG, CONFIGDIR};
-use anyhow::{Result, anyhow};
+use crate::{SYSCONFIG, registry::RegistrySource};
+use anyhow::{Context, Result, anyhow};
 use serde::{Deserialize, Serialize};
 use std::{
     fs::{self, File},
     io::{Write, BufReader},
-    path::Path,
+    path::{Path, PathBuf},
 };
 
 /// Struct containing locations of system configuration files and some user configuration.
@@ -21,45 +21,111 @@ pub struct NixDataConfig {
     /// Specifies how many NixOS generations to keep. If set to 0, all generations will be kept.
     /// If not set, the default is 5.
     pub generations: Option<u32>,
+    /// Where the package databases (`nixos-<release>/nixpkgs.db.br`) are fetched from.
+    /// Accepts an HTTP(S) URL, a `file://` URL or a local directory.
+    /// If not set, [DEFAULT_DATABASE](crate::registry::DEFAULT_DATABASE) is used.
+    pub database: Option<RegistrySource>,
+    /// Where the per-revision package snapshots (`nixos-<release>/<revision>.json.br`) are fetched from.
+    /// Accepts an HTTP(S) URL, a `file://` URL or a local directory.
+    /// If not set, [DEFAULT_REGISTRY](crate::registry::DEFAULT_REGISTRY) is used.
+    pub registry: Option<RegistrySource>,
+    /// Minisign public keys trusted to sign registry manifests.
+    /// If set, downloaded package databases and their revisions must match a manifest signed by one of these keys,
+    /// otherwise they are not installed.
+    pub trustedkeys: Option<Vec<String>>,
+    /// Which cached databases are kept, applied after every refresh.
+    /// If not set, the defaults of [RetentionPolicy] are used.
+    pub retention: Option<RetentionPolicy>,
+    /// If set to `true`, cache functions never access the network
+    /// and return the cached data marked as [stale](crate::cache::Cached::stale) instead.
+    pub offline: Option<bool>,
+    /// Timeouts, retries and proxy used for downloads.
+    /// If not set, the defaults of [HttpConfig] are used.
+    pub http: Option<HttpConfig>,
 }
 
+/// Limits on what is kept in the cache directory, see [collect()](crate::cache::gc::collect).
+/// Databases in use by a view (`nixospkgs.db`, `flakespkgs.db`, `nixpkgs.db`, `nonnixospkgs.db`)
+/// and cached files such as `legacypkgs.db` or `options.db` are never removed.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug, Default)]
+pub struct RetentionPolicy {
+    /// Number of nixpkgs revisions to keep, newest first.
+    /// If not set, only revisions in use are kept.
+    pub keeprevisions: Option<usize>,
+    /// Maximum total size of the cache directory in bytes. Oldest unused databases are removed first.
+    pub maxbytes: Option<u64>,
+    /// Maximum age of unused databases in days.
+    pub maxage: Option<u64>,
+}
+
+/// Settings of the [HTTP client](crate::registry::client::HttpClient) used for downloads.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug, Default)]
+pub struct HttpConfig {
+    /// Seconds to wait for a connection to be established. If not set, the default is 30.
+    pub connecttimeout: Option<u64>,
+    /// Seconds to wait for data before a download is aborted. If not set, the default is 60.
+    pub timeout: Option<u64>,
+    /// How often a request is retried on connection errors, timeouts and server errors.
+    /// If not set, the default is 3.
+    pub retries: Option<u32>,
+    /// Proxy used for all requests, such as `http://proxy.example.org:3128`.
+    /// If not set, the `HTTP_PROXY`, `HTTPS_PROXY` and `NO_PROXY` environment variables are honoured.
+    pub proxy: Option<String>,
+}
 
 /// Type of package management used by the user.
 /// - [Profile](UserPkgType::Profile) refers to the `nix profile` command.
 /// - [Env](UserPkgType::Env) refers to the `nix-env` command.
+///
+/// Detected for the current user by [detect_system()](crate::system::detect_system).
 #[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
 pub enum UserPkgType {
     Profile,
     Env,
 }
 
+/// Returns the user config directory, `~/.config/nix-data`.
+/// Returns `None` if `HOME` is not set, such as in system services.
+fn userconfigdir() -> Option<PathBuf> {
+    std::env::var_os("HOME")
+        .filter(|home| !home.is_empty())
+        .map(|home| PathBuf::from(home).join(".config/nix-data"))
+}
+
 /// Reads the config file and returns the config struct.
 /// If the config file doesn't exist in both the user (`~/.config/nix-data`) and system (`/etc/nix-data`) config directories,
 /// this function will return an error.
 pub fn getconfig() -> Result<NixDataConfig> {
-    // Check if user config exists
-    if Path::new(&*CONFIG).exists() {
-        // Read user config
-        let config: NixDataConfig = serde_json::from_reader(BufReader::new(File::open(&*CONFIG)?))?;
-        Ok(config)
-    } else if Path::new(SYSCONFIG).exists() {
-        // Read system config
-        let config: NixDataConfig = serde_json::from_reader(BufReader::new(File::open(SYSCONFIG)?))?;
-        Ok(config)
-    }  else {
-        Err(anyhow!("No config file found"))
-    }
+    readconfig()?.ok_or_else(|| anyhow!("No config file found"))
+}
+
+/// Reads the user config file, or the system config file if there is none.
+/// Returns `None` if neither exists, and an error if the one found can't be read or parsed.
+pub(crate) fn readconfig() -> Result<Option<NixDataConfig>> {
+    let userconfig = userconfigdir().map(|dir| dir.join("config.json"));
+    // Check if user config exists, then system config
+    let Some(path) = userconfig.filter(|path| path.exists()).or_else(|| {
+        Path::new(SYSCONFIG)
+            .exists()
+            .then(|| PathBuf::from(SYSCONFIG))
+    }) else {
+        return Ok(None);
+    };
+    let config: NixDataConfig = serde_json::from_reader(BufReader::new(File::open(&path)?))
+        .with_context(|| format!("Failed to parse {}", path.display()))?;
+    Ok(Some(config))
 }
 
 /// Writes the config struct to the config file in the user config directory (`~/.config/nix-data`).
 pub fn setuserconfig(config: NixDataConfig) -> Result<()> {
+    let configdir = userconfigdir().context("No user config directory found")?;
     // Check if config directory exists
-    if !Path::new(&*CONFIGDIR).exists() {
-        fs::create_dir_all(&*CONFIGDIR)?;
+    if !configdir.exists() {
+        fs::create_dir_all(&configdir)?;
     }
 
     // Write user config
-    let mut file = File::create(&*CONFIG)?;
+    let mut file = File::create(configdir.join("config.json"))?;
     file.write_all(serde_json::to_string_pretty(&config)?.as_bytes())?;
     Ok(())
 }
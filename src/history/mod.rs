@@ -0,0 +1,402 @@
+use anyhow::{Context, Result, anyhow};
+use log::debug;
+use sqlx::{
+    Connection, Row, SqliteConnection, SqlitePool,
+    sqlite::{SqliteConnectOptions, SqliteRow},
+};
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+};
+
+/// A nixpkgs revision recorded in a [HistoryDb].
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct HistoryRevision {
+    /// Revision, as published in the registry, which is the nixpkgs commit hash.
+    pub revision: String,
+    /// Release the revision belongs to, such as `25.11` or `unstable`.
+    pub release: String,
+    /// Date of the revision as `YYYYMMDD`, such as its commit date, as given when it was ingested.
+    pub date: String,
+}
+
+impl HistoryRevision {
+    fn fromrow(row: &SqliteRow) -> Result<Self> {
+        Ok(HistoryRevision {
+            revision: row.try_get("revision")?,
+            release: row.try_get("release")?,
+            date: row.try_get("date")?,
+        })
+    }
+}
+
+/// A version a package had in a run of consecutive revisions, as returned by [HistoryDb::timeline()].
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct VersionSpan {
+    /// Version of the package.
+    pub version: String,
+    /// First revision of the run.
+    pub first: HistoryRevision,
+    /// Last revision of the run.
+    pub last: HistoryRevision,
+    /// Number of revisions in the run.
+    pub revisions: usize,
+}
+
+/// The history store returned by [historydb()](crate::cache::history::historydb),
+/// holding the version of every package in each ingested nixpkgs revision.
+///
+/// Revisions are ordered by their date, revisions of the same day in the order they were ingested.
+#[derive(Clone, Debug)]
+pub struct HistoryDb {
+    pool: SqlitePool,
+}
+
+impl HistoryDb {
+    /// Opens the history store at `path` read-only.
+    pub async fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let options = SqliteConnectOptions::new()
+            .filename(path.as_ref())
+            .read_only(true);
+        let pool = SqlitePool::connect_with(options)
+            .await
+            .with_context(|| format!("Failed to open {}", path.as_ref().display()))?;
+        Ok(HistoryDb { pool })
+    }
+
+    /// Returns all ingested revisions, oldest first.
+    pub async fn revisions(&self) -> Result<Vec<HistoryRevision>> {
+        sqlx::query("SELECT * FROM revisions ORDER BY date, position")
+            .fetch_all(&self.pool)
+            .await?
+            .iter()
+            .map(HistoryRevision::fromrow)
+            .collect()
+    }
+
+    /// Returns the versions the package at `attr` had, oldest first.
+    /// A version gets a new span whenever it changes or the package is missing from a revision in between,
+    /// so a version that came back after a downgrade is listed twice.
+    /// Returns nothing if the package is in none of the revisions.
+    pub async fn timeline(&self, attr: &str) -> Result<Vec<VersionSpan>> {
+        let rows = sqlx::query(
+            r#"
+            SELECT revisions.*, versions.version AS version FROM revisions
+            LEFT JOIN versions ON versions.position = revisions.position AND versions.attribute = $1
+            ORDER BY revisions.date, revisions.position
+            "#,
+        )
+        .bind(attr)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut spans: Vec<VersionSpan> = Vec::new();
+        let mut previous: Option<String> = None;
+        for row in &rows {
+            let version: Option<String> = row.try_get("version")?;
+            let revision = HistoryRevision::fromrow(row)?;
+            match (&version, spans.last_mut()) {
+                (Some(version), Some(span)) if previous.as_ref() == Some(version) => {
+                    span.last = revision;
+                    span.revisions += 1;
+                }
+                (Some(version), _) => spans.push(VersionSpan {
+                    version: version.clone(),
+                    first: revision.clone(),
+                    last: revision,
+                    revisions: 1,
+                }),
+                (None, _) => {}
+            }
+            previous = version;
+        }
+        Ok(spans)
+    }
+
+    /// Returns the oldest revision in which the package at `attr` had `version`, if any.
+    pub async fn first_with_version(
+        &self,
+        attr: &str,
+        version: &str,
+    ) -> Result<Option<HistoryRevision>> {
+        sqlx::query(
+            r#"
+            SELECT revisions.* FROM versions
+            JOIN revisions ON revisions.position = versions.position
+            WHERE versions.attribute = $1 AND versions.version = $2
+            ORDER BY revisions.date, revisions.position
+            LIMIT 1
+            "#,
+        )
+        .bind(attr)
+        .bind(version)
+        .fetch_optional(&self.pool)
+        .await?
+        .map(|row| HistoryRevision::fromrow(&row))
+        .transpose()
+    }
+}
+
+/// Returns the revisions already ingested into the history store at `dbfile`.
+/// A missing or unreadable store has none.
+pub(crate) async fn ingestedrevisions(dbfile: &Path) -> HashSet<String> {
+    async fn read(dbfile: &Path) -> Result<HashSet<String>> {
+        let options = SqliteConnectOptions::new().filename(dbfile).read_only(true);
+        let mut conn = SqliteConnection::connect_with(&options).await?;
+        let revisions: Vec<(String,)> = sqlx::query_as("SELECT revision FROM revisions")
+            .fetch_all(&mut conn)
+            .await?;
+        conn.close().await?;
+        Ok(revisions.into_iter().map(|(revision,)| revision).collect())
+    }
+    read(dbfile).await.unwrap_or_default()
+}
+
+/// Returns the newest revision by date in the history store at `dbfile`, if it has any.
+pub(crate) async fn latestrevision(dbfile: &Path) -> Result<Option<String>> {
+    let options = SqliteConnectOptions::new().filename(dbfile).read_only(true);
+    let mut conn = SqliteConnection::connect_with(&options).await?;
+    let revision: Option<(String,)> =
+        sqlx::query_as("SELECT revision FROM revisions ORDER BY date DESC, position DESC LIMIT 1")
+            .fetch_optional(&mut conn)
+            .await?;
+    conn.close().await?;
+    Ok(revision.map(|(revision,)| revision))
+}
+
+/// Adds the versions of all packages in `revision` of `release` to the history store at `dbfile`,
+/// creating it if needed. `date` is the date of the revision as `YYYYMMDD`, which orders it among the others.
+pub(crate) async fn ingest(
+    dbfile: &Path,
+    release: &str,
+    revision: &str,
+    date: &str,
+    pkgs: &HashMap<String, String>,
+) -> Result<()> {
+    if pkgs.is_empty() {
+        return Err(anyhow!("No packages found in revision {}", revision));
+    }
+    if date.len() != 8 || !date.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(anyhow!(
+            "Invalid date {:?} of revision {}, expected YYYYMMDD",
+            date,
+            revision
+        ));
+    }
+    let connectoptions = SqliteConnectOptions::new()
+        .filename(dbfile)
+        .create_if_missing(true);
+    let mut conn = SqliteConnection::connect_with(&connectoptions).await?;
+    let mut tx = conn.begin().await?;
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS "revisions" (
+            "position"	INTEGER NOT NULL,
+            "revision"	TEXT NOT NULL UNIQUE,
+            "release"	TEXT NOT NULL,
+            "date"	TEXT NOT NULL,
+            PRIMARY KEY("position")
+        )
+        "#,
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS "versions" (
+            "attribute"	TEXT NOT NULL,
+            "position"	INTEGER NOT NULL,
+            "version"	TEXT NOT NULL,
+            PRIMARY KEY("attribute", "position")
+        ) WITHOUT ROWID
+        "#,
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    let (position,): (i64,) =
+        sqlx::query_as("SELECT coalesce(max(position), 0) + 1 FROM revisions")
+            .fetch_one(&mut *tx)
+            .await?;
+    sqlx::query(
+        "INSERT INTO revisions (position, revision, release, date) VALUES ($1, $2, $3, $4)",
+    )
+    .bind(position)
+    .bind(revision)
+    .bind(release)
+    .bind(date)
+    .execute(&mut *tx)
+    .await
+    .with_context(|| format!("Revision {} is already in {}", revision, dbfile.display()))?;
+    for (attr, version) in pkgs {
+        sqlx::query("INSERT INTO versions (attribute, position, version) VALUES ($1, $2, $3)")
+            .bind(attr)
+            .bind(position)
+            .bind(version)
+            .execute(&mut *tx)
+            .await
+            .with_context(|| format!("Failed to insert {} into {}", attr, dbfile.display()))?;
+    }
+    tx.commit().await?;
+    conn.close().await?;
+    debug!(
+        "Ingested {} packages of {} into {}",
+        pkgs.len(),
+        revision,
+        dbfile.display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pkgs(pkgs: &[(&str, &str)]) -> HashMap<String, String> {
+        pkgs.iter()
+            .map(|(attr, version)| (attr.to_string(), version.to_string()))
+            .collect()
+    }
+
+    /// Ingests revisions out of date order, with `hello` downgraded and missing in between.
+    async fn historydb(path: &Path) -> Result<HistoryDb> {
+        for (revision, date, versions) in [
+            ("ddd", "20251201", pkgs(&[("hello", "2.12"), ("jq", "1.7")])),
+            ("aaa", "20250901", pkgs(&[("hello", "2.10"), ("jq", "1.6")])),
+            ("bbb", "20251001", pkgs(&[("hello", "2.12"), ("jq", "1.7")])),
+            ("ccc", "20251101", pkgs(&[("jq", "1.7")])),
+            ("eee", "20251215", pkgs(&[("hello", "2.11"), ("jq", "1.7")])),
+        ] {
+            ingest(path, "25.11", revision, date, &versions).await?;
+        }
+        HistoryDb::open(path).await
+    }
+
+    fn revision(revision: &str, date: &str) -> HistoryRevision {
+        HistoryRevision {
+            revision: revision.to_string(),
+            release: String::from("25.11"),
+            date: date.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn revisions_are_ordered_by_date() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("history.db");
+        let db = historydb(&path).await?;
+        let revisions = db
+            .revisions()
+            .await?
+            .into_iter()
+            .map(|revision| revision.revision)
+            .collect::<Vec<_>>();
+        assert_eq!(revisions, vec!["aaa", "bbb", "ccc", "ddd", "eee"]);
+        assert_eq!(latestrevision(&path).await?.as_deref(), Some("eee"));
+        assert_eq!(ingestedrevisions(&path).await.len(), 5);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn timeline_splits_on_changes_and_gaps() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let db = historydb(&dir.path().join("history.db")).await?;
+        let span = |version: &str, first, last, revisions| VersionSpan {
+            version: version.to_string(),
+            first,
+            last,
+            revisions,
+        };
+        assert_eq!(
+            db.timeline("hello").await?,
+            vec![
+                span(
+                    "2.10",
+                    revision("aaa", "20250901"),
+                    revision("aaa", "20250901"),
+                    1
+                ),
+                span(
+                    "2.12",
+                    revision("bbb", "20251001"),
+                    revision("bbb", "20251001"),
+                    1
+                ),
+                span(
+                    "2.12",
+                    revision("ddd", "20251201"),
+                    revision("ddd", "20251201"),
+                    1
+                ),
+                span(
+                    "2.11",
+                    revision("eee", "20251215"),
+                    revision("eee", "20251215"),
+                    1
+                ),
+            ]
+        );
+        assert_eq!(
+            db.timeline("jq").await?,
+            vec![
+                span(
+                    "1.6",
+                    revision("aaa", "20250901"),
+                    revision("aaa", "20250901"),
+                    1
+                ),
+                span(
+                    "1.7",
+                    revision("bbb", "20251001"),
+                    revision("eee", "20251215"),
+                    4
+                ),
+            ]
+        );
+        assert!(db.timeline("missing").await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn first_with_version_uses_dates() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let db = historydb(&dir.path().join("history.db")).await?;
+        assert_eq!(
+            db.first_with_version("hello", "2.12").await?,
+            Some(revision("bbb", "20251001"))
+        );
+        assert_eq!(
+            db.first_with_version("hello", "2.11").await?,
+            Some(revision("eee", "20251215"))
+        );
+        assert_eq!(db.first_with_version("hello", "3.0").await?, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn ingest_rejects_invalid_revisions() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("history.db");
+        let versions = pkgs(&[("hello", "2.12")]);
+        for date in ["", "2025-10-01", "2025100", "202510011"] {
+            assert!(
+                ingest(&path, "25.11", "aaa", date, &versions)
+                    .await
+                    .is_err()
+            );
+        }
+        assert!(
+            ingest(&path, "25.11", "aaa", "20251001", &HashMap::new())
+                .await
+                .is_err()
+        );
+        ingest(&path, "25.11", "aaa", "20251001", &versions).await?;
+        assert!(
+            ingest(&path, "25.11", "aaa", "20251002", &versions)
+                .await
+                .is_err()
+        );
+        assert_eq!(HistoryDb::open(&path).await?.revisions().await?.len(), 1);
+        Ok(())
+    }
+}
@@ -12,12 +12,15 @@
 //! extern crate nix_data_xinux;
 //!
 //! fn main() {
-//!     let userpkgs = nix_data_xinux::cache::profile::getprofilepkgs_versioned();
+//!     let Ok(store) = nix_data_xinux::cache::CacheStore::user() else {
+//!         return;
+//!     };
+//!     let userpkgs = nix_data_xinux::cache::profile::getprofilepkgs(&store);
 //!     if let Ok(pkgs) = userpkgs {
 //!         println!("List of installed nix profile packages");
 //!         println!("===");
-//!         for (pkg, version) in pkgs {
-//!             println!("{}: {}", pkg, version);
+//!         for (pkg, info) in pkgs {
+//!             println!("{}: {}", pkg, info.name);
 //!         }
 //!     }
 //! }
@@ -27,13 +30,19 @@
 pub mod cache;
 /// A module for managing the configuration containing user and system options.
 pub mod config;
+/// A module for querying package versions across nixpkgs revisions.
+pub mod history;
+/// A module for querying indexed NixOS and Home Manager options.
+pub mod options;
+/// A module for querying cached package databases.
+pub mod packages;
+/// A module for finding the packages that provide a command.
+pub mod programs;
+/// A module for choosing where registry artifacts are fetched from.
+pub mod registry;
+/// A module for detecting the running system, such as its NixOS version.
+pub mod system;
 
 pub mod utils;
 
-lazy_static::lazy_static! {
-    static ref CACHEDIR: String = format!("{}/.cache/nix-data", std::env::var("HOME").unwrap());
-    static ref CONFIGDIR: String = format!("{}/.config/nix-data", std::env::var("HOME").unwrap());
-    static ref CONFIG: String = format!("{}/config.json", &*CONFIGDIR);
-    static ref HOME: String = std::env::var("HOME").unwrap();
-}
 static SYSCONFIG: &str = "/etc/nix-data/config.json";
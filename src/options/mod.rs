@@ -0,0 +1,554 @@
+use crate::utils::ftsquery;
+use anyhow::{Context, Result, anyhow};
+use log::debug;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::{Connection, FromRow, SqliteConnection, SqlitePool, sqlite::SqliteConnectOptions};
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::Read,
+    path::Path,
+};
+
+/// Where an option is declared. Both kinds are kept in the same [OptionsDb].
+#[derive(Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug, Hash)]
+#[serde(rename_all = "kebab-case")]
+pub enum OptionSource {
+    /// Options of NixOS modules, set in `configuration.nix`.
+    Nixos,
+    /// Options of Home Manager modules, set in `home.nix`.
+    HomeManager,
+}
+
+impl OptionSource {
+    /// All sources of options.
+    pub const ALL: [OptionSource; 2] = [OptionSource::Nixos, OptionSource::HomeManager];
+
+    /// Name the source is stored as, such as `home-manager`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            OptionSource::Nixos => "nixos",
+            OptionSource::HomeManager => "home-manager",
+        }
+    }
+
+    fn fromname(name: &str) -> Option<Self> {
+        OptionSource::ALL
+            .into_iter()
+            .find(|source| source.name() == name)
+    }
+}
+
+/// A single option from `options.json`, of NixOS or Home Manager.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct NixosOption {
+    /// Where the option is declared.
+    pub source: OptionSource,
+    /// Full name of the option, such as `services.openssh.enable`.
+    pub name: String,
+    /// Description of the option's type, such as `boolean` or `list of string`.
+    pub optiontype: Option<String>,
+    /// Default value, as a Nix expression or JSON.
+    pub default: Option<String>,
+    /// Example value, as a Nix expression or JSON.
+    pub example: Option<String>,
+    /// Description of the option in Markdown.
+    pub description: Option<String>,
+    /// Files the option is declared in, such as `nixos/modules/services/networking/ssh/sshd.nix`.
+    pub declarations: Vec<String>,
+    /// Whether the option can't be set in a configuration.
+    pub readonly: bool,
+}
+
+/// A direct child of an option path, as returned by [OptionsDb::children()].
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct OptionNode {
+    /// Last component of the path, such as `enable`.
+    pub name: String,
+    /// Full path, such as `services.openssh.enable`.
+    pub path: String,
+    /// Whether the path is an option itself. Otherwise it only groups further options.
+    pub option: bool,
+}
+
+#[derive(FromRow)]
+struct OptionRow {
+    source: String,
+    name: String,
+    #[sqlx(rename = "type")]
+    optiontype: Option<String>,
+    default: Option<String>,
+    example: Option<String>,
+    description: Option<String>,
+    declarations: Option<String>,
+    readonly: bool,
+}
+
+impl OptionRow {
+    /// Converts the row, skipping sources unknown to this version.
+    fn into_option(self) -> Option<NixosOption> {
+        Some(NixosOption {
+            source: OptionSource::fromname(&self.source)?,
+            name: self.name,
+            optiontype: self.optiontype,
+            default: self.default,
+            example: self.example,
+            description: self.description,
+            declarations: self
+                .declarations
+                .and_then(|declarations| serde_json::from_str(&declarations).ok())
+                .unwrap_or_default(),
+            readonly: self.readonly,
+        })
+    }
+}
+
+/// An SQLite database of NixOS and Home Manager options, such as the one returned by
+/// [nixosoptionsdb()](crate::cache::nixos::nixosoptionsdb) and [homemanageroptionsdb()](crate::cache::homemanager::homemanageroptionsdb).
+///
+/// Queries cover every source in the database, unless restricted with [with_source()](OptionsDb::with_source).
+#[derive(Clone, Debug)]
+pub struct OptionsDb {
+    pool: SqlitePool,
+    source: Option<OptionSource>,
+}
+
+const COLUMNS: &str =
+    r#"source, name, type, "default", example, description, declarations, readonly"#;
+
+impl OptionsDb {
+    /// Opens the options database at `path` read-only.
+    pub async fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let options = SqliteConnectOptions::new()
+            .filename(path.as_ref())
+            .read_only(true);
+        let pool = SqlitePool::connect_with(options)
+            .await
+            .with_context(|| format!("Failed to open {}", path.as_ref().display()))?;
+        Ok(OptionsDb { pool, source: None })
+    }
+
+    /// Restricts all queries to options of `source`.
+    pub fn with_source(mut self, source: OptionSource) -> Self {
+        self.source = Some(source);
+        self
+    }
+
+    /// Sources that have been indexed into the database, with the revision of their options.
+    pub async fn sources(&self) -> Result<Vec<(OptionSource, String)>> {
+        let rows: Vec<(String, String)> =
+            sqlx::query_as("SELECT source, revision FROM sources ORDER BY source")
+                .fetch_all(&self.pool)
+                .await?;
+        Ok(rows
+            .into_iter()
+            .filter_map(|(source, revision)| Some((OptionSource::fromname(&source)?, revision)))
+            .collect())
+    }
+
+    /// Returns the option `name`, if it exists.
+    /// If both sources declare it, the NixOS option is returned, see [with_source()](OptionsDb::with_source).
+    pub async fn get(&self, name: &str) -> Result<Option<NixosOption>> {
+        let rows: Vec<OptionRow> = sqlx::query_as(&format!(
+            "SELECT {} FROM options WHERE name = $1 AND ($2 IS NULL OR source = $2)",
+            COLUMNS
+        ))
+        .bind(name)
+        .bind(self.sourcename())
+        .fetch_all(&self.pool)
+        .await?;
+        let mut options = rows
+            .into_iter()
+            .filter_map(OptionRow::into_option)
+            .collect::<Vec<_>>();
+        options.sort_by_key(|option| option.source);
+        Ok(options.into_iter().next())
+    }
+
+    /// Returns up to `limit` options whose names start with `prefix`, sorted by name.
+    pub async fn prefix(&self, prefix: &str, limit: u32) -> Result<Vec<NixosOption>> {
+        // Range comparisons use the indexes, unlike LIKE or substr()
+        let end = format!("{}\u{10FFFF}", prefix);
+        let rows: Vec<OptionRow> = sqlx::query_as(&format!(
+            "SELECT {} FROM options WHERE name >= $1 AND name < $2 AND ($3 IS NULL OR source = $3) \
+             ORDER BY name, source LIMIT $4",
+            COLUMNS
+        ))
+        .bind(prefix)
+        .bind(&end)
+        .bind(self.sourcename())
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .filter_map(OptionRow::into_option)
+            .collect())
+    }
+
+    /// Searches option names and descriptions for all words of `query`, best matches first.
+    /// Words are matched as prefixes, so `open fire` finds `services.openssh.openFirewall`.
+    pub async fn search(&self, query: &str, limit: u32) -> Result<Vec<NixosOption>> {
+        let Some(query) = ftsquery(query) else {
+            return Ok(Vec::new());
+        };
+        let rows: Vec<OptionRow> = sqlx::query_as(&format!(
+            "SELECT {} FROM options_fts JOIN options ON options.rowid = options_fts.rowid \
+             WHERE options_fts MATCH $1 AND ($2 IS NULL OR options.source = $2) \
+             ORDER BY bm25(options_fts, 10.0, 1.0) LIMIT $3",
+            COLUMNS
+                .split(", ")
+                .map(|column| format!("options.{}", column))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ))
+        .bind(query)
+        .bind(self.sourcename())
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .filter_map(OptionRow::into_option)
+            .collect())
+    }
+
+    /// Lists the direct children of the option path `path`, such as `enable` and `settings` for `services.openssh`.
+    /// An empty path lists the top-level names.
+    pub async fn children(&self, path: &str) -> Result<Vec<OptionNode>> {
+        let prefix = if path.is_empty() {
+            String::new()
+        } else {
+            format!("{}.", path)
+        };
+        let end = format!("{}\u{10FFFF}", prefix);
+        let names: Vec<(String,)> = sqlx::query_as(
+            "SELECT DISTINCT name FROM options WHERE name >= $1 AND name < $2 \
+             AND ($3 IS NULL OR source = $3) ORDER BY name",
+        )
+        .bind(&prefix)
+        .bind(&end)
+        .bind(self.sourcename())
+        .fetch_all(&self.pool)
+        .await?;
+
+        // Children aren't contiguous when sorted, `a.b-c.x` comes between `a.b` and `a.b.c`
+        let mut nodes: BTreeMap<String, OptionNode> = BTreeMap::new();
+        for (name,) in names {
+            let rest = &name[prefix.len()..];
+            let Some(child) = splitpath(rest).into_iter().next() else {
+                continue;
+            };
+            let childpath = format!("{}{}", prefix, child);
+            let option = childpath == name;
+            nodes
+                .entry(childpath.clone())
+                .or_insert_with(|| OptionNode {
+                    name: child.to_string(),
+                    path: childpath,
+                    option: false,
+                })
+                .option |= option;
+        }
+        Ok(nodes.into_values().collect())
+    }
+
+    fn sourcename(&self) -> Option<&'static str> {
+        self.source.map(|source| source.name())
+    }
+}
+
+/// Splits an option path into its components, keeping quoted components like `"net.ipv4.ip_forward"` intact.
+pub fn splitpath(path: &str) -> Vec<&str> {
+    let mut out = Vec::new();
+    let mut start = 0;
+    let mut quoted = false;
+    for (i, c) in path.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '.' if !quoted => {
+                out.push(&path[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    if start < path.len() {
+        out.push(&path[start..]);
+    }
+    out
+}
+
+/// An option as found in `options.json`.
+#[derive(Deserialize)]
+struct JsonOption {
+    #[serde(rename = "type")]
+    optiontype: Option<String>,
+    default: Option<Value>,
+    example: Option<Value>,
+    description: Option<Value>,
+    #[serde(default)]
+    declarations: Vec<Value>,
+    #[serde(rename = "readOnly", default)]
+    readonly: bool,
+}
+
+/// Renders a default or example value: literal expressions as their text, anything else as JSON.
+fn rendervalue(value: Value) -> String {
+    match value {
+        Value::Object(ref map)
+            if map.contains_key("_type")
+                && let Some(Value::String(text)) = map.get("text") =>
+        {
+            text.clone()
+        }
+        other => other.to_string(),
+    }
+}
+
+/// Renders a description, which older releases wrap like literal values.
+fn rendertext(value: Value) -> String {
+    match value {
+        Value::String(text) => text,
+        other => rendervalue(other),
+    }
+}
+
+/// Reads `options.json`, which may still be brotli compressed as published.
+pub(crate) fn readoptions(path: &Path) -> Result<HashMap<String, Value>> {
+    let data = std::fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    if data.iter().find(|b| !b.is_ascii_whitespace()) == Some(&b'{') {
+        return Ok(serde_json::from_slice(&data)?);
+    }
+    let mut json = Vec::new();
+    brotli::Decompressor::new(data.as_slice(), 4096)
+        .read_to_end(&mut json)
+        .context("Failed to decompress brotli data")?;
+    Ok(serde_json::from_slice(&json)?)
+}
+
+/// Returns the revision of `source` indexed into the options database `dbfile`,
+/// or `None` if the database or the source are missing.
+pub(crate) async fn indexedrevision(dbfile: &Path, source: OptionSource) -> Option<String> {
+    let db = OptionsDb::open(dbfile).await.ok()?;
+    let revision = db
+        .sources()
+        .await
+        .ok()?
+        .into_iter()
+        .find(|(indexed, _)| *indexed == source)
+        .map(|(_, revision)| revision);
+    db.pool.close().await;
+    revision
+}
+
+/// Replaces the options of `source` in the options database `dbfile` with the contents of `options.json`,
+/// in a single transaction. The database is created if `dbfile` is empty.
+/// Options of other sources are kept.
+pub(crate) async fn updatedb(
+    dbfile: &Path,
+    source: OptionSource,
+    revision: &str,
+    options: HashMap<String, Value>,
+) -> Result<()> {
+    if options.is_empty() {
+        return Err(anyhow!("No options found"));
+    }
+    let connectoptions = SqliteConnectOptions::new()
+        .filename(dbfile)
+        .create_if_missing(true);
+    let mut conn = SqliteConnection::connect_with(&connectoptions).await?;
+    let mut tx = conn.begin().await?;
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS "options" (
+            "source"	TEXT NOT NULL,
+            "name"	TEXT NOT NULL,
+            "type"	TEXT,
+            "default"	TEXT,
+            "example"	TEXT,
+            "description"	TEXT,
+            "declarations"	TEXT,
+            "readonly"	INTEGER NOT NULL DEFAULT 0,
+            PRIMARY KEY("source", "name")
+        )
+        "#,
+    )
+    .execute(&mut *tx)
+    .await?;
+    // The primary key only helps lookups within one source
+    sqlx::query(r#"CREATE INDEX IF NOT EXISTS "names" ON "options" ("name")"#)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS "sources" (
+            "source"	TEXT NOT NULL UNIQUE,
+            "revision"	TEXT NOT NULL,
+            PRIMARY KEY("source")
+        )
+        "#,
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query(
+        r#"
+        CREATE VIRTUAL TABLE IF NOT EXISTS "options_fts" USING fts5(
+            name, description, content='options', content_rowid='rowid'
+        )
+        "#,
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query("DELETE FROM options WHERE source = $1")
+        .bind(source.name())
+        .execute(&mut *tx)
+        .await?;
+
+    let count = options.len();
+    for (name, option) in options {
+        let option: JsonOption =
+            serde_json::from_value(option).with_context(|| format!("Invalid option {}", name))?;
+        let declarations = option
+            .declarations
+            .into_iter()
+            .filter_map(|declaration| match declaration {
+                Value::String(file) => Some(file),
+                // Some releases publish `{ name, url }` pairs
+                Value::Object(map) => map.get("name").and_then(Value::as_str).map(str::to_string),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        sqlx::query(&format!(
+            "INSERT INTO options ({}) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+            COLUMNS
+        ))
+        .bind(source.name())
+        .bind(&name)
+        .bind(option.optiontype)
+        .bind(option.default.map(rendervalue))
+        .bind(option.example.map(rendervalue))
+        .bind(option.description.map(rendertext))
+        .bind(serde_json::to_string(&declarations)?)
+        .bind(option.readonly)
+        .execute(&mut *tx)
+        .await
+        .with_context(|| format!("Failed to insert {} into {}", name, dbfile.display()))?;
+    }
+    sqlx::query("INSERT OR REPLACE INTO sources (source, revision) VALUES ($1, $2)")
+        .bind(source.name())
+        .bind(revision)
+        .execute(&mut *tx)
+        .await?;
+    // The index is external content, so it is rebuilt from the table after every change
+    sqlx::query("INSERT INTO options_fts(options_fts) VALUES('rebuild')")
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    conn.close().await?;
+    debug!(
+        "Indexed {} {} options into {}",
+        count,
+        source.name(),
+        dbfile.display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    async fn optionsdb(dir: &Path, names: &[&str]) -> Result<OptionsDb> {
+        let path = dir.join("options.db");
+        let options = names
+            .iter()
+            .map(|name| (name.to_string(), json!({ "type": "boolean" })))
+            .collect();
+        updatedb(
+            &path,
+            OptionSource::Nixos,
+            "25.11.20251020.abcdef0",
+            options,
+        )
+        .await?;
+        OptionsDb::open(&path).await
+    }
+
+    fn node(name: &str, path: &str, option: bool) -> OptionNode {
+        OptionNode {
+            name: name.to_string(),
+            path: path.to_string(),
+            option,
+        }
+    }
+
+    #[tokio::test]
+    async fn children_merges_split_entries() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let db = optionsdb(dir.path(), &["a.b", "a.b-c.x", "a.b.c", "ab.c"]).await?;
+        assert_eq!(
+            db.children("a").await?,
+            vec![node("b", "a.b", true), node("b-c", "a.b-c", false)]
+        );
+        assert_eq!(db.children("a.b").await?, vec![node("c", "a.b.c", true)]);
+        assert_eq!(
+            db.children("").await?,
+            vec![node("a", "a", false), node("ab", "ab", false)]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn children_keeps_quoted_components() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let db = optionsdb(
+            dir.path(),
+            &[
+                "boot.kernel.sysctl.\"net.ipv4.ip_forward\"",
+                "boot.kernel.sysctl.\"net.ipv4.ip_forward\".x",
+            ],
+        )
+        .await?;
+        assert_eq!(
+            db.children("boot.kernel.sysctl").await?,
+            vec![node(
+                "\"net.ipv4.ip_forward\"",
+                "boot.kernel.sysctl.\"net.ipv4.ip_forward\"",
+                true
+            )]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn prefix_uses_name_ranges() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let db = optionsdb(dir.path(), &["a.b", "a.b-c.x", "a.b.c", "ab.c", "b"]).await?;
+        let names = |options: Vec<NixosOption>| {
+            options
+                .into_iter()
+                .map(|option| option.name)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            names(db.prefix("a.b", 10).await?),
+            vec!["a.b", "a.b-c.x", "a.b.c"]
+        );
+        assert_eq!(names(db.prefix("a.b", 2).await?), vec!["a.b", "a.b-c.x"]);
+        assert_eq!(names(db.prefix("", 10).await?).len(), 5);
+        assert!(db.prefix("c", 10).await?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn splitpath_keeps_quotes() {
+        assert_eq!(
+            splitpath("services.\"a.b\".enable"),
+            vec!["services", "\"a.b\"", "enable"]
+        );
+        assert_eq!(splitpath("a..b"), vec!["a", "", "b"]);
+        assert!(splitpath("").is_empty());
+    }
+}
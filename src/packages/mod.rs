@@ -0,0 +1,1656 @@
+use crate::{
+    options::splitpath,
+    utils::{editdistance, ftsquery},
+};
+use anyhow::{Context, Result};
+use log::debug;
+use serde::Deserialize;
+use serde_json::Value;
+use sqlx::{
+    Connection, Row, SqliteConnection, SqlitePool,
+    sqlite::{SqliteConnectOptions, SqliteRow},
+};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    path::Path,
+};
+
+/// Indexes created by [createindex()]: the full-text index of `pkgs` and `meta`
+/// and the trigram index of attribute paths and names.
+const SEARCH_TABLES: &[&str] = &["pkgs_fts", "pkgs_trigrams"];
+
+/// A package in a package database.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Package {
+    /// Attribute path of the package, such as `firefox` or `python3Packages.requests`.
+    pub attribute: String,
+    /// Name of the package without its version, such as `firefox`.
+    /// Databases built from `packages.json` of a legacy system don't record it.
+    pub pname: Option<String>,
+    /// Version of the package.
+    pub version: Option<String>,
+    /// Platform the database was generated for, such as `x86_64-linux`.
+    pub system: Option<String>,
+}
+
+impl Package {
+    fn fromrow(row: &SqliteRow) -> Result<Self> {
+        Ok(Package {
+            attribute: row.try_get("attribute")?,
+            pname: row.try_get("pname").ok().flatten(),
+            version: row.try_get("version").ok().flatten(),
+            system: row.try_get("system").ok().flatten(),
+        })
+    }
+}
+
+/// Metadata of a package from the `meta` table.
+#[derive(PartialEq, Eq, Clone, Debug, Default)]
+pub struct PackageMeta {
+    /// Attribute path of the package.
+    pub attribute: String,
+    /// One line description.
+    pub description: Option<String>,
+    /// Longer description in Markdown.
+    pub longdescription: Option<String>,
+    /// Homepage of the project.
+    pub homepage: Option<String>,
+    /// Name of the main executable, used by `nix run`.
+    pub mainprogram: Option<String>,
+    /// File and line in nixpkgs the package is defined at, such as `pkgs/by-name/fi/firefox/package.nix:10`.
+    pub position: Option<String>,
+    /// Whether the package is marked as broken.
+    pub broken: bool,
+    /// Whether the package has known vulnerabilities.
+    pub insecure: bool,
+    /// Whether the package is unsupported on the platform the database was generated for.
+    pub unsupported: bool,
+    /// Whether the package has an unfree license.
+    pub unfree: bool,
+    /// Licenses of the package, usually one.
+    pub licenses: Vec<License>,
+    /// People maintaining the package in nixpkgs.
+    pub maintainers: Vec<Maintainer>,
+    /// Platforms the package builds on, such as `x86_64-linux`.
+    /// Empty if it isn't restricted to any.
+    pub platforms: Vec<String>,
+    /// Whether `meta.platforms` also has patterns such as `{ kernel.name = "linux"; }`,
+    /// which have no name to be listed in [platforms](PackageMeta::platforms).
+    pub platformpatterns: bool,
+    /// Platforms the package is known not to build on, from `meta.badPlatforms`.
+    pub badplatforms: Vec<String>,
+    /// Outputs of the package, such as `out` and `man`.
+    /// Empty if the database doesn't record them.
+    pub outputs: Vec<String>,
+}
+
+/// A license from `meta.license`, such as `lib.licenses.mit`.
+#[derive(Deserialize, PartialEq, Eq, Clone, Debug, Default)]
+pub struct License {
+    /// SPDX identifier, such as `MIT`.
+    #[serde(rename = "spdxId")]
+    pub spdxid: Option<String>,
+    /// Short name used in nixpkgs, such as `mit`.
+    #[serde(rename = "shortName")]
+    pub shortname: Option<String>,
+    /// Full name, such as `MIT License`.
+    #[serde(rename = "fullName")]
+    pub fullname: Option<String>,
+    /// Where the license text can be read.
+    pub url: Option<String>,
+    /// Whether the license is free. Packages without a free license need `allowUnfree`.
+    pub free: bool,
+    /// Whether the package may be distributed in binary form, such as by the binary cache.
+    pub redistributable: Option<bool>,
+}
+
+impl License {
+    /// Most readable name of the license.
+    pub fn name(&self) -> Option<&str> {
+        self.fullname
+            .as_deref()
+            .or(self.spdxid.as_deref())
+            .or(self.shortname.as_deref())
+    }
+}
+
+/// A maintainer from `meta.maintainers`, as listed in `maintainers/maintainer-list.nix`.
+#[derive(Deserialize, PartialEq, Eq, Clone, Debug, Default)]
+pub struct Maintainer {
+    /// Full name.
+    pub name: Option<String>,
+    /// Email address.
+    pub email: Option<String>,
+    /// GitHub user name.
+    pub github: Option<String>,
+    /// GitHub user id, which unlike the user name never changes.
+    #[serde(rename = "githubId")]
+    pub githubid: Option<u64>,
+    /// Matrix id, such as `@user:example.org`.
+    pub matrix: Option<String>,
+}
+
+impl PackageMeta {
+    fn fromrow(row: &SqliteRow) -> Result<Self> {
+        let flag = |column: &str| {
+            row.try_get::<Option<i64>, _>(column)
+                .ok()
+                .flatten()
+                .is_some_and(|value| value != 0)
+        };
+        let text = |column: &str| row.try_get::<Option<String>, _>(column).ok().flatten();
+        let json = |column: &str| {
+            text(column)
+                .and_then(|text| serde_json::from_str::<Value>(&text).ok())
+                .unwrap_or(Value::Null)
+        };
+        let unfree = flag("unfree");
+        let platforms = list(json("platforms"));
+        Ok(PackageMeta {
+            attribute: row.try_get("attribute")?,
+            description: text("description"),
+            longdescription: text("longdescription"),
+            homepage: text("homepage"),
+            mainprogram: text("mainprogram"),
+            position: text("position"),
+            broken: flag("broken"),
+            insecure: flag("insecure"),
+            unsupported: flag("unsupported"),
+            unfree,
+            licenses: list(json("license"))
+                .into_iter()
+                .filter_map(|license| match license {
+                    // Free form licenses, such as "unfree", only say as much as the unfree flag
+                    Value::String(name) => Some(License {
+                        fullname: Some(name),
+                        free: !unfree,
+                        ..Default::default()
+                    }),
+                    Value::Object(mut fields) => {
+                        fields.entry("free").or_insert(Value::Bool(!unfree));
+                        serde_json::from_value(Value::Object(fields)).ok()
+                    }
+                    _ => None,
+                })
+                .collect(),
+            maintainers: list(json("maintainers"))
+                .into_iter()
+                .filter_map(|maintainer| match maintainer {
+                    Value::String(name) => Some(Maintainer {
+                        name: Some(name),
+                        ..Default::default()
+                    }),
+                    maintainer => serde_json::from_value(maintainer).ok(),
+                })
+                .collect(),
+            platformpatterns: platforms.iter().any(|platform| !platform.is_string()),
+            // Platform patterns such as `{ kernel.name = "linux"; }` have no name to list
+            platforms: platforms
+                .into_iter()
+                .filter_map(|platform| platform.as_str().map(str::to_string))
+                .collect(),
+            badplatforms: list(json("badplatforms"))
+                .into_iter()
+                .filter_map(|platform| platform.as_str().map(str::to_string))
+                .collect(),
+            outputs: match json("outputs") {
+                Value::Object(outputs) => outputs.keys().cloned().collect(),
+                outputs => list(outputs)
+                    .into_iter()
+                    .filter_map(|output| output.as_str().map(str::to_string))
+                    .collect(),
+            },
+        })
+    }
+}
+
+impl PackageMeta {
+    /// Whether the package can be built on `system`, such as `aarch64-linux`:
+    /// it is listed in [platforms](PackageMeta::platforms), or that list is empty, and not in [badplatforms](PackageMeta::badplatforms).
+    /// [Platform patterns](PackageMeta::platformpatterns) can't be checked, so packages with any count as available,
+    /// the same as with [with_platform()](PackageDb::with_platform).
+    /// See [hostplatform()](crate::system::hostplatform) for the platform of the running system.
+    pub fn availableon(&self, system: &str) -> bool {
+        !self.badplatforms.iter().any(|platform| platform == system)
+            && (self.platforms.is_empty()
+                || self.platformpatterns
+                || self.platforms.iter().any(|platform| platform == system))
+    }
+}
+
+/// Returns the name of `package` closest to the lowercase `query` with its edit distance,
+/// out of the attribute path, the last component of it and the name of the package.
+fn closest(query: &str, package: &Package) -> Option<(usize, String)> {
+    [
+        Some(package.attribute.as_str()),
+        package.attribute.rsplit('.').next(),
+        package.pname.as_deref(),
+    ]
+    .into_iter()
+    .flatten()
+    .map(|name| (editdistance(query, &name.to_lowercase()), name.to_string()))
+    .min()
+}
+
+/// Sorts fuzzy matches best first, then by the shortest attribute path.
+fn sortmatches(matches: &mut [FuzzyMatch]) {
+    matches.sort_by(|a, b| {
+        (a.distance, a.package.attribute.len(), &a.package.attribute).cmp(&(
+            b.distance,
+            b.package.attribute.len(),
+            &b.package.attribute,
+        ))
+    });
+}
+
+/// Turns a `meta` value that is either a list or a single item into a list.
+fn list(value: Value) -> Vec<Value> {
+    match value {
+        Value::Array(items) => items,
+        Value::Null => Vec::new(),
+        item => vec![item],
+    }
+}
+
+/// A package found by [PackageDb::search()].
+#[derive(PartialEq, Clone, Debug)]
+pub struct SearchResult {
+    /// The package found.
+    pub package: Package,
+    /// One line description of the package.
+    pub description: Option<String>,
+    /// Attribute path with the matched words wrapped in the [markers](PackageDb::with_markers).
+    pub highlighted: String,
+    /// Part of the description around the matched words, wrapped in the markers.
+    /// Taken from the long description if only that matched.
+    pub snippet: Option<String>,
+    /// Relevance of the match, lower is better.
+    pub rank: f64,
+}
+
+/// A package found by [PackageDb::fuzzy()].
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct FuzzyMatch {
+    /// The package found.
+    pub package: Package,
+    /// Attribute path, last component of it or name of the package closest to the query.
+    pub matched: String,
+    /// Number of characters to insert, delete, replace or swap to turn the query into [matched](FuzzyMatch::matched).
+    pub distance: usize,
+}
+
+/// A direct child of an attribute path, as returned by [PackageDb::children()].
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct PackageNode {
+    /// Last component of the path, such as `requests`.
+    pub name: String,
+    /// Full attribute path, such as `python3Packages.requests`.
+    pub path: String,
+    /// Whether the path is a package itself.
+    pub package: bool,
+    /// Number of packages below the path. Package sets like `python3Packages` have some, plain packages none.
+    pub packages: u64,
+}
+
+/// Which packages searches look at, set with [PackageDb::with_scope()].
+#[derive(PartialEq, Eq, Clone, Debug, Default)]
+pub enum PackageScope {
+    /// All packages.
+    #[default]
+    All,
+    /// Only packages outside of package sets, such as `firefox` but not `python3Packages.requests`.
+    TopLevel,
+    /// Only packages inside the package set at this path, such as `python3Packages`.
+    Within(String),
+    /// All packages except those inside the package sets at these paths.
+    Excluding(Vec<String>),
+}
+
+/// A package database, such as the one returned by [nixospkgs()](crate::cache::nixos::nixospkgs),
+/// holding a connection pool for all queries.
+///
+/// Databases from the registry have a `pkgs` and a `meta` table, those built for legacy systems by
+/// [legacypkgs()](crate::cache::channel::legacypkgs) only the attribute and version of every package.
+#[derive(Clone, Debug)]
+pub struct PackageDb {
+    pool: SqlitePool,
+    markers: (String, String),
+    platform: Option<String>,
+    scope: PackageScope,
+    /// Columns of the `meta` table, empty if the database has none.
+    metacolumns: HashSet<String>,
+}
+
+impl PackageDb {
+    /// Opens the package database at `path` read-only.
+    pub async fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let options = SqliteConnectOptions::new()
+            .filename(path.as_ref())
+            .read_only(true);
+        let pool = SqlitePool::connect_with(options)
+            .await
+            .with_context(|| format!("Failed to open {}", path.as_ref().display()))?;
+        let metacolumns: Vec<(String,)> =
+            sqlx::query_as("SELECT name FROM pragma_table_info('meta')")
+                .fetch_all(&pool)
+                .await?;
+        Ok(PackageDb {
+            pool,
+            markers: (String::from("<b>"), String::from("</b>")),
+            platform: None,
+            scope: PackageScope::All,
+            metacolumns: metacolumns.into_iter().map(|(name,)| name).collect(),
+        })
+    }
+
+    /// Leaves packages that can't be built on `system`, such as `aarch64-linux`, out of all package queries,
+    /// as told by their `meta.platforms` and `meta.badPlatforms`. Metadata is still returned for them.
+    /// See [hostplatform()](crate::system::hostplatform) for the platform of the running system.
+    pub fn with_platform(mut self, system: impl Into<String>) -> Self {
+        self.platform = Some(system.into());
+        self
+    }
+
+    /// Restricts [search()](PackageDb::search), [complete()](PackageDb::complete) and [fuzzy()](PackageDb::fuzzy)
+    /// to the packages of `scope`, such as only Python libraries or only applications outside of package sets.
+    pub fn with_scope(mut self, scope: PackageScope) -> Self {
+        self.scope = scope;
+        self
+    }
+
+    /// SQL condition on `pkgs` keeping the packages in the [scope](PackageDb::with_scope),
+    /// which has to be bound as parameter `$n` with [scopeparam()](PackageDb::scopeparam).
+    fn inscope(&self, n: usize) -> String {
+        // Paths inside `set` sort between `set.` and `set/`
+        match self.scope {
+            PackageScope::All => format!("${n} IS NULL"),
+            PackageScope::TopLevel => format!("(${n} IS NULL AND instr(pkgs.attribute, '.') = 0)"),
+            PackageScope::Within(_) => {
+                format!("(pkgs.attribute > ${n} || '.' AND pkgs.attribute < ${n} || '/')")
+            }
+            PackageScope::Excluding(_) => format!(
+                "NOT EXISTS (SELECT 1 FROM json_each(${n}) \
+                 WHERE pkgs.attribute > value || '.' AND pkgs.attribute < value || '/')"
+            ),
+        }
+    }
+
+    fn scopeparam(&self) -> Result<Option<String>> {
+        Ok(match &self.scope {
+            PackageScope::All | PackageScope::TopLevel => None,
+            PackageScope::Within(set) => Some(set.clone()),
+            PackageScope::Excluding(sets) => Some(serde_json::to_string(sets)?),
+        })
+    }
+
+    /// SQL condition on `pkgs` keeping the packages available on the [platform](PackageDb::with_platform),
+    /// which has to be bound as parameter `$n`. Platform patterns such as `{ kernel.name = "linux"; }`
+    /// can't be checked, so packages listing any are kept.
+    fn available(&self, n: usize) -> String {
+        let mut excluded = Vec::new();
+        if self.metacolumns.contains("platforms") {
+            excluded.push(format!(
+                r#"(EXISTS (SELECT 1 FROM json_each(meta.platforms)) AND NOT EXISTS (
+                    SELECT 1 FROM json_each(meta.platforms) WHERE type != 'text' OR value = ${n}
+                ))"#
+            ));
+        }
+        if self.metacolumns.contains("badplatforms") {
+            excluded.push(format!(
+                "EXISTS (SELECT 1 FROM json_each(meta.badplatforms) WHERE value = ${n})"
+            ));
+        }
+        if excluded.is_empty() {
+            return format!("(${n} IS NULL OR 1)");
+        }
+        // Malformed JSON makes json_each() fail, so only valid values are looked at
+        format!(
+            r#"(${n} IS NULL OR NOT EXISTS (
+                SELECT 1 FROM (
+                    SELECT {platforms} AS platforms, {bad} AS badplatforms
+                    FROM meta WHERE meta.attribute = pkgs.attribute
+                ) AS meta
+                WHERE {excluded}
+            ))"#,
+            platforms = self.validjson("platforms"),
+            bad = self.validjson("badplatforms"),
+            excluded = excluded.join(" OR "),
+        )
+    }
+
+    /// SQL expression for the JSON list in the `meta` column `column`,
+    /// or an empty list if the value is malformed or the column is missing.
+    fn validjson(&self, column: &str) -> String {
+        if self.metacolumns.contains(column) {
+            format!("CASE WHEN json_valid({column}) THEN {column} ELSE '[]' END")
+        } else {
+            String::from("'[]'")
+        }
+    }
+
+    /// Sets the strings [search()](PackageDb::search) wraps matched words in. Defaults to `<b>` and `</b>`.
+    pub fn with_markers(mut self, start: impl Into<String>, end: impl Into<String>) -> Self {
+        self.markers = (start.into(), end.into());
+        self
+    }
+
+    /// Returns the package at `attr`, if it exists.
+    pub async fn get(&self, attr: &str) -> Result<Option<Package>> {
+        sqlx::query(&format!(
+            "SELECT * FROM pkgs WHERE attribute = $1 AND {}",
+            self.available(2)
+        ))
+        .bind(attr)
+        .bind(&self.platform)
+        .fetch_optional(&self.pool)
+        .await?
+        .map(|row| Package::fromrow(&row))
+        .transpose()
+    }
+
+    /// Returns the packages of `attrs` that exist, keyed by attribute, in a single query.
+    pub async fn get_many<S: AsRef<str>>(&self, attrs: &[S]) -> Result<HashMap<String, Package>> {
+        let attrs = serde_json::to_string(&attrs.iter().map(AsRef::as_ref).collect::<Vec<_>>())?;
+        sqlx::query(&format!(
+            "SELECT * FROM pkgs WHERE attribute IN (SELECT value FROM json_each($1)) AND {}",
+            self.available(2)
+        ))
+        .bind(attrs)
+        .bind(&self.platform)
+        .fetch_all(&self.pool)
+        .await?
+        .iter()
+        .map(|row| Package::fromrow(row).map(|pkg| (pkg.attribute.clone(), pkg)))
+        .collect()
+    }
+
+    /// Returns all packages named `name`, such as `python3` and `python312` for `python3`, sorted by attribute.
+    pub async fn by_pname(&self, name: &str) -> Result<Vec<Package>> {
+        sqlx::query(&format!(
+            "SELECT * FROM pkgs WHERE pname = $1 AND {} ORDER BY attribute",
+            self.available(2)
+        ))
+        .bind(name)
+        .bind(&self.platform)
+        .fetch_all(&self.pool)
+        .await?
+        .iter()
+        .map(Package::fromrow)
+        .collect()
+    }
+
+    /// Returns every version of the package at `attr` available in the database:
+    /// all packages with the same name, such as `nodejs_20` and `nodejs_22` for `nodejs`, sorted by attribute.
+    /// Returns nothing if `attr` doesn't exist.
+    pub async fn versions_of(&self, attr: &str) -> Result<Vec<Package>> {
+        match self.get(attr).await? {
+            Some(Package {
+                pname: Some(pname), ..
+            }) => self.by_pname(&pname).await,
+            Some(pkg) => Ok(vec![pkg]),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Returns the metadata of the package at `attr`, if the database has any for it.
+    pub async fn meta(&self, attr: &str) -> Result<Option<PackageMeta>> {
+        if self.metacolumns.is_empty() {
+            return Ok(None);
+        }
+        sqlx::query("SELECT * FROM meta WHERE attribute = $1")
+            .bind(attr)
+            .fetch_optional(&self.pool)
+            .await?
+            .map(|row| PackageMeta::fromrow(&row))
+            .transpose()
+    }
+
+    /// Returns the metadata of the packages of `attrs` that have any, keyed by attribute, in a single query.
+    pub async fn meta_many<S: AsRef<str>>(
+        &self,
+        attrs: &[S],
+    ) -> Result<HashMap<String, PackageMeta>> {
+        if self.metacolumns.is_empty() {
+            return Ok(HashMap::new());
+        }
+        let attrs = serde_json::to_string(&attrs.iter().map(AsRef::as_ref).collect::<Vec<_>>())?;
+        sqlx::query("SELECT * FROM meta WHERE attribute IN (SELECT value FROM json_each($1))")
+            .bind(attrs)
+            .fetch_all(&self.pool)
+            .await?
+            .iter()
+            .map(|row| PackageMeta::fromrow(row).map(|meta| (meta.attribute.clone(), meta)))
+            .collect()
+    }
+
+    /// Searches attribute paths, names and descriptions for all words of `query`, best matches first.
+    /// Words are matched as prefixes. Matches in the attribute path or name rank above matches in
+    /// descriptions, and a package whose attribute path equals `query` comes first.
+    /// `limit` and `offset` select a page of the results.
+    ///
+    /// Requires the search index built into databases of the revision store;
+    /// databases of legacy systems don't have one.
+    pub async fn search(&self, query: &str, limit: u32, offset: u32) -> Result<Vec<SearchResult>> {
+        let Some(ftsquery) = ftsquery(query) else {
+            return Ok(Vec::new());
+        };
+        let (start, end) = &self.markers;
+        // snippet() returns the start of a column even if nothing matched in it,
+        // so the long description is only taken if it has the markers and the description doesn't
+        let rows = sqlx::query(&format!(
+            r#"
+            SELECT pkgs.*, meta.description AS description,
+                highlight(pkgs_fts, 0, $2, $3) AS highlighted,
+                coalesce(
+                    CASE WHEN instr(snippet(pkgs_fts, 2, $2, $3, '…', 12), $2) = 0
+                        AND instr(snippet(pkgs_fts, 3, $2, $3, '…', 12), $2) > 0
+                    THEN snippet(pkgs_fts, 3, $2, $3, '…', 12) END,
+                    nullif(snippet(pkgs_fts, 2, $2, $3, '…', 12), ''),
+                    nullif(snippet(pkgs_fts, 3, $2, $3, '…', 12), '')
+                ) AS snippet,
+                bm25(pkgs_fts, 10.0, 10.0, 2.0, 1.0) AS rank
+            FROM pkgs_fts
+            JOIN pkgs ON pkgs.rowid = pkgs_fts.rowid
+            LEFT JOIN meta ON meta.attribute = pkgs.attribute
+            WHERE pkgs_fts MATCH $1 AND {} AND {}
+            ORDER BY pkgs.attribute = $4 DESC, rank
+            LIMIT $5 OFFSET $6
+            "#,
+            self.available(7),
+            self.inscope(8)
+        ))
+        .bind(&ftsquery)
+        .bind(start)
+        .bind(end)
+        .bind(query.trim())
+        .bind(limit)
+        .bind(offset)
+        .bind(&self.platform)
+        .bind(self.scopeparam()?)
+        .fetch_all(&self.pool)
+        .await
+        .context("Failed to search packages, the database may have no search index")?;
+        rows.iter()
+            .map(|row| {
+                Ok(SearchResult {
+                    package: Package::fromrow(row)?,
+                    description: row.try_get("description")?,
+                    highlighted: row.try_get("highlighted")?,
+                    snippet: row.try_get("snippet")?,
+                    rank: row.try_get("rank")?,
+                })
+            })
+            .collect()
+    }
+
+    /// Returns up to `limit` packages whose attribute path or name starts with `prefix`, shortest first.
+    /// Meant for completing what the user is typing, so matching is exact and cheap.
+    pub async fn complete(&self, prefix: &str, limit: u32) -> Result<Vec<Package>> {
+        if prefix.is_empty() {
+            return Ok(Vec::new());
+        }
+        // Range comparisons use the indexes, unlike LIKE or substr()
+        let end = format!("{}\u{10FFFF}", prefix);
+        sqlx::query(&format!(
+            r#"
+            SELECT * FROM pkgs WHERE rowid IN (
+                SELECT rowid FROM pkgs WHERE attribute >= $1 AND attribute < $2
+                UNION
+                SELECT rowid FROM pkgs WHERE pname >= $1 AND pname < $2
+            ) AND {} AND {}
+            ORDER BY length(attribute), attribute
+            LIMIT $3
+            "#,
+            self.available(4),
+            self.inscope(5)
+        ))
+        .bind(prefix)
+        .bind(&end)
+        .bind(limit)
+        .bind(&self.platform)
+        .bind(self.scopeparam()?)
+        .fetch_all(&self.pool)
+        .await?
+        .iter()
+        .map(Package::fromrow)
+        .collect()
+    }
+
+    /// Finds up to `limit` packages with an attribute path or name close to `query`, allowing for typos,
+    /// such as `libreoffice` for `libreofice`. Best matches come first, an exact match has a distance of 0.
+    /// Useful for "did you mean" messages when a package is not found.
+    ///
+    /// Candidates sharing the most trigrams with `query` are compared by edit distance,
+    /// ignoring case. Queries shorter than three characters are [completed](PackageDb::complete) instead,
+    /// then ranked by edit distance the same way.
+    /// Requires the index built into databases of the revision store.
+    pub async fn fuzzy(&self, query: &str, limit: u32) -> Result<Vec<FuzzyMatch>> {
+        let query = query.trim().to_lowercase();
+        let chars = query.chars().collect::<Vec<_>>();
+        if chars.len() < 3 {
+            let mut matches = self
+                .complete(&query, limit)
+                .await?
+                .into_iter()
+                .filter_map(|package| {
+                    let (distance, matched) = closest(&query, &package)?;
+                    Some(FuzzyMatch {
+                        package,
+                        matched,
+                        distance,
+                    })
+                })
+                .collect::<Vec<_>>();
+            sortmatches(&mut matches);
+            return Ok(matches);
+        }
+        let trigrams = chars
+            .windows(3)
+            .map(|trigram| {
+                let trigram = trigram.iter().collect::<String>();
+                format!("\"{}\"", trigram.replace('"', "\"\""))
+            })
+            .collect::<Vec<_>>();
+        let rows = sqlx::query(&format!(
+            r#"
+            SELECT pkgs.* FROM pkgs_trigrams
+            JOIN pkgs ON pkgs.rowid = pkgs_trigrams.rowid
+            WHERE pkgs_trigrams MATCH $1 AND {} AND {}
+            ORDER BY rank
+            LIMIT $2
+            "#,
+            self.available(3),
+            self.inscope(4)
+        ))
+        .bind(trigrams.join(" OR "))
+        .bind(limit.saturating_mul(10).max(50))
+        .bind(&self.platform)
+        .bind(self.scopeparam()?)
+        .fetch_all(&self.pool)
+        .await
+        .context("Failed to search packages, the database may have no search index")?;
+
+        // Allow more typos in longer names
+        let maxdistance = match chars.len() {
+            0..=4 => 1,
+            5..=8 => 2,
+            _ => 3,
+        };
+        let mut matches = Vec::new();
+        for row in &rows {
+            let package = Package::fromrow(row)?;
+            if let Some((distance, matched)) = closest(&query, &package)
+                && distance <= maxdistance
+            {
+                matches.push(FuzzyMatch {
+                    package,
+                    matched,
+                    distance,
+                });
+            }
+        }
+        sortmatches(&mut matches);
+        matches.truncate(limit as usize);
+        Ok(matches)
+    }
+
+    /// Lists the direct children of the attribute path `path` with the number of packages below each,
+    /// such as `requests` and `numpy` for `python3Packages`. An empty path lists the top level,
+    /// where package sets like `python3Packages` show up next to packages like `firefox`.
+    /// Sorted by name.
+    pub async fn children(&self, path: &str) -> Result<Vec<PackageNode>> {
+        let prefix = if path.is_empty() {
+            String::new()
+        } else {
+            format!("{}.", path)
+        };
+        let attrs: Vec<(String,)> = sqlx::query_as(&format!(
+            "SELECT attribute FROM pkgs WHERE ($1 = '' OR (attribute >= $1 AND attribute < $2)) AND {}",
+            self.available(3)
+        ))
+        .bind(&prefix)
+        .bind(format!("{}/", path))
+        .bind(&self.platform)
+        .fetch_all(&self.pool)
+        .await?;
+
+        // Children aren't contiguous when sorted, `a.b-c` comes between `a.b` and `a.b.c`
+        let mut nodes: BTreeMap<String, (bool, u64)> = BTreeMap::new();
+        for (attr,) in &attrs {
+            let rest = &attr[prefix.len()..];
+            let Some(child) = splitpath(rest).into_iter().next() else {
+                continue;
+            };
+            let node = nodes.entry(child.to_string()).or_default();
+            if child.len() == rest.len() {
+                node.0 = true;
+            } else {
+                node.1 += 1;
+            }
+        }
+        Ok(nodes
+            .into_iter()
+            .map(|(name, (package, packages))| PackageNode {
+                path: format!("{}{}", prefix, name),
+                name,
+                package,
+                packages,
+            })
+            .collect())
+    }
+
+    /// Lists the top-level package sets, such as `python3Packages` and `haskellPackages`,
+    /// with the number of packages in each, largest first.
+    pub async fn sets(&self) -> Result<Vec<PackageNode>> {
+        let mut sets = self
+            .children("")
+            .await?
+            .into_iter()
+            .filter(|node| node.packages > 0)
+            .collect::<Vec<_>>();
+        sets.sort_by(|a, b| {
+            b.packages
+                .cmp(&a.packages)
+                .then_with(|| a.name.cmp(&b.name))
+        });
+        Ok(sets)
+    }
+}
+
+/// Builds the indexes used by [PackageDb::search()], [PackageDb::fuzzy()] and [PackageDb::complete()]
+/// into the database at `path`: a full-text index of attribute paths, names and both descriptions of every package,
+/// a trigram index of attribute paths and names, and an index of names.
+/// The indexes read their content from `pkgs` and `meta`, so they are rebuilt from scratch if they exist.
+pub(crate) async fn createindex(path: &Path) -> Result<()> {
+    let options = SqliteConnectOptions::new().filename(path);
+    let mut conn = SqliteConnection::connect_with(&options).await?;
+    let mut tx = conn.begin().await?;
+    sqlx::query(
+        r#"
+        CREATE VIEW IF NOT EXISTS "pkgs_search" AS
+        SELECT pkgs.rowid AS rowid, pkgs.attribute AS attribute, pkgs.pname AS pname,
+            meta.description AS description, meta.longdescription AS longdescription
+        FROM pkgs LEFT JOIN meta ON meta.attribute = pkgs.attribute
+        "#,
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query(
+        r#"
+        CREATE VIRTUAL TABLE IF NOT EXISTS "pkgs_fts" USING fts5(
+            attribute, pname, description, longdescription,
+            content='pkgs_search', content_rowid='rowid', prefix='2 3'
+        )
+        "#,
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query("INSERT INTO pkgs_fts(pkgs_fts) VALUES('rebuild')")
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query(
+        r#"
+        CREATE VIRTUAL TABLE IF NOT EXISTS "pkgs_trigrams" USING fts5(
+            attribute, pname, content='pkgs', content_rowid='rowid', tokenize='trigram'
+        )
+        "#,
+    )
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query("INSERT INTO pkgs_trigrams(pkgs_trigrams) VALUES('rebuild')")
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query(r#"CREATE INDEX IF NOT EXISTS "pnames" ON "pkgs" ("pname")"#)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    conn.close().await?;
+    debug!("Built search index of {}", path.display());
+    Ok(())
+}
+
+/// Checks whether the database at `path` has all indexes built by [createindex()].
+pub(crate) async fn hasindex(path: &Path) -> Result<bool> {
+    let options = SqliteConnectOptions::new().filename(path).read_only(true);
+    let mut conn = SqliteConnection::connect_with(&options).await?;
+    let found: Vec<(String,)> =
+        sqlx::query_as("SELECT name FROM sqlite_master WHERE type = 'table'")
+            .fetch_all(&mut conn)
+            .await?;
+    conn.close().await?;
+    Ok(SEARCH_TABLES
+        .iter()
+        .all(|table| found.iter().any(|(name,)| name == table)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// Columns of the `meta` table in databases of the registry.
+    const METACOLUMNS: &[&str] = &[
+        "description",
+        "longdescription",
+        "homepage",
+        "mainprogram",
+        "position",
+        "broken",
+        "insecure",
+        "unsupported",
+        "unfree",
+        "license",
+        "maintainers",
+        "platforms",
+        "badplatforms",
+        "outputs",
+    ];
+
+    /// Creates a package database at `path` laid out like the ones in the registry, with the search index built.
+    /// `pkgs` are the attribute path, name, version and `meta` columns of each package;
+    /// strings are stored as they are, flags as integers and anything else as JSON.
+    async fn packagedb(
+        path: &Path,
+        pkgs: &[(&str, Option<&str>, &str, Value)],
+    ) -> Result<PackageDb> {
+        packagetables(path, pkgs).await?;
+        createindex(path).await?;
+        PackageDb::open(path).await
+    }
+
+    /// Creates the tables of [packagedb()] without the search index.
+    async fn packagetables(path: &Path, pkgs: &[(&str, Option<&str>, &str, Value)]) -> Result<()> {
+        let options = SqliteConnectOptions::new()
+            .filename(path)
+            .create_if_missing(true);
+        let mut conn = SqliteConnection::connect_with(&options).await?;
+        sqlx::query(
+            "CREATE TABLE pkgs (attribute TEXT PRIMARY KEY, system TEXT, pname TEXT, version TEXT)",
+        )
+        .execute(&mut conn)
+        .await?;
+        sqlx::query(&format!(
+            "CREATE TABLE meta (attribute TEXT PRIMARY KEY, {})",
+            METACOLUMNS.join(", ")
+        ))
+        .execute(&mut conn)
+        .await?;
+        for (attr, pname, version, meta) in pkgs {
+            sqlx::query(
+                "INSERT INTO pkgs (attribute, system, pname, version) \
+                 VALUES ($1, 'x86_64-linux', $2, $3)",
+            )
+            .bind(attr)
+            .bind(pname)
+            .bind(version)
+            .execute(&mut conn)
+            .await?;
+            let Value::Object(meta) = meta else {
+                continue;
+            };
+            let sql = format!(
+                "INSERT INTO meta ({}) VALUES ({})",
+                ["attribute"]
+                    .into_iter()
+                    .chain(meta.keys().map(String::as_str))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                (1..=meta.len() + 1)
+                    .map(|n| format!("${}", n))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+            let mut query = sqlx::query(&sql).bind(attr);
+            for value in meta.values() {
+                query = match value {
+                    Value::String(text) => query.bind(text.clone()),
+                    Value::Bool(flag) => query.bind(i64::from(*flag)),
+                    value => query.bind(value.to_string()),
+                };
+            }
+            query.execute(&mut conn).await?;
+        }
+        conn.close().await?;
+        Ok(())
+    }
+
+    /// A few packages of different kinds: versions of the same package, package sets and legacy entries.
+    async fn nixpkgs(path: &Path) -> Result<PackageDb> {
+        packagedb(
+            path,
+            &[
+                (
+                    "firefox",
+                    Some("firefox"),
+                    "144.0",
+                    json!({ "description": "Web browser built from Firefox source tree", "mainprogram": "firefox" }),
+                ),
+                (
+                    "nodejs_20",
+                    Some("nodejs"),
+                    "20.19.5",
+                    json!({ "description": "Event-driven I/O framework for the V8 JavaScript engine" }),
+                ),
+                (
+                    "nodejs_22",
+                    Some("nodejs"),
+                    "22.20.0",
+                    json!({ "description": "Event-driven I/O framework for the V8 JavaScript engine" }),
+                ),
+                ("nodejs", Some("nodejs"), "22.20.0", json!({})),
+                ("legacy", None, "1.0", Value::Null),
+            ],
+        )
+        .await
+    }
+
+    fn attributes(pkgs: impl IntoIterator<Item = Package>) -> Vec<String> {
+        pkgs.into_iter().map(|pkg| pkg.attribute).collect()
+    }
+
+    #[tokio::test]
+    async fn get_returns_typed_packages() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let db = nixpkgs(&dir.path().join("pkgs.db")).await?;
+        assert_eq!(
+            db.get("firefox").await?,
+            Some(Package {
+                attribute: String::from("firefox"),
+                pname: Some(String::from("firefox")),
+                version: Some(String::from("144.0")),
+                system: Some(String::from("x86_64-linux")),
+            })
+        );
+        assert_eq!(db.get("legacy").await?.and_then(|pkg| pkg.pname), None);
+        assert_eq!(db.get("missing").await?, None);
+
+        let found = db.get_many(&["firefox", "legacy", "missing"]).await?;
+        let mut keys = found.keys().cloned().collect::<Vec<_>>();
+        keys.sort();
+        assert_eq!(keys, vec!["firefox", "legacy"]);
+        assert!(db.get_many::<&str>(&[]).await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn versions_share_a_name() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let db = nixpkgs(&dir.path().join("pkgs.db")).await?;
+        assert_eq!(
+            attributes(db.by_pname("nodejs").await?),
+            vec!["nodejs", "nodejs_20", "nodejs_22"]
+        );
+        assert_eq!(
+            attributes(db.versions_of("nodejs_20").await?),
+            vec!["nodejs", "nodejs_20", "nodejs_22"]
+        );
+        // Without a name only the package itself is known
+        assert_eq!(attributes(db.versions_of("legacy").await?), vec!["legacy"]);
+        assert!(db.versions_of("missing").await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn meta_is_looked_up_by_attribute() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let db = nixpkgs(&dir.path().join("pkgs.db")).await?;
+        let meta = db.meta("firefox").await?.context("missing meta")?;
+        assert_eq!(meta.mainprogram.as_deref(), Some("firefox"));
+        assert_eq!(db.meta("legacy").await?, None);
+
+        let found = db.meta_many(&["firefox", "nodejs_22", "legacy"]).await?;
+        let mut keys = found.keys().cloned().collect::<Vec<_>>();
+        keys.sort();
+        assert_eq!(keys, vec!["firefox", "nodejs_22"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn legacy_databases_have_no_meta() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("legacypkgs.db");
+        let options = SqliteConnectOptions::new()
+            .filename(&path)
+            .create_if_missing(true);
+        let mut conn = SqliteConnection::connect_with(&options).await?;
+        sqlx::query("CREATE TABLE pkgs (attribute TEXT PRIMARY KEY, version TEXT)")
+            .execute(&mut conn)
+            .await?;
+        sqlx::query("INSERT INTO pkgs VALUES ('firefox', '144.0')")
+            .execute(&mut conn)
+            .await?;
+        conn.close().await?;
+
+        let db = PackageDb::open(&path).await?.with_platform("x86_64-linux");
+        let firefox = db.get("firefox").await?.context("missing package")?;
+        assert_eq!(firefox.version.as_deref(), Some("144.0"));
+        assert_eq!(firefox.pname, None);
+        assert_eq!(db.meta("firefox").await?, None);
+        assert!(db.meta_many(&["firefox"]).await?.is_empty());
+        Ok(())
+    }
+
+    /// Creates a package database at `path` with the `meta.platforms` and `meta.badPlatforms` of each package.
+    async fn platformdb(path: &Path, pkgs: &[(&str, Option<&str>, &str)]) -> Result<()> {
+        let options = SqliteConnectOptions::new()
+            .filename(path)
+            .create_if_missing(true);
+        let mut conn = SqliteConnection::connect_with(&options).await?;
+        sqlx::query("CREATE TABLE pkgs (attribute TEXT PRIMARY KEY, pname TEXT, version TEXT)")
+            .execute(&mut conn)
+            .await?;
+        sqlx::query(
+            "CREATE TABLE meta (attribute TEXT PRIMARY KEY, platforms TEXT, badplatforms TEXT)",
+        )
+        .execute(&mut conn)
+        .await?;
+        for (attr, platforms, badplatforms) in pkgs {
+            sqlx::query("INSERT INTO pkgs (attribute, pname, version) VALUES ($1, $1, '1.0')")
+                .bind(attr)
+                .execute(&mut conn)
+                .await?;
+            sqlx::query(
+                "INSERT INTO meta (attribute, platforms, badplatforms) VALUES ($1, $2, $3)",
+            )
+            .bind(attr)
+            .bind(platforms)
+            .bind(badplatforms)
+            .execute(&mut conn)
+            .await?;
+        }
+        conn.close().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn platform_filter_matches_availableon() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("pkgs.db");
+        platformdb(
+            &path,
+            &[
+                // A pattern for any Linux next to a named platform
+                (
+                    "ripgrep",
+                    Some(r#"[{"kernel":{"name":"linux"}},"x86_64-darwin"]"#),
+                    "[]",
+                ),
+                ("darwinonly", Some(r#"["x86_64-darwin"]"#), "[]"),
+                (
+                    "linuxonly",
+                    Some(r#"["aarch64-linux","x86_64-linux"]"#),
+                    "[]",
+                ),
+                ("anywhere", None, "[]"),
+                ("notonarm", Some("[]"), r#"["aarch64-linux"]"#),
+                ("single", Some(r#""x86_64-darwin""#), "[]"),
+            ],
+        )
+        .await?;
+
+        let db = PackageDb::open(&path).await?.with_platform("aarch64-linux");
+        let expected = [
+            ("ripgrep", true),
+            ("darwinonly", false),
+            ("linuxonly", true),
+            ("anywhere", true),
+            ("notonarm", false),
+            ("single", false),
+        ];
+        for (attr, available) in expected {
+            let meta = db.meta(attr).await?.context("missing meta")?;
+            assert_eq!(meta.availableon("aarch64-linux"), available, "{}", attr);
+            assert_eq!(db.get(attr).await?.is_some(), available, "{}", attr);
+        }
+        let ripgrep = db.meta("ripgrep").await?.context("missing meta")?;
+        assert!(ripgrep.platformpatterns);
+        assert_eq!(ripgrep.platforms, vec!["x86_64-darwin"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn platform_filter_without_platforms_column() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("pkgs.db");
+        let options = SqliteConnectOptions::new()
+            .filename(&path)
+            .create_if_missing(true);
+        let mut conn = SqliteConnection::connect_with(&options).await?;
+        sqlx::query("CREATE TABLE pkgs (attribute TEXT PRIMARY KEY, pname TEXT, version TEXT)")
+            .execute(&mut conn)
+            .await?;
+        sqlx::query("CREATE TABLE meta (attribute TEXT PRIMARY KEY, badplatforms TEXT)")
+            .execute(&mut conn)
+            .await?;
+        for (attr, badplatforms) in [("hello", "[]"), ("notonarm", r#"["aarch64-linux"]"#)] {
+            sqlx::query("INSERT INTO pkgs (attribute, pname, version) VALUES ($1, $1, '1.0')")
+                .bind(attr)
+                .execute(&mut conn)
+                .await?;
+            sqlx::query("INSERT INTO meta (attribute, badplatforms) VALUES ($1, $2)")
+                .bind(attr)
+                .bind(badplatforms)
+                .execute(&mut conn)
+                .await?;
+        }
+        conn.close().await?;
+
+        let db = PackageDb::open(&path).await?.with_platform("aarch64-linux");
+        assert!(db.get("hello").await?.is_some());
+        assert!(db.get("notonarm").await?.is_none());
+        let meta = db.meta("notonarm").await?.context("missing meta")?;
+        assert!(!meta.availableon("aarch64-linux"));
+        assert!(meta.availableon("x86_64-linux"));
+        Ok(())
+    }
+
+    /// Packages matching `browser` in different columns.
+    async fn browsers(path: &Path) -> Result<PackageDb> {
+        packagedb(
+            path,
+            &[
+                (
+                    "firefox",
+                    Some("firefox"),
+                    "144.0",
+                    json!({ "description": "Web browser built from Firefox source tree" }),
+                ),
+                (
+                    "browserpass",
+                    Some("browserpass"),
+                    "3.1.0",
+                    json!({ "description": "Native host for the browserpass extension" }),
+                ),
+                (
+                    "w3m",
+                    Some("w3m"),
+                    "0.5.3",
+                    json!({
+                        "description": "Pager with text-based browsing",
+                        "longdescription": "A text based web browser as well as a pager like more or less"
+                    }),
+                ),
+                ("browser", Some("browser"), "1.0", json!({})),
+                ("hello", Some("hello"), "2.12", json!({ "description": "Program that produces a familiar, friendly greeting" })),
+            ],
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn search_ranks_names_above_descriptions() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let db = browsers(&dir.path().join("pkgs.db")).await?;
+        let results = db.search("browser", 10, 0).await?;
+        let found = results
+            .iter()
+            .map(|result| result.package.attribute.as_str())
+            .collect::<Vec<_>>();
+        // The exact match first, then matches in names, then in descriptions
+        assert_eq!(found[..2], ["browser", "browserpass"]);
+        assert_eq!(found.len(), 4);
+        assert!(found.contains(&"firefox") && found.contains(&"w3m"));
+        assert!(
+            results
+                .windows(2)
+                .skip(1)
+                .all(|pair| pair[0].rank <= pair[1].rank)
+        );
+
+        assert_eq!(
+            attributes(
+                db.search("web browser", 10, 0)
+                    .await?
+                    .into_iter()
+                    .map(|result| result.package)
+            )
+            .len(),
+            2
+        );
+        assert!(db.search("browser nomatch", 10, 0).await?.is_empty());
+        assert!(db.search("  ", 10, 0).await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn search_highlights_matches() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let db = browsers(&dir.path().join("pkgs.db"))
+            .await?
+            .with_markers("[", "]");
+        let results = db.search("browserp", 10, 0).await?;
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].highlighted, "[browserpass]");
+        assert_eq!(
+            results[0].description.as_deref(),
+            Some("Native host for the browserpass extension")
+        );
+        assert_eq!(
+            results[0].snippet.as_deref(),
+            Some("Native host for the [browserpass] extension")
+        );
+
+        // Only the long description matches
+        let results = db.search("web", 10, 0).await?;
+        let w3m = results
+            .iter()
+            .find(|result| result.package.attribute == "w3m")
+            .context("w3m not found")?;
+        assert_eq!(w3m.highlighted, "w3m");
+        assert!(
+            w3m.snippet
+                .as_deref()
+                .is_some_and(|snippet| snippet.contains("[web]"))
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn search_pages_results() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let db = browsers(&dir.path().join("pkgs.db")).await?;
+        let all = db.search("browser", 10, 0).await?;
+        let first = db.search("browser", 2, 0).await?;
+        let second = db.search("browser", 2, 2).await?;
+        assert_eq!(first[..], all[..2]);
+        assert_eq!(second[..], all[2..]);
+        assert!(db.search("browser", 2, 4).await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn search_requires_index() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("pkgs.db");
+        packagetables(&path, &[("hello", Some("hello"), "2.12", json!({}))]).await?;
+        assert!(!hasindex(&path).await?);
+        let err = PackageDb::open(&path)
+            .await?
+            .search("hello", 10, 0)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("search index"), "{:#}", err);
+
+        createindex(&path).await?;
+        assert!(hasindex(&path).await?);
+        // Building the index again replaces it
+        createindex(&path).await?;
+        let results = PackageDb::open(&path).await?.search("hello", 10, 0).await?;
+        assert_eq!(results.len(), 1);
+        Ok(())
+    }
+
+    /// Packages with similar names, some of them in package sets.
+    async fn similar(path: &Path) -> Result<PackageDb> {
+        let pkgs = [
+            ("libreoffice", "libreoffice"),
+            ("libreoffice-fresh", "libreoffice"),
+            ("libre", "libre"),
+            ("firefox", "firefox"),
+            ("firefox-esr", "firefox-esr"),
+            ("fish", "fish"),
+            ("fd", "fd"),
+            ("python3Packages.fire", "fire"),
+            ("gimp", "gimp"),
+        ];
+        packagedb(
+            path,
+            &pkgs
+                .iter()
+                .map(|(attr, pname)| (*attr, Some(*pname), "1.0", json!({})))
+                .collect::<Vec<_>>(),
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn complete_matches_prefixes_shortest_first() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let db = similar(&dir.path().join("pkgs.db")).await?;
+        assert_eq!(
+            attributes(db.complete("fi", 10).await?),
+            vec!["fish", "firefox", "firefox-esr", "python3Packages.fire"]
+        );
+        assert_eq!(
+            attributes(db.complete("fi", 2).await?),
+            vec!["fish", "firefox"]
+        );
+        // Package sets match by name as well as by attribute path
+        assert_eq!(
+            attributes(db.complete("python3Packages.f", 10).await?),
+            vec!["python3Packages.fire"]
+        );
+        assert!(db.complete("", 10).await?.is_empty());
+        assert!(db.complete("zz", 10).await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fuzzy_allows_typos() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let db = similar(&dir.path().join("pkgs.db")).await?;
+        let found = db.fuzzy("LibreOfice", 3).await?;
+        assert_eq!(found[0].package.attribute, "libreoffice");
+        assert_eq!(found[0].matched, "libreoffice");
+        assert_eq!(found[0].distance, 1);
+        assert!(found.iter().all(|found| found.distance <= 2));
+
+        let found = db.fuzzy("firefox", 10).await?;
+        assert_eq!(found[0].distance, 0);
+        assert_eq!(found[0].package.attribute, "firefox");
+
+        // Transposed letters count once
+        let found = db.fuzzy("firefxo", 10).await?;
+        assert_eq!(found[0].package.attribute, "firefox");
+        assert_eq!(found[0].distance, 1);
+
+        // The last component of package sets is compared too
+        let found = db.fuzzy("fiire", 10).await?;
+        assert_eq!(found[0].package.attribute, "python3Packages.fire");
+        assert_eq!(found[0].matched, "fire");
+        assert_eq!(found[0].distance, 1);
+
+        assert!(db.fuzzy("photoshop", 10).await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fuzzy_completes_short_queries() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let db = similar(&dir.path().join("pkgs.db")).await?;
+        let found = db.fuzzy("fd", 10).await?;
+        assert_eq!(found[0].package.attribute, "fd");
+        assert_eq!(found[0].distance, 0);
+        let found = db.fuzzy("f", 10).await?;
+        assert_eq!(
+            found
+                .iter()
+                .map(|found| (found.matched.as_str(), found.distance))
+                .collect::<Vec<_>>(),
+            vec![
+                ("fd", 1),
+                ("fish", 3),
+                ("fire", 3),
+                ("firefox", 6),
+                ("firefox-esr", 10)
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn meta_parses_nixpkgs_values() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let db = packagedb(
+            &dir.path().join("pkgs.db"),
+            &[(
+                "ripgrep",
+                Some("ripgrep"),
+                "14.1.1",
+                json!({
+                    "description": "Utility that combines the usability of The Silver Searcher with the raw speed of grep",
+                    "homepage": "https://github.com/BurntSushi/ripgrep",
+                    "mainprogram": "rg",
+                    "position": "pkgs/by-name/ri/ripgrep/package.nix:80",
+                    "broken": false,
+                    "insecure": true,
+                    "license": [
+                        { "spdxId": "MIT", "shortName": "mit", "fullName": "MIT License",
+                          "url": "https://spdx.org/licenses/MIT.html", "free": true, "redistributable": true },
+                        { "spdxId": "Unlicense", "shortName": "unlicense", "free": true }
+                    ],
+                    "maintainers": [
+                        { "name": "Jane Doe", "email": "jane@example.org", "github": "jane", "githubId": 42 },
+                        "team"
+                    ],
+                    "platforms": ["x86_64-linux", "aarch64-darwin"],
+                    "outputs": { "out": "/nix/store/…-ripgrep", "man": "/nix/store/…-ripgrep-man" }
+                }),
+            )],
+        )
+        .await?;
+        let meta = db.meta("ripgrep").await?.context("missing meta")?;
+        assert_eq!(meta.mainprogram.as_deref(), Some("rg"));
+        assert_eq!(
+            meta.position.as_deref(),
+            Some("pkgs/by-name/ri/ripgrep/package.nix:80")
+        );
+        assert!(!meta.broken && meta.insecure && !meta.unsupported && !meta.unfree);
+        assert_eq!(
+            meta.licenses[0],
+            License {
+                spdxid: Some(String::from("MIT")),
+                shortname: Some(String::from("mit")),
+                fullname: Some(String::from("MIT License")),
+                url: Some(String::from("https://spdx.org/licenses/MIT.html")),
+                free: true,
+                redistributable: Some(true),
+            }
+        );
+        assert_eq!(
+            meta.licenses
+                .iter()
+                .map(|license| license.name())
+                .collect::<Vec<_>>(),
+            vec![Some("MIT License"), Some("Unlicense")]
+        );
+        assert_eq!(
+            meta.maintainers,
+            vec![
+                Maintainer {
+                    name: Some(String::from("Jane Doe")),
+                    email: Some(String::from("jane@example.org")),
+                    github: Some(String::from("jane")),
+                    githubid: Some(42),
+                    matrix: None,
+                },
+                Maintainer {
+                    name: Some(String::from("team")),
+                    ..Default::default()
+                },
+            ]
+        );
+        assert_eq!(meta.platforms, vec!["x86_64-linux", "aarch64-darwin"]);
+        assert!(!meta.platformpatterns);
+        assert_eq!(meta.outputs, vec!["man", "out"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn meta_accepts_single_values() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let db = packagedb(
+            &dir.path().join("pkgs.db"),
+            &[
+                (
+                    "steam",
+                    Some("steam"),
+                    "1.0",
+                    json!({
+                        "unfree": true,
+                        "license": { "shortName": "unfreeRedistributable" },
+                        "maintainers": "\"someone\"",
+                        "platforms": "\"x86_64-linux\"",
+                        "outputs": ["out"]
+                    }),
+                ),
+                (
+                    "oldpkg",
+                    Some("oldpkg"),
+                    "1.0",
+                    json!({ "unfree": true, "license": "\"unfree\"", "broken": true }),
+                ),
+                (
+                    "garbage",
+                    Some("garbage"),
+                    "1.0",
+                    json!({ "license": "{not json", "maintainers": "[", "outputs": "{" }),
+                ),
+            ],
+        )
+        .await?;
+        let steam = db.meta("steam").await?.context("missing meta")?;
+        assert!(steam.unfree);
+        assert_eq!(steam.licenses.len(), 1);
+        assert_eq!(steam.licenses[0].name(), Some("unfreeRedistributable"));
+        assert!(!steam.licenses[0].free);
+        assert_eq!(steam.maintainers[0].name.as_deref(), Some("someone"));
+        assert_eq!(steam.platforms, vec!["x86_64-linux"]);
+        assert_eq!(steam.outputs, vec!["out"]);
+
+        // Free form licenses are only as free as the package
+        let oldpkg = db.meta("oldpkg").await?.context("missing meta")?;
+        assert!(oldpkg.broken);
+        assert_eq!(oldpkg.licenses[0].name(), Some("unfree"));
+        assert!(!oldpkg.licenses[0].free);
+
+        // Malformed JSON is left out rather than failing the lookup
+        let garbage = db.meta("garbage").await?.context("missing meta")?;
+        assert!(garbage.licenses.is_empty());
+        assert!(garbage.maintainers.is_empty());
+        assert!(garbage.outputs.is_empty());
+        Ok(())
+    }
+
+    /// Packages at the top level and in package sets, including sets whose names prefix each other.
+    async fn packagesets(path: &Path) -> Result<PackageDb> {
+        let pkgs = [
+            ("requests", "requests"),
+            ("python3", "python3"),
+            ("python3Packages.requests", "requests"),
+            ("python3Packages.numpy", "numpy"),
+            ("python3Packages.requests-mock", "requests-mock"),
+            ("python3Packages-extra.requests", "requests"),
+            ("haskellPackages.aeson", "aeson"),
+            ("haskellPackages.lens", "lens"),
+            ("haskellPackages.lens-aeson", "lens-aeson"),
+        ];
+        packagedb(
+            path,
+            &pkgs
+                .iter()
+                .map(|(attr, pname)| {
+                    (
+                        *attr,
+                        Some(*pname),
+                        "1.0",
+                        json!({ "description": format!("The {} package", pname) }),
+                    )
+                })
+                .collect::<Vec<_>>(),
+        )
+        .await
+    }
+
+    fn pkgnode(name: &str, path: &str, package: bool, packages: u64) -> PackageNode {
+        PackageNode {
+            name: name.to_string(),
+            path: path.to_string(),
+            package,
+            packages,
+        }
+    }
+
+    #[tokio::test]
+    async fn children_lists_package_sets() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let db = packagesets(&dir.path().join("pkgs.db")).await?;
+        assert_eq!(
+            db.children("").await?,
+            vec![
+                pkgnode("haskellPackages", "haskellPackages", false, 3),
+                pkgnode("python3", "python3", true, 0),
+                pkgnode("python3Packages", "python3Packages", false, 3),
+                pkgnode("python3Packages-extra", "python3Packages-extra", false, 1),
+                pkgnode("requests", "requests", true, 0),
+            ]
+        );
+        assert_eq!(
+            db.children("python3Packages").await?,
+            vec![
+                pkgnode("numpy", "python3Packages.numpy", true, 0),
+                pkgnode("requests", "python3Packages.requests", true, 0),
+                pkgnode("requests-mock", "python3Packages.requests-mock", true, 0),
+            ]
+        );
+        assert!(db.children("requests").await?.is_empty());
+        assert!(db.children("missing").await?.is_empty());
+
+        assert_eq!(
+            db.sets()
+                .await?
+                .into_iter()
+                .map(|node| (node.name, node.packages))
+                .collect::<Vec<_>>(),
+            vec![
+                (String::from("haskellPackages"), 3),
+                (String::from("python3Packages"), 3),
+                (String::from("python3Packages-extra"), 1),
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn scope_restricts_searches() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("pkgs.db");
+        packagesets(&path).await?;
+        let scoped =
+            async |scope: PackageScope| -> Result<(Vec<String>, Vec<String>, Vec<String>)> {
+                let db = PackageDb::open(&path).await?.with_scope(scope);
+                let mut searched = attributes(
+                    db.search("requests", 20, 0)
+                        .await?
+                        .into_iter()
+                        .map(|result| result.package),
+                );
+                searched.sort();
+                let completed = attributes(db.complete("requests", 20).await?);
+                let mut fuzzy = attributes(
+                    db.fuzzy("request", 20)
+                        .await?
+                        .into_iter()
+                        .map(|found| found.package),
+                );
+                fuzzy.sort();
+                Ok((searched, completed, fuzzy))
+            };
+
+        let (searched, completed, fuzzy) = scoped(PackageScope::All).await?;
+        assert_eq!(searched.len(), 4);
+        assert_eq!(completed.len(), 4);
+        assert_eq!(fuzzy.len(), 3);
+
+        let (searched, completed, fuzzy) = scoped(PackageScope::TopLevel).await?;
+        assert_eq!(searched, vec!["requests"]);
+        assert_eq!(completed, vec!["requests"]);
+        assert_eq!(fuzzy, vec!["requests"]);
+
+        // Sets whose names start with the same letters are kept apart
+        let (searched, completed, fuzzy) =
+            scoped(PackageScope::Within(String::from("python3Packages"))).await?;
+        assert_eq!(
+            searched,
+            vec!["python3Packages.requests", "python3Packages.requests-mock"]
+        );
+        assert_eq!(
+            completed,
+            vec!["python3Packages.requests", "python3Packages.requests-mock"]
+        );
+        assert_eq!(fuzzy, vec!["python3Packages.requests"]);
+
+        let (searched, completed, fuzzy) = scoped(PackageScope::Excluding(vec![
+            String::from("python3Packages"),
+            String::from("haskellPackages"),
+        ]))
+        .await?;
+        assert_eq!(searched, vec!["python3Packages-extra.requests", "requests"]);
+        assert_eq!(
+            completed,
+            vec!["requests", "python3Packages-extra.requests"]
+        );
+        assert_eq!(fuzzy, vec!["python3Packages-extra.requests", "requests"]);
+        Ok(())
+    }
+}
@@ -0,0 +1,280 @@
+use anyhow::{Context, Result};
+use log::debug;
+use sqlx::{Connection, Row, SqliteConnection, SqlitePool, sqlite::SqliteConnectOptions};
+use std::path::Path;
+
+/// A package providing a command, as returned by [ProgramsDb::providers_of()].
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Provider {
+    /// Attribute path of the package, such as `ripgrep`.
+    pub attribute: String,
+    /// Platform the package was found to install the command on, such as `x86_64-linux`.
+    /// `None` if the package is only known from its `mainProgram`.
+    pub system: Option<String>,
+    /// Whether the command is the `meta.mainProgram` of the package, i.e. what `nix run` starts.
+    pub mainprogram: bool,
+}
+
+/// The programs index returned by [programsdb()](crate::cache::programs::programsdb),
+/// mapping commands to the packages installing them in `bin`.
+///
+/// It is built from the `programs.sqlite` shipped with nixos channels, which `command-not-found` uses,
+/// together with the `mainProgram` of every package in the cached package database.
+#[derive(Clone, Debug)]
+pub struct ProgramsDb {
+    pool: SqlitePool,
+    system: Option<String>,
+}
+
+impl ProgramsDb {
+    /// Opens the programs index at `path` read-only.
+    pub async fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let options = SqliteConnectOptions::new()
+            .filename(path.as_ref())
+            .read_only(true);
+        let pool = SqlitePool::connect_with(options)
+            .await
+            .with_context(|| format!("Failed to open {}", path.as_ref().display()))?;
+        Ok(ProgramsDb { pool, system: None })
+    }
+
+    /// Restricts all queries to packages for `system`, such as `x86_64-linux`.
+    pub fn with_system(mut self, system: impl Into<String>) -> Self {
+        self.system = Some(system.into());
+        self
+    }
+
+    /// Returns the packages providing `command`, such as `ripgrep` for `rg`.
+    /// Packages with `command` as their `mainProgram` come first, then the shortest attribute paths.
+    pub async fn providers_of(&self, command: &str) -> Result<Vec<Provider>> {
+        sqlx::query(
+            r#"
+            SELECT attribute, max(system) AS system, max(mainprogram) AS mainprogram FROM (
+                SELECT package AS attribute, system, 0 AS mainprogram FROM Programs
+                WHERE name = $1 AND ($2 IS NULL OR system = $2)
+                UNION ALL
+                SELECT attribute, NULL AS system, 1 AS mainprogram FROM mainprograms
+                WHERE name = $1
+            )
+            GROUP BY attribute
+            ORDER BY mainprogram DESC, length(attribute), attribute
+            "#,
+        )
+        .bind(command)
+        .bind(&self.system)
+        .fetch_all(&self.pool)
+        .await?
+        .iter()
+        .map(|row| {
+            Ok(Provider {
+                attribute: row.try_get("attribute")?,
+                system: row.try_get("system")?,
+                mainprogram: row.try_get("mainprogram")?,
+            })
+        })
+        .collect()
+    }
+}
+
+/// Fills the `mainprograms` table of the programs index at `dbfile` from the `meta` table of the package database
+/// at `pkgsdb`, replacing what was there. Without a package database, or one without `meta`, the table is left empty.
+pub(crate) async fn updatedb(dbfile: &Path, pkgsdb: Option<&Path>) -> Result<()> {
+    let options = SqliteConnectOptions::new().filename(dbfile);
+    let mut conn = SqliteConnection::connect_with(&options).await?;
+    sqlx::query(
+        r#"
+        CREATE TABLE IF NOT EXISTS "mainprograms" (
+            "name"	TEXT NOT NULL,
+            "attribute"	TEXT NOT NULL,
+            PRIMARY KEY("name", "attribute")
+        ) WITHOUT ROWID
+        "#,
+    )
+    .execute(&mut conn)
+    .await?;
+    sqlx::query("DELETE FROM mainprograms")
+        .execute(&mut conn)
+        .await?;
+
+    if let Some(pkgsdb) = pkgsdb {
+        // Databases can't be attached inside a transaction
+        sqlx::query("ATTACH DATABASE $1 AS pkgsdb")
+            .bind(pkgsdb.to_string_lossy())
+            .execute(&mut conn)
+            .await
+            .with_context(|| format!("Failed to open {}", pkgsdb.display()))?;
+        let hasmeta: Option<(String,)> = sqlx::query_as(
+            "SELECT name FROM pkgsdb.sqlite_master WHERE type = 'table' AND name = 'meta'",
+        )
+        .fetch_optional(&mut conn)
+        .await?;
+        if hasmeta.is_some() {
+            let count = sqlx::query(
+                r#"
+                INSERT OR IGNORE INTO mainprograms (name, attribute)
+                SELECT mainprogram, attribute FROM pkgsdb.meta WHERE mainprogram IS NOT NULL
+                "#,
+            )
+            .execute(&mut conn)
+            .await?
+            .rows_affected();
+            debug!("Indexed {} main programs from {}", count, pkgsdb.display());
+        }
+        sqlx::query("DETACH DATABASE pkgsdb")
+            .execute(&mut conn)
+            .await?;
+    }
+    conn.close().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Creates a `programs.sqlite` at `path` as shipped with nixos channels,
+    /// from `(command, system, package)` triples.
+    async fn channelprograms(path: &Path, programs: &[(&str, &str, &str)]) -> Result<()> {
+        let options = SqliteConnectOptions::new()
+            .filename(path)
+            .create_if_missing(true);
+        let mut conn = SqliteConnection::connect_with(&options).await?;
+        sqlx::query(
+            "CREATE TABLE Programs (name TEXT NOT NULL, system TEXT NOT NULL, package TEXT NOT NULL, \
+             PRIMARY KEY (name, system, package))",
+        )
+        .execute(&mut conn)
+        .await?;
+        for (name, system, package) in programs {
+            sqlx::query("INSERT INTO Programs (name, system, package) VALUES ($1, $2, $3)")
+                .bind(name)
+                .bind(system)
+                .bind(package)
+                .execute(&mut conn)
+                .await?;
+        }
+        conn.close().await?;
+        Ok(())
+    }
+
+    /// Creates a package database at `path` with the `meta.mainProgram` of each package.
+    async fn pkgsdb(path: &Path, mainprograms: &[(&str, Option<&str>)]) -> Result<()> {
+        let options = SqliteConnectOptions::new()
+            .filename(path)
+            .create_if_missing(true);
+        let mut conn = SqliteConnection::connect_with(&options).await?;
+        sqlx::query("CREATE TABLE meta (attribute TEXT PRIMARY KEY, mainprogram TEXT)")
+            .execute(&mut conn)
+            .await?;
+        for (attr, mainprogram) in mainprograms {
+            sqlx::query("INSERT INTO meta (attribute, mainprogram) VALUES ($1, $2)")
+                .bind(attr)
+                .bind(mainprogram)
+                .execute(&mut conn)
+                .await?;
+        }
+        conn.close().await?;
+        Ok(())
+    }
+
+    fn provider(attribute: &str, system: Option<&str>, mainprogram: bool) -> Provider {
+        Provider {
+            attribute: attribute.to_string(),
+            system: system.map(str::to_string),
+            mainprogram,
+        }
+    }
+
+    #[tokio::test]
+    async fn providers_of_prefers_main_programs() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let programs = dir.path().join("programs.db");
+        let pkgs = dir.path().join("pkgs.db");
+        channelprograms(
+            &programs,
+            &[
+                ("rg", "x86_64-linux", "ripgrep"),
+                ("rg", "aarch64-linux", "ripgrep"),
+                ("rg", "x86_64-linux", "ripgrep-all"),
+                ("vi", "x86_64-linux", "nvi"),
+                ("vi", "x86_64-linux", "vim"),
+                ("vi", "aarch64-linux", "busybox"),
+            ],
+        )
+        .await?;
+        pkgsdb(
+            &pkgs,
+            &[
+                ("ripgrep", Some("rg")),
+                ("neovim", Some("nvim")),
+                ("hello", None),
+            ],
+        )
+        .await?;
+        updatedb(&programs, Some(&pkgs)).await?;
+
+        let db = ProgramsDb::open(&programs)
+            .await?
+            .with_system("x86_64-linux");
+        assert_eq!(
+            db.providers_of("rg").await?,
+            vec![
+                provider("ripgrep", Some("x86_64-linux"), true),
+                provider("ripgrep-all", Some("x86_64-linux"), false),
+            ]
+        );
+        assert_eq!(
+            db.providers_of("vi").await?,
+            vec![
+                provider("nvi", Some("x86_64-linux"), false),
+                provider("vim", Some("x86_64-linux"), false),
+            ]
+        );
+        // Only known from the package database
+        assert_eq!(
+            db.providers_of("nvim").await?,
+            vec![provider("neovim", None, true)]
+        );
+        assert!(db.providers_of("missing").await?.is_empty());
+
+        let db = ProgramsDb::open(&programs).await?;
+        assert_eq!(db.providers_of("vi").await?.len(), 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn updatedb_replaces_main_programs() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let programs = dir.path().join("programs.db");
+        let pkgs = dir.path().join("pkgs.db");
+        channelprograms(&programs, &[("rg", "x86_64-linux", "ripgrep")]).await?;
+        pkgsdb(&pkgs, &[("neovim", Some("nvim"))]).await?;
+        updatedb(&programs, Some(&pkgs)).await?;
+        updatedb(&programs, Some(&pkgs)).await?;
+        let db = ProgramsDb::open(&programs).await?;
+        assert_eq!(db.providers_of("nvim").await?.len(), 1);
+        db.pool.close().await;
+
+        // Without a package database only the channel's programs are left
+        updatedb(&programs, None).await?;
+        let db = ProgramsDb::open(&programs).await?;
+        assert!(db.providers_of("nvim").await?.is_empty());
+        assert_eq!(db.providers_of("rg").await?.len(), 1);
+        db.pool.close().await;
+
+        // Legacy package databases have no meta table
+        let legacy = dir.path().join("legacy.db");
+        let options = SqliteConnectOptions::new()
+            .filename(&legacy)
+            .create_if_missing(true);
+        let mut conn = SqliteConnection::connect_with(&options).await?;
+        sqlx::query("CREATE TABLE pkgs (attribute TEXT PRIMARY KEY, version TEXT)")
+            .execute(&mut conn)
+            .await?;
+        conn.close().await?;
+        updatedb(&programs, Some(&legacy)).await?;
+        let db = ProgramsDb::open(&programs).await?;
+        assert_eq!(db.providers_of("rg").await?.len(), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,314 @@
+use crate::config::configfile::HttpConfig;
+use anyhow::{Context, Result, anyhow};
+use log::{debug, warn};
+use reqwest::{
+    Proxy, Response, StatusCode,
+    header::{ETAG, HeaderMap, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED, USER_AGENT},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use super::manifest::sha256;
+
+/// Seconds to wait for a connection if `connecttimeout` is not set.
+const DEFAULT_CONNECT_TIMEOUT: u64 = 30;
+/// Seconds to wait for data if `timeout` is not set.
+const DEFAULT_TIMEOUT: u64 = 60;
+/// Retries on transient errors if `retries` is not set.
+const DEFAULT_RETRIES: u32 = 3;
+/// Delay before the first retry, doubled for every further one.
+const BACKOFF: Duration = Duration::from_millis(500);
+/// Longest delay between two retries.
+const MAX_BACKOFF: Duration = Duration::from_secs(8);
+/// Largest body kept for conditional requests. They only pay off for small artifacts
+/// checked on every refresh, such as `nixpkgs.ver` and manifests.
+const MAX_CACHED_BODY: usize = 1024 * 1024;
+
+/// HTTP client used for every download of a [CacheStore](crate::cache::CacheStore),
+/// configured by `http` in the config.
+///
+/// Requests are retried with exponential backoff on connection errors, timeouts and
+/// `408`, `429` and `5xx` responses. [get()](HttpClient::get) remembers the `ETag` and `Last-Modified`
+/// headers of small artifacts and sends conditional requests, so unchanged artifacts are not downloaded again.
+#[derive(Clone, Debug)]
+pub struct HttpClient {
+    client: reqwest::Client,
+    retries: u32,
+    cachedir: Option<PathBuf>,
+}
+
+/// Validators of an artifact fetched with [HttpClient::get()], stored next to its body.
+#[derive(Serialize, Deserialize, Debug)]
+struct CachedResponse {
+    url: String,
+    etag: Option<String>,
+    lastmodified: Option<String>,
+}
+
+impl HttpClient {
+    /// Builds a client with the timeouts, retries and proxy from `config`.
+    /// Without a [cache directory](HttpClient::with_cache), requests are never conditional.
+    pub fn new(config: &HttpConfig) -> Result<Self> {
+        let mut builder = reqwest::Client::builder()
+            .brotli(true)
+            .connect_timeout(Duration::from_secs(
+                config.connecttimeout.unwrap_or(DEFAULT_CONNECT_TIMEOUT),
+            ))
+            .read_timeout(Duration::from_secs(
+                config.timeout.unwrap_or(DEFAULT_TIMEOUT),
+            ));
+        if let Some(proxy) = &config.proxy {
+            builder = builder
+                .proxy(Proxy::all(proxy).with_context(|| format!("Invalid proxy: {}", proxy))?);
+        }
+        Ok(HttpClient {
+            client: builder.build()?,
+            retries: config.retries.unwrap_or(DEFAULT_RETRIES),
+            cachedir: None,
+        })
+    }
+
+    /// Stores validators and bodies of fetched artifacts in `dir`.
+    pub fn with_cache(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.cachedir = Some(dir.into());
+        self
+    }
+
+    /// Sends a GET request to `url`, retrying transient failures.
+    /// Unsuccessful responses that are not worth retrying, such as `404`, are returned as they are.
+    pub async fn send(&self, url: &str) -> Result<Response> {
+        self.sendwith(url, HeaderMap::new()).await
+    }
+
+    async fn sendwith(&self, url: &str, headers: HeaderMap) -> Result<Response> {
+        let mut attempt = 0;
+        loop {
+            let resp = self
+                .client
+                .get(url)
+                .header(USER_AGENT, "rust-reqwest")
+                .headers(headers.clone())
+                .send()
+                .await;
+            let retry = match &resp {
+                Ok(resp) => {
+                    let status = resp.status();
+                    status.is_server_error()
+                        || status == StatusCode::REQUEST_TIMEOUT
+                        || status == StatusCode::TOO_MANY_REQUESTS
+                }
+                Err(e) => e.is_timeout() || e.is_connect(),
+            };
+            if !retry || attempt >= self.retries {
+                return resp.with_context(|| format!("Failed to fetch {}", url));
+            }
+            let delay = (BACKOFF * 2u32.pow(attempt)).min(MAX_BACKOFF);
+            match &resp {
+                Ok(resp) => warn!(
+                    "Fetching {} returned {}, retrying in {:?}",
+                    url,
+                    resp.status(),
+                    delay
+                ),
+                Err(e) => warn!("Fetching {} failed: {}, retrying in {:?}", url, e, delay),
+            }
+            tokio::time::sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    /// Fetches `url` into memory.
+    /// If the artifact was fetched before and the server reports it unchanged, the stored copy is returned.
+    /// Returns `None` if the server doesn't have it and an error for any other failure, see [found()].
+    pub async fn get(&self, url: &str) -> Result<Option<Vec<u8>>> {
+        let stored = self
+            .cachedir
+            .as_ref()
+            .map(|dir| dir.join(sha256(url.as_bytes())));
+        let cached = stored.as_deref().and_then(|path| loadcached(path, url));
+
+        let mut headers = HeaderMap::new();
+        if let Some((meta, _)) = &cached {
+            if let Some(etag) = &meta.etag {
+                headers.insert(IF_NONE_MATCH, etag.parse()?);
+            }
+            if let Some(lastmodified) = &meta.lastmodified {
+                headers.insert(IF_MODIFIED_SINCE, lastmodified.parse()?);
+            }
+        }
+
+        let resp = self.sendwith(url, headers).await?;
+        debug!("response getting {}: {:?}", url, resp.status());
+        if resp.status() == StatusCode::NOT_MODIFIED
+            && let Some((_, body)) = cached
+        {
+            debug!("{} is unchanged", url);
+            return Ok(Some(body));
+        }
+        let Some(resp) = found(resp)? else {
+            return Ok(None);
+        };
+
+        let header = |name| {
+            resp.headers()
+                .get(name)
+                .and_then(|value: &reqwest::header::HeaderValue| value.to_str().ok())
+                .map(str::to_string)
+        };
+        let meta = CachedResponse {
+            url: url.to_string(),
+            etag: header(ETAG),
+            lastmodified: header(LAST_MODIFIED),
+        };
+        let body = resp.bytes().await?.to_vec();
+        if let Some(path) = stored
+            && (meta.etag.is_some() || meta.lastmodified.is_some())
+            && body.len() <= MAX_CACHED_BODY
+            && let Err(e) = storecached(&path, &meta, &body)
+        {
+            warn!("Failed to store response of {}: {:#}", url, e);
+        }
+        Ok(Some(body))
+    }
+}
+
+/// Checks the status of `resp`, which is returned if successful.
+/// Returns `None` if the server doesn't have the artifact (`404 Not Found` or `410 Gone`)
+/// and an error for any other status, such as a server error that outlasted all retries,
+/// so an unavailable server isn't mistaken for a missing artifact.
+pub(crate) fn found(resp: Response) -> Result<Option<Response>> {
+    let status = resp.status();
+    if status == StatusCode::NOT_FOUND || status == StatusCode::GONE {
+        return Ok(None);
+    }
+    if !status.is_success() {
+        return Err(anyhow!("Fetching {} returned {}", resp.url(), status));
+    }
+    Ok(Some(resp))
+}
+
+/// Reads the validators and body stored for `url` at `path`.
+fn loadcached(path: &Path, url: &str) -> Option<(CachedResponse, Vec<u8>)> {
+    let meta: CachedResponse =
+        serde_json::from_slice(&fs::read(path.with_extension("json")).ok()?).ok()?;
+    if meta.url != url {
+        return None;
+    }
+    Some((meta, fs::read(path).ok()?))
+}
+
+/// Stores the body before its validators, so validators never describe a missing body.
+fn storecached(path: &Path, meta: &CachedResponse, body: &[u8]) -> Result<()> {
+    let dir = path.parent().context("Invalid cache path")?;
+    fs::create_dir_all(dir)?;
+    for (target, data) in [
+        (path.to_path_buf(), body.to_vec()),
+        (path.with_extension("json"), serde_json::to_vec(meta)?),
+    ] {
+        let mut temp = tempfile::NamedTempFile::new_in(dir)?;
+        temp.write_all(&data)?;
+        temp.persist(target)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registry::RegistrySource;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    /// Serves every request for `/<status>` with that status and the body `body`.
+    async fn server() -> Result<String> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let mut request = vec![0u8; 4096];
+                let n = socket.read(&mut request).await.unwrap_or(0);
+                let request = String::from_utf8_lossy(&request[..n]);
+                let status = request
+                    .split_whitespace()
+                    .nth(1)
+                    .and_then(|path| path.trim_start_matches('/').parse::<u16>().ok())
+                    .unwrap_or(400);
+                let response = format!(
+                    "HTTP/1.1 {} Test\r\nContent-Length: 4\r\nConnection: close\r\n\r\nbody",
+                    status
+                );
+                let _ = socket.write_all(response.as_bytes()).await;
+            }
+        });
+        Ok(format!("http://{}", addr))
+    }
+
+    fn client() -> HttpClient {
+        HttpClient::new(&HttpConfig {
+            retries: Some(0),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn get_distinguishes_missing_from_failed() -> Result<()> {
+        let base = server().await?;
+        let client = client();
+        assert_eq!(
+            client.get(&format!("{}/200", base)).await?,
+            Some(b"body".to_vec())
+        );
+        assert_eq!(client.get(&format!("{}/404", base)).await?, None);
+        assert_eq!(client.get(&format!("{}/410", base)).await?, None);
+        for status in [304, 403, 429, 500, 503] {
+            let err = client
+                .get(&format!("{}/{}", base, status))
+                .await
+                .unwrap_err();
+            assert!(
+                err.to_string().contains(&status.to_string()),
+                "unexpected error: {:#}",
+                err
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fetch_stream_distinguishes_missing_from_failed() -> Result<()> {
+        let source: RegistrySource = server().await?.parse()?;
+        let client = client();
+        let mut out = Vec::new();
+        let fetched = source
+            .fetch_stream(&client, "200", &mut out, |_, _| {})
+            .await?
+            .context("artifact missing")?;
+        assert_eq!(fetched.size, 4);
+        assert_eq!(fetched.sha256, sha256(b"body"));
+        assert_eq!(out, b"body");
+
+        let mut out = Vec::new();
+        assert!(
+            source
+                .fetch_stream(&client, "404", &mut out, |_, _| {})
+                .await?
+                .is_none()
+        );
+        assert!(
+            source
+                .fetch_stream(&client, "503", &mut out, |_, _| {})
+                .await
+                .is_err()
+        );
+        assert!(out.is_empty());
+        Ok(())
+    }
+}
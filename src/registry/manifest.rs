@@ -0,0 +1,364 @@
+use anyhow::{Context, Result, anyhow};
+use log::debug;
+use minisign_verify::{PublicKey, Signature};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+use super::{RegistrySource, client::HttpClient};
+
+/// File name of the manifest published in every registry directory.
+pub const MANIFEST: &str = "manifest.json";
+/// File name of the minisign signature of [MANIFEST].
+pub const MANIFEST_SIG: &str = "manifest.json.minisig";
+
+/// List of artifacts published in a registry directory, such as `nixos-25.11/`.
+///
+/// The manifest is stored as `manifest.json` next to the artifacts and signed with
+/// [minisign](https://jedisct1.github.io/minisign/) in `manifest.json.minisig`:
+/// ```json
+/// {
+///   "revision": "25.11.20251020.abcdef0",
+///   "artifacts": { "nixpkgs.db.br": { "sha256": "9f86d0…", "size": 31457280 } }
+/// }
+/// ```
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug, Default)]
+pub struct Manifest {
+    /// Revision the artifacts were built from, the same as in the `.ver` file of the directory.
+    /// Binds the artifacts to the revision they are cached under, so an old signed database
+    /// can't be passed off as a newer revision.
+    pub revision: Option<String>,
+    /// Artifacts keyed by their file name.
+    pub artifacts: HashMap<String, ManifestEntry>,
+}
+
+/// A single artifact listed in a [Manifest].
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct ManifestEntry {
+    /// Lowercase hex encoded SHA-256 of the artifact as published (i.e. still compressed).
+    pub sha256: String,
+    /// Size of the artifact in bytes.
+    pub size: Option<u64>,
+}
+
+impl Manifest {
+    /// Fetches the manifest of `dir` from `source` and checks its signature against `keys`.
+    /// Returns an error if the manifest is missing or not signed by any of the keys.
+    pub(crate) async fn fetch(
+        client: &HttpClient,
+        source: &RegistrySource,
+        dir: &str,
+        keys: &[PublicKey],
+    ) -> Result<Self> {
+        let data = source
+            .fetch(client, &format!("{}/{}", dir, MANIFEST))
+            .await?
+            .with_context(|| format!("No {} published for {}", MANIFEST, dir))?;
+        let sig = source
+            .fetch_text(client, &format!("{}/{}", dir, MANIFEST_SIG))
+            .await?
+            .with_context(|| format!("No {} published for {}", MANIFEST_SIG, dir))?;
+        let sig = Signature::decode(&sig).context("Invalid manifest signature")?;
+        if !keys
+            .iter()
+            .any(|key| key.verify(&data, &sig, false).is_ok())
+        {
+            return Err(anyhow!(
+                "Manifest of {} is not signed by a trusted key",
+                dir
+            ));
+        }
+        debug!("Verified manifest of {}", dir);
+        Ok(serde_json::from_slice(&data)?)
+    }
+
+    /// Checks that `data` matches the checksum (and size, if listed) of the artifact `name`.
+    pub fn verify(&self, name: &str, data: &[u8]) -> Result<()> {
+        self.verify_digest(name, data.len() as u64, &sha256(data))
+    }
+
+    /// Checks that the manifest is of `revision`, ignoring a `nixos-` prefix on either side.
+    /// Returns an error if the manifest names another revision or none at all.
+    pub fn verify_revision(&self, revision: &str) -> Result<()> {
+        let strip = |revision: &str| {
+            revision
+                .strip_prefix("nixos-")
+                .unwrap_or(revision)
+                .to_string()
+        };
+        match &self.revision {
+            Some(signed) if strip(signed) == strip(revision) => Ok(()),
+            Some(signed) => Err(anyhow!(
+                "Revision {} does not match the manifest: expected {}",
+                revision,
+                signed
+            )),
+            None => Err(anyhow!(
+                "Manifest does not name the revision of its artifacts"
+            )),
+        }
+    }
+
+    /// Checks that an artifact of `size` bytes with the hex encoded SHA-256 `digest`
+    /// matches the entry for `name`.
+    pub fn verify_digest(&self, name: &str, size: u64, digest: &str) -> Result<()> {
+        let entry = self
+            .artifacts
+            .get(name)
+            .with_context(|| format!("{} is not listed in the manifest", name))?;
+        if let Some(expected) = entry.size
+            && expected != size
+        {
+            return Err(anyhow!(
+                "Size of {} does not match the manifest: expected {}, got {}",
+                name,
+                expected,
+                size
+            ));
+        }
+        if !digest.eq_ignore_ascii_case(&entry.sha256) {
+            return Err(anyhow!(
+                "Checksum of {} does not match the manifest: expected {}, got {}",
+                name,
+                entry.sha256,
+                digest
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Returns the lowercase hex encoded SHA-256 of `data`.
+pub fn sha256(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+/// Parses minisign public keys, either as the bare base64 key (`RWQ…`)
+/// or as the full contents of a `minisign.pub` file.
+pub(crate) fn parsekeys(keys: &[String]) -> Result<Vec<PublicKey>> {
+    keys.iter()
+        .map(|key| {
+            let key = key.trim();
+            if key.contains('\n') {
+                PublicKey::decode(key)
+            } else {
+                PublicKey::from_base64(key)
+            }
+            .with_context(|| format!("Invalid minisign public key: {}", key))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    /// Public key the fixtures below are signed with.
+    const PUBKEY: &str = "RWQBAgMEBQYHCOpKbGPinFIKvvVQexMuxfmVR3auvr57kkIe6mkURtIs";
+    /// Another key with the same key id, which must not verify the fixtures.
+    const OTHERKEY: &str = "RWQBAgMEBQYHCBOY9ixtGkV8UbpqS189vS9p/KkyFiGNyJl+QWvRfZPK";
+    const VERSION: &str = "25.11.20251020.abcdef0\n";
+    const SIGNED: &str = r#"{"revision": "25.11.20251020.abcdef0", "artifacts": {"nixpkgs.ver": {"sha256": "ba1bdb2568a8830d06b7d2d5dabbfcb3169428babcd01734da4c226257a75138", "size": 23}}}"#;
+    const SIGNATURE: &str = "untrusted comment: x
+RUQBAgMEBQYHCLpfbM4uFgCaJwLi0nmqs0H7KcpyubqGRdh8c8QShtaqkW0BlA0Hbznq49B1UcjNe5RUhbh3ZvdNAHblWxShFAU=
+trusted comment: timestamp:0
+yscbtX3n78DUSDtE70caZBL9pQVm8qw5Ty6sbSR4huegarTj/T05GxkjrHouTKOnfJn3mhZLQpgt6WBg3PSgDQ==
+";
+
+    fn manifest() -> Manifest {
+        serde_json::from_str(SIGNED).unwrap()
+    }
+
+    /// Publishes `nixos-25.11/` with the given files in a local source.
+    fn publish(files: &[(&str, &str)]) -> Result<(tempfile::TempDir, RegistrySource)> {
+        let root = tempfile::tempdir()?;
+        let dir = root.path().join("nixos-25.11");
+        fs::create_dir(&dir)?;
+        for (name, content) in files {
+            fs::write(dir.join(name), content)?;
+        }
+        let source = RegistrySource::Local(root.path().to_path_buf());
+        Ok((root, source))
+    }
+
+    fn client() -> HttpClient {
+        HttpClient::new(&Default::default()).unwrap()
+    }
+
+    fn keys(keys: &[&str]) -> Vec<PublicKey> {
+        parsekeys(&keys.iter().map(|key| key.to_string()).collect::<Vec<_>>()).unwrap()
+    }
+
+    #[test]
+    fn verify_accepts_listed_artifact() {
+        let manifest = manifest();
+        manifest.verify("nixpkgs.ver", VERSION.as_bytes()).unwrap();
+        manifest
+            .verify_digest(
+                "nixpkgs.ver",
+                23,
+                &sha256(VERSION.as_bytes()).to_uppercase(),
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn verify_rejects_wrong_digest() {
+        let manifest = manifest();
+        assert!(
+            manifest
+                .verify("nixpkgs.ver", b"25.11.20251020.0000000\n")
+                .is_err()
+        );
+        assert!(manifest.verify("nixpkgs.ver", b"25.11").is_err());
+        assert!(
+            manifest
+                .verify_digest("nixpkgs.ver", 23, &sha256(b"something else"))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn verify_rejects_wrong_size() {
+        let manifest = manifest();
+        let digest = sha256(VERSION.as_bytes());
+        assert!(manifest.verify_digest("nixpkgs.ver", 24, &digest).is_err());
+
+        // Entries without a size are checked by their digest alone
+        let mut unsized_manifest = manifest.clone();
+        unsized_manifest
+            .artifacts
+            .get_mut("nixpkgs.ver")
+            .unwrap()
+            .size = None;
+        unsized_manifest
+            .verify_digest("nixpkgs.ver", 24, &digest)
+            .unwrap();
+    }
+
+    #[test]
+    fn verify_rejects_unlisted_artifact() {
+        assert!(
+            manifest()
+                .verify("nixpkgs.db.br", VERSION.as_bytes())
+                .is_err()
+        );
+        assert!(Manifest::default().verify("nixpkgs.ver", b"").is_err());
+    }
+
+    #[test]
+    fn verify_revision_matches_signed_revision() {
+        let manifest = manifest();
+        manifest.verify_revision("25.11.20251020.abcdef0").unwrap();
+        manifest
+            .verify_revision("nixos-25.11.20251020.abcdef0")
+            .unwrap();
+        assert!(manifest.verify_revision("25.11.20251019.1234567").is_err());
+        assert!(
+            Manifest::default()
+                .verify_revision("25.11.20251020.abcdef0")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn parsekeys_accepts_both_forms() {
+        let file = format!("untrusted comment: minisign public key\n{}\n", PUBKEY);
+        assert_eq!(parsekeys(&[PUBKEY.to_string(), file]).unwrap().len(), 2);
+        assert!(parsekeys(&[String::from("not a key")]).is_err());
+    }
+
+    #[tokio::test]
+    async fn fetch_accepts_signed_manifest() -> Result<()> {
+        let (_root, source) = publish(&[(MANIFEST, SIGNED), (MANIFEST_SIG, SIGNATURE)])?;
+        let fetched = Manifest::fetch(
+            &client(),
+            &source,
+            "nixos-25.11",
+            &keys(&[OTHERKEY, PUBKEY]),
+        )
+        .await?;
+        assert_eq!(fetched, manifest());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fetch_rejects_bad_signature() -> Result<()> {
+        let client = client();
+
+        // Signed by another key
+        let (_root, source) = publish(&[(MANIFEST, SIGNED), (MANIFEST_SIG, SIGNATURE)])?;
+        assert!(
+            Manifest::fetch(&client, &source, "nixos-25.11", &keys(&[OTHERKEY]))
+                .await
+                .is_err()
+        );
+
+        // Tampered after signing
+        let tampered = SIGNED.replace("ba1bdb", "000000");
+        let (_root, source) = publish(&[(MANIFEST, &tampered), (MANIFEST_SIG, SIGNATURE)])?;
+        assert!(
+            Manifest::fetch(&client, &source, "nixos-25.11", &keys(&[PUBKEY]))
+                .await
+                .is_err()
+        );
+
+        // Malformed or missing signature
+        let (_root, source) = publish(&[(MANIFEST, SIGNED), (MANIFEST_SIG, "garbage")])?;
+        assert!(
+            Manifest::fetch(&client, &source, "nixos-25.11", &keys(&[PUBKEY]))
+                .await
+                .is_err()
+        );
+        let (_root, source) = publish(&[(MANIFEST, SIGNED)])?;
+        assert!(
+            Manifest::fetch(&client, &source, "nixos-25.11", &keys(&[PUBKEY]))
+                .await
+                .is_err()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn fetch_revision_checks_manifest() -> Result<()> {
+        let client = client();
+        let keys = keys(&[PUBKEY]);
+        let path = "nixos-25.11/nixpkgs.ver";
+
+        let (_root, source) = publish(&[
+            ("nixpkgs.ver", VERSION),
+            (MANIFEST, SIGNED),
+            (MANIFEST_SIG, SIGNATURE),
+        ])?;
+        assert_eq!(
+            source
+                .fetch_revision(&client, path, &keys)
+                .await?
+                .as_deref(),
+            Some("25.11.20251020.abcdef0")
+        );
+        assert_eq!(
+            source
+                .fetch_revision(&client, "nixos-25.11/missing.ver", &keys)
+                .await?,
+            None
+        );
+
+        let (_root, source) = publish(&[
+            ("nixpkgs.ver", "25.11.20251021.1234567\n"),
+            (MANIFEST, SIGNED),
+            (MANIFEST_SIG, SIGNATURE),
+        ])?;
+        assert!(source.fetch_revision(&client, path, &keys).await.is_err());
+        // Without keys nothing is verified
+        assert_eq!(
+            source.fetch_revision(&client, path, &[]).await?.as_deref(),
+            Some("25.11.20251021.1234567")
+        );
+
+        let (_root, source) = publish(&[("nixpkgs.ver", "../escape\n")])?;
+        assert!(source.fetch_revision(&client, path, &[]).await.is_err());
+        Ok(())
+    }
+}
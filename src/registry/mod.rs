@@ -0,0 +1,325 @@
+use anyhow::{Context, Result, anyhow};
+use log::debug;
+use minisign_verify::PublicKey;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{fmt, io::Write, path::PathBuf, str::FromStr};
+use tokio::io::AsyncReadExt;
+
+/// Shared HTTP client with retries and conditional requests.
+pub mod client;
+/// Signed manifests listing the checksums of published artifacts.
+pub mod manifest;
+
+use client::HttpClient;
+use manifest::Manifest;
+
+/// Default location of the prebuilt package databases (`nixos-<release>/nixpkgs.db.br` and `nixpkgs.ver`).
+pub const DEFAULT_DATABASE: &str = "https://raw.githubusercontent.com/xinux-org/database/main";
+/// Default location of the per-revision package snapshots (`nixos-<release>/<revision>.json.br`).
+pub const DEFAULT_REGISTRY: &str = "https://raw.githubusercontent.com/xinux-org/registry/main/data";
+
+/// Where registry artifacts are fetched from.
+///
+/// Serialized as a plain string so it can be set from the config file:
+/// - `https://mirror.example.org/database` is fetched over HTTP(S).
+/// - `file:///srv/nix-data/database` or `/srv/nix-data/database` is read from a local directory.
+///
+/// Both kinds must mirror the layout of the upstream repositories,
+/// i.e. `<source>/nixos-25.11/nixpkgs.db.br`.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+#[serde(try_from = "String", into = "String")]
+pub enum RegistrySource {
+    /// Base URL of an HTTP(S) server.
+    Http(String),
+    /// Local directory, such as a mounted mirror on an air-gapped machine.
+    Local(PathBuf),
+}
+
+impl RegistrySource {
+    /// Returns the location of `path` inside this source, for logging and error messages.
+    pub fn url(&self, path: &str) -> String {
+        match self {
+            RegistrySource::Http(base) => format!("{}/{}", base.trim_end_matches('/'), path),
+            RegistrySource::Local(dir) => dir.join(path).to_string_lossy().to_string(),
+        }
+    }
+
+    /// Fetches `path` relative to the source, using `client` for HTTP(S) sources.
+    /// Returns `None` if the artifact does not exist in this source.
+    pub async fn fetch(&self, client: &HttpClient, path: &str) -> Result<Option<Vec<u8>>> {
+        let url = self.url(path);
+        debug!("Fetching {}", url);
+        match self {
+            RegistrySource::Http(_) => client.get(&url).await,
+            RegistrySource::Local(dir) => match tokio::fs::read(dir.join(path)).await {
+                Ok(data) => Ok(Some(data)),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(e).with_context(|| format!("Failed to read {}", url)),
+            },
+        }
+    }
+
+    /// Streams `path` relative to the source into `out` without holding it in memory.
+    /// `progress` is called after every chunk with the bytes received so far and the total size, if known.
+    /// Streamed downloads are never conditional, as their bodies are not kept by the client.
+    /// Returns `None` if the artifact does not exist in this source.
+    pub(crate) async fn fetch_stream(
+        &self,
+        client: &HttpClient,
+        path: &str,
+        out: &mut impl Write,
+        mut progress: impl FnMut(u64, Option<u64>),
+    ) -> Result<Option<Fetched>> {
+        let url = self.url(path);
+        debug!("Streaming {}", url);
+        let mut hasher = Sha256::new();
+        let mut received = 0u64;
+        let mut consume = |chunk: &[u8], total: Option<u64>| -> Result<()> {
+            hasher.update(chunk);
+            out.write_all(chunk)?;
+            received += chunk.len() as u64;
+            progress(received, total);
+            Ok(())
+        };
+        match self {
+            RegistrySource::Http(_) => {
+                let resp = client.send(&url).await?;
+                debug!("response getting {}: {:?}", url, resp.status());
+                let Some(mut resp) = client::found(resp)? else {
+                    return Ok(None);
+                };
+                let total = resp.content_length();
+                while let Some(chunk) = resp.chunk().await? {
+                    consume(&chunk, total)?;
+                }
+            }
+            RegistrySource::Local(dir) => {
+                let mut file = match tokio::fs::File::open(dir.join(path)).await {
+                    Ok(file) => file,
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+                    Err(e) => return Err(e).with_context(|| format!("Failed to read {}", url)),
+                };
+                let total = Some(file.metadata().await?.len());
+                let mut buf = vec![0u8; 64 * 1024];
+                loop {
+                    let n = file.read(&mut buf).await?;
+                    if n == 0 {
+                        break;
+                    }
+                    consume(&buf[..n], total)?;
+                }
+            }
+        }
+        Ok(Some(Fetched {
+            size: received,
+            sha256: format!("{:x}", hasher.finalize()),
+        }))
+    }
+
+    /// Fetches `path` relative to the source and, if any `keys` are given,
+    /// checks it against the signed [Manifest] of its directory.
+    /// If `revision` is given, the manifest also has to be of that revision, see [Manifest::verify_revision()].
+    /// Returns `None` if the artifact does not exist in this source
+    /// and an error if it fails verification.
+    pub(crate) async fn fetch_verified(
+        &self,
+        client: &HttpClient,
+        path: &str,
+        keys: &[PublicKey],
+        revision: Option<&str>,
+    ) -> Result<Option<Vec<u8>>> {
+        let Some(data) = self.fetch(client, path).await? else {
+            return Ok(None);
+        };
+        if !keys.is_empty() {
+            let (dir, name) = path.rsplit_once('/').unwrap_or(("", path));
+            let manifest = Manifest::fetch(client, self, dir, keys).await?;
+            manifest
+                .verify(name, &data)
+                .and_then(|_| match revision {
+                    Some(revision) => manifest.verify_revision(revision),
+                    None => Ok(()),
+                })
+                .with_context(|| format!("Refusing to use {}", self.url(path)))?;
+            debug!("Verified {}", path);
+        }
+        Ok(Some(data))
+    }
+
+    /// Fetches the revision in `path`, such as `nixos-25.11/nixpkgs.ver`, with surrounding whitespace removed.
+    /// If any `keys` are given, the file has to be listed in the signed [Manifest] of its directory,
+    /// which has to be of the same revision.
+    /// Returns `None` if the file does not exist in this source
+    /// and an error if it fails verification or doesn't hold a revision.
+    pub(crate) async fn fetch_revision(
+        &self,
+        client: &HttpClient,
+        path: &str,
+        keys: &[PublicKey],
+    ) -> Result<Option<String>> {
+        let Some(data) = self.fetch(client, path).await? else {
+            return Ok(None);
+        };
+        let revision = std::str::from_utf8(&data)?.trim().to_string();
+        // Revisions name files in the cache
+        if revision.is_empty() || revision.contains(['/', '\\']) || revision.starts_with('.') {
+            return Err(anyhow!(
+                "Invalid revision in {}: {:?}",
+                self.url(path),
+                revision
+            ));
+        }
+        if !keys.is_empty() {
+            let (dir, name) = path.rsplit_once('/').unwrap_or(("", path));
+            let manifest = Manifest::fetch(client, self, dir, keys).await?;
+            manifest
+                .verify(name, &data)
+                .and_then(|_| manifest.verify_revision(&revision))
+                .with_context(|| format!("Refusing to use {}", self.url(path)))?;
+            debug!("Verified {}", path);
+        }
+        Ok(Some(revision))
+    }
+
+    /// Fetches `path` relative to the source as a string.
+    /// Returns `None` if the artifact does not exist in this source.
+    pub async fn fetch_text(&self, client: &HttpClient, path: &str) -> Result<Option<String>> {
+        match self.fetch(client, path).await? {
+            Some(data) => Ok(Some(String::from_utf8(data)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Size and checksum of an artifact streamed with [RegistrySource::fetch_stream()].
+#[derive(Debug)]
+pub(crate) struct Fetched {
+    pub size: u64,
+    pub sha256: String,
+}
+
+impl FromStr for RegistrySource {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.starts_with("http://") || s.starts_with("https://") {
+            Ok(RegistrySource::Http(s.trim_end_matches('/').to_string()))
+        } else if s.starts_with("file://") {
+            let url = Url::parse(s)?;
+            let path = url
+                .to_file_path()
+                .map_err(|_| anyhow!("Invalid file URL: {}", s))?;
+            Ok(RegistrySource::Local(path))
+        } else if s.contains("://") {
+            Err(anyhow!("Unsupported registry source: {}", s))
+        } else {
+            Ok(RegistrySource::Local(PathBuf::from(s)))
+        }
+    }
+}
+
+impl TryFrom<String> for RegistrySource {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<RegistrySource> for String {
+    fn from(source: RegistrySource) -> Self {
+        source.to_string()
+    }
+}
+
+impl fmt::Display for RegistrySource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RegistrySource::Http(base) => write!(f, "{}", base),
+            RegistrySource::Local(dir) => write!(f, "{}", dir.display()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_http_sources() {
+        assert_eq!(
+            "https://mirror.example.org/database/"
+                .parse::<RegistrySource>()
+                .unwrap(),
+            RegistrySource::Http(String::from("https://mirror.example.org/database"))
+        );
+        assert_eq!(
+            "http://localhost:8080".parse::<RegistrySource>().unwrap(),
+            RegistrySource::Http(String::from("http://localhost:8080"))
+        );
+    }
+
+    #[test]
+    fn parses_local_sources() {
+        assert_eq!(
+            "file:///srv/nix-data/database"
+                .parse::<RegistrySource>()
+                .unwrap(),
+            RegistrySource::Local(PathBuf::from("/srv/nix-data/database"))
+        );
+        assert_eq!(
+            "/srv/nix-data/database".parse::<RegistrySource>().unwrap(),
+            RegistrySource::Local(PathBuf::from("/srv/nix-data/database"))
+        );
+        assert_eq!(
+            "mirror/database".parse::<RegistrySource>().unwrap(),
+            RegistrySource::Local(PathBuf::from("mirror/database"))
+        );
+    }
+
+    #[test]
+    fn rejects_unsupported_sources() {
+        assert!(
+            "ftp://mirror.example.org"
+                .parse::<RegistrySource>()
+                .is_err()
+        );
+        assert!("s3://bucket/database".parse::<RegistrySource>().is_err());
+        assert!(
+            "file://remotehost/database"
+                .parse::<RegistrySource>()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn roundtrips_through_config() {
+        for source in [DEFAULT_DATABASE, DEFAULT_REGISTRY, "/srv/nix-data/database"] {
+            let parsed: RegistrySource = source.parse().unwrap();
+            assert_eq!(parsed.to_string(), source);
+            let json = serde_json::to_string(&parsed).unwrap();
+            assert_eq!(json, format!("\"{}\"", source));
+            assert_eq!(
+                serde_json::from_str::<RegistrySource>(&json).unwrap(),
+                parsed
+            );
+        }
+        assert!(serde_json::from_str::<RegistrySource>("\"ftp://example.org\"").is_err());
+    }
+
+    #[test]
+    fn joins_paths() {
+        let source: RegistrySource = "https://mirror.example.org/database/".parse().unwrap();
+        assert_eq!(
+            source.url("nixos-25.11/nixpkgs.ver"),
+            "https://mirror.example.org/database/nixos-25.11/nixpkgs.ver"
+        );
+        let source: RegistrySource = "/srv/database".parse().unwrap();
+        assert_eq!(
+            source.url("nixos-25.11/nixpkgs.ver"),
+            "/srv/database/nixos-25.11/nixpkgs.ver"
+        );
+    }
+}
@@ -0,0 +1,253 @@
+use crate::{
+    cache::{
+        CacheStore, Cached, channel, flakes, homemanager, nixos, nonnixos,
+        profile::getprofilepkgs_versioned,
+    },
+    config::configfile::UserPkgType,
+};
+use anyhow::Result;
+use log::debug;
+use std::{collections::HashMap, path::Path};
+
+use super::{NixosVersion, hostplatform};
+
+/// Marker file present on every NixOS system.
+const NIXOS_MARKER: &str = "/etc/NIXOS";
+/// Flake used by `nixos-rebuild` if none is given.
+const DEFAULT_FLAKE: &str = "/etc/nixos/flake.nix";
+/// Configuration used by `nixos-rebuild` without flakes.
+const DEFAULT_SYSTEMCONFIG: &str = "/etc/nixos/configuration.nix";
+
+/// How the packages of the system are managed.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum SystemType {
+    /// NixOS built from a flake, see [flakes].
+    NixosFlakes,
+    /// NixOS built from channels, see [channel].
+    NixosChannels,
+    /// Nix installed on another Linux distribution or macOS, see [nonnixos].
+    NonNixos,
+}
+
+/// Description of the running system, as returned by [detect_system()].
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct SystemInfo {
+    /// How the packages of the system are managed.
+    pub system: SystemType,
+    /// Version of NixOS, if it could be detected.
+    pub version: Option<NixosVersion>,
+    /// Nix platform of the system, such as `x86_64-linux`, see [hostplatform()].
+    pub platform: String,
+    /// How the user installs packages, or `None` if they have no profile.
+    pub userpkgs: Option<UserPkgType>,
+    /// Whether the user manages their home directory with Home Manager.
+    pub homemanager: bool,
+}
+
+/// Detects how packages are managed on the running system and by the user of `store`,
+/// so applications can pick the matching cache and listing functions.
+///
+/// A system is considered flake based if `flake` is set in the config or `/etc/nixos/flake.nix` exists.
+/// The user's package manager is told apart by the manifest of `~/.nix-profile`.
+pub fn detect_system(store: &CacheStore) -> SystemInfo {
+    let version = NixosVersion::detect();
+    let nixos = version.is_ok() || Path::new(NIXOS_MARKER).exists();
+    let system = systemtype(store, nixos);
+    let userpkgs = store.home().ok().and_then(userpkgtype);
+
+    let homemanager = homemanager::localoptions(store).is_some()
+        || store.home().is_ok_and(|home| {
+            home.join(".local/state/nix/profiles/home-manager").exists()
+                || home.join(".local/state/home-manager").exists()
+        })
+        || std::env::var("USER").is_ok_and(|user| {
+            Path::new("/nix/var/nix/profiles/per-user")
+                .join(user)
+                .join("home-manager")
+                .exists()
+        });
+
+    let info = SystemInfo {
+        system,
+        version: version.ok(),
+        platform: hostplatform(),
+        userpkgs,
+        homemanager,
+    };
+    debug!("Detected system: {:?}", info);
+    info
+}
+
+/// Tells how the packages of a system are managed, given whether it runs NixOS.
+fn systemtype(store: &CacheStore, nixos: bool) -> SystemType {
+    if !nixos {
+        SystemType::NonNixos
+    } else if store.config().flake.is_some() || Path::new(DEFAULT_FLAKE).exists() {
+        SystemType::NixosFlakes
+    } else {
+        SystemType::NixosChannels
+    }
+}
+
+/// Tells how the user with the home directory `home` installs packages, from the manifest of `~/.nix-profile`.
+fn userpkgtype(home: &Path) -> Option<UserPkgType> {
+    let profile = home.join(".nix-profile");
+    if profile.join("manifest.json").exists() {
+        Some(UserPkgType::Profile)
+    } else if profile.join("manifest.nix").exists() {
+        Some(UserPkgType::Env)
+    } else {
+        None
+    }
+}
+
+impl SystemInfo {
+    /// Whether the system runs NixOS.
+    pub fn nixos(&self) -> bool {
+        self.system != SystemType::NonNixos
+    }
+
+    /// Caches the database of the packages of the system:
+    /// [flakespkgs()](flakes::flakespkgs), [legacypkgs()](channel::legacypkgs) or [nixpkgs()](nonnixos::nixpkgs).
+    pub async fn systemdb(&self, store: &CacheStore) -> Result<Cached<String>> {
+        match self.system {
+            SystemType::NixosFlakes => flakes::flakespkgs(store).await,
+            SystemType::NixosChannels => channel::legacypkgs(store).await,
+            SystemType::NonNixos => nonnixos::nixpkgs(store).await,
+        }
+    }
+
+    /// Caches the database of the latest packages available to the system:
+    /// [nixospkgs()](nixos::nixospkgs) on NixOS or [nixpkgs()](nonnixos::nixpkgs) elsewhere.
+    pub async fn latestdb(&self, store: &CacheStore) -> Result<Cached<String>> {
+        if self.nixos() {
+            nixos::nixospkgs(store).await
+        } else {
+            nonnixos::nixpkgs(store).await
+        }
+    }
+
+    /// Lists the packages in `environment.systemPackages` of the system configuration with their version,
+    /// read from `systemconfig` in the config or `/etc/nixos/configuration.nix`.
+    /// Returns an empty list on systems other than NixOS.
+    pub async fn systempkgs(&self, store: &CacheStore) -> Result<HashMap<String, String>> {
+        let systemconfig = store
+            .config()
+            .systemconfig
+            .clone()
+            .unwrap_or_else(|| DEFAULT_SYSTEMCONFIG.to_string());
+        match self.system {
+            SystemType::NixosFlakes => flakes::getflakepkgs(store, &[&systemconfig]).await,
+            SystemType::NixosChannels => channel::getlegacypkgs(store, &[&systemconfig]).await,
+            SystemType::NonNixos => Ok(HashMap::new()),
+        }
+    }
+
+    /// Lists the packages the user installed with `nix profile` or `nix-env` with their version.
+    /// Returns an empty list if the user has no profile.
+    pub async fn userpkgs(&self, store: &CacheStore) -> Result<HashMap<String, String>> {
+        match self.userpkgs {
+            Some(UserPkgType::Profile) => getprofilepkgs_versioned(store).await,
+            Some(UserPkgType::Env) => channel::getenvpkgs(),
+            None => Ok(HashMap::new()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::configfile::NixDataConfig;
+    use std::fs;
+
+    fn store(root: &Path, config: NixDataConfig) -> Result<CacheStore> {
+        Ok(CacheStore::new(root.join("nix-data"))?
+            .with_config(config)
+            .with_home(root.join("home"))
+            .with_offline(true))
+    }
+
+    fn info(system: SystemType, userpkgs: Option<UserPkgType>) -> SystemInfo {
+        SystemInfo {
+            system,
+            version: None,
+            platform: String::from("x86_64-linux"),
+            userpkgs,
+            homemanager: false,
+        }
+    }
+
+    #[test]
+    fn systemtype_follows_config() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let flake = store(
+            dir.path(),
+            NixDataConfig {
+                flake: Some(String::from("/srv/nixos/flake.nix")),
+                ..Default::default()
+            },
+        )?;
+        assert_eq!(systemtype(&flake, true), SystemType::NixosFlakes);
+        assert_eq!(systemtype(&flake, false), SystemType::NonNixos);
+        let channels = store(dir.path(), NixDataConfig::default())?;
+        assert_eq!(systemtype(&channels, false), SystemType::NonNixos);
+        if !Path::new(DEFAULT_FLAKE).exists() {
+            assert_eq!(systemtype(&channels, true), SystemType::NixosChannels);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn userpkgtype_follows_profile_manifest() -> Result<()> {
+        let home = tempfile::tempdir()?;
+        let profile = home.path().join(".nix-profile");
+        assert_eq!(userpkgtype(home.path()), None);
+        fs::create_dir(&profile)?;
+        assert_eq!(userpkgtype(home.path()), None);
+        fs::write(profile.join("manifest.nix"), "[ ]")?;
+        assert_eq!(userpkgtype(home.path()), Some(UserPkgType::Env));
+        fs::write(profile.join("manifest.json"), "{}")?;
+        assert_eq!(userpkgtype(home.path()), Some(UserPkgType::Profile));
+        Ok(())
+    }
+
+    #[test]
+    fn detect_system_reads_home() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let store = store(dir.path(), NixDataConfig::default())?;
+        let home = dir.path().join("home");
+        fs::create_dir_all(home.join(".nix-profile"))?;
+        fs::write(home.join(".nix-profile/manifest.json"), "{}")?;
+        fs::create_dir_all(home.join(".local/state/home-manager"))?;
+        let info = detect_system(&store);
+        assert_eq!(info.userpkgs, Some(UserPkgType::Profile));
+        assert!(info.homemanager);
+        assert_eq!(info.platform, hostplatform());
+        assert_eq!(info.nixos(), info.system != SystemType::NonNixos);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn routes_to_the_matching_cache() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let store = store(dir.path(), NixDataConfig::default())?;
+        let nonnixos = info(SystemType::NonNixos, None);
+        assert!(!nonnixos.nixos());
+        for result in [
+            nonnixos.systemdb(&store).await,
+            nonnixos.latestdb(&store).await,
+        ] {
+            let err = result.unwrap_err();
+            assert!(err.to_string().contains("nonnixospkgs.db"), "{:#}", err);
+        }
+        let channels = info(SystemType::NixosChannels, None);
+        assert!(channels.nixos());
+        let err = channels.latestdb(&store).await.unwrap_err();
+        assert!(err.to_string().contains("nixospkgs"), "{:#}", err);
+        assert!(!err.to_string().contains("nonnixospkgs"), "{:#}", err);
+
+        assert!(nonnixos.systempkgs(&store).await?.is_empty());
+        assert!(nonnixos.userpkgs(&store).await?.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,10 @@
+/// Detect how packages are managed on the running system
+pub mod detect;
+/// Detect the Nix platform of the running system
+pub mod platform;
+/// Parse the version of the running NixOS system
+pub mod version;
+
+pub use detect::{SystemInfo, SystemType, detect_system};
+pub use platform::hostplatform;
+pub use version::NixosVersion;
@@ -0,0 +1,18 @@
+use std::env::consts;
+
+/// Returns the Nix platform of the running system, such as `x86_64-linux` or `aarch64-darwin`,
+/// as `builtins.currentSystem` would report it.
+pub fn hostplatform() -> String {
+    let cpu = match consts::ARCH {
+        "x86" => "i686",
+        "arm" => "armv7l",
+        "powerpc64" if cfg!(target_endian = "little") => "powerpc64le",
+        "mips64" if cfg!(target_endian = "little") => "mips64el",
+        arch => arch,
+    };
+    let kernel = match consts::OS {
+        "macos" => "darwin",
+        os => os,
+    };
+    format!("{}-{}", cpu, kernel)
+}
@@ -0,0 +1,270 @@
+use anyhow::{Context, Result, anyhow};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, fmt, fs, path::Path, process::Command, str::FromStr};
+
+/// Version file of the running system, containing a version such as `25.11.20251020.abcdef0`.
+pub const CURRENT_SYSTEM_VERSION: &str = "/run/current-system/nixos-version";
+/// `os-release` file, with the version in `BUILD_ID` on NixOS.
+pub const OS_RELEASE: &str = "/etc/os-release";
+
+/// Version of a NixOS system, such as `25.11.20251020.abcdef0 (Xantusia)`.
+///
+/// Stable releases have versions like `25.11.20251020.abcdef0`, older ones `21.11.335130.386234e2a61`
+/// with a counter in place of the date. Systems following nixos-unstable run pre-releases of the next release,
+/// like `26.05pre20251020.abcdef0`.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct NixosVersion {
+    /// Full version, such as `25.11.20251020.abcdef0`.
+    pub version: String,
+    /// Release, such as `25.11`.
+    pub release: String,
+    /// Whether the system runs a pre-release of [release](NixosVersion::release), as on nixos-unstable.
+    pub prerelease: bool,
+    /// Date of the nixpkgs revision as `YYYYMMDD`, if the version contains one.
+    pub date: Option<String>,
+    /// Commit hash of the nixpkgs revision.
+    /// It is abbreviated unless read with [fromcommand()](NixosVersion::fromcommand).
+    pub revision: Option<String>,
+    /// Codename of the release, such as `Xantusia`.
+    pub codename: Option<String>,
+}
+
+impl NixosVersion {
+    /// Detects the version of the running system, from `nixos-version --json`, [CURRENT_SYSTEM_VERSION]
+    /// or [OS_RELEASE], whichever is available first.
+    /// Returns an error on systems other than NixOS.
+    pub fn detect() -> Result<Self> {
+        let mut errors = Vec::new();
+        for detect in [
+            NixosVersion::fromcommand,
+            || NixosVersion::fromfile(CURRENT_SYSTEM_VERSION),
+            || NixosVersion::fromosrelease(OS_RELEASE),
+        ] {
+            match detect() {
+                Ok(version) => return Ok(version),
+                Err(e) => errors.push(format!("{:#}", e)),
+            }
+        }
+        Err(anyhow!(
+            "Could not detect the NixOS version: {}",
+            errors.join("; ")
+        ))
+    }
+
+    /// Reads the version from the output of `nixos-version --json`, the only source of the full nixpkgs revision.
+    pub fn fromcommand() -> Result<Self> {
+        let output = Command::new("nixos-version")
+            .arg("--json")
+            .output()
+            .context("Failed to run nixos-version")?;
+        if !output.status.success() {
+            return Err(anyhow!("nixos-version failed with {}", output.status));
+        }
+        let json: HashMap<String, String> = serde_json::from_slice(&output.stdout)
+            .context("Invalid output of nixos-version --json")?;
+        let mut version: NixosVersion = json
+            .get("nixosVersion")
+            .context("No NixOS version found")?
+            .parse()?;
+        if let Some(revision) = json.get("nixpkgsRevision") {
+            version.revision = Some(revision.clone());
+        }
+        if let Some(codename) = json.get("codeName") {
+            version.codename = Some(codename.clone());
+        }
+        Ok(version)
+    }
+
+    /// Reads the version from a file containing only the version, such as [CURRENT_SYSTEM_VERSION].
+    pub fn fromfile(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?
+            .parse()
+    }
+
+    /// Reads the version from an `os-release` file such as [OS_RELEASE],
+    /// failing if it doesn't describe NixOS.
+    pub fn fromosrelease(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        let fields = content
+            .lines()
+            .filter_map(|line| line.split_once('='))
+            .map(|(key, value)| (key.trim(), value.trim().trim_matches('"')))
+            .collect::<HashMap<_, _>>();
+        if fields.get("ID") != Some(&"nixos") {
+            return Err(anyhow!("{} does not describe NixOS", path.display()));
+        }
+        let mut version: NixosVersion = fields
+            .get("BUILD_ID")
+            .or_else(|| fields.get("VERSION_ID"))
+            .context("No NixOS version found")?
+            .parse()?;
+        // `VERSION` is like `25.11 (Xantusia)`, `VERSION_CODENAME` only has it in lowercase
+        version.codename = fields
+            .get("VERSION")
+            .and_then(|full| codename(full))
+            .or_else(|| fields.get("VERSION_CODENAME").map(|name| name.to_string()))
+            .filter(|name| !name.is_empty());
+        Ok(version)
+    }
+
+    /// Release channel the system follows, such as `25.11`, or `unstable` for pre-releases.
+    pub fn channel(&self) -> &str {
+        if self.prerelease {
+            "unstable"
+        } else {
+            &self.release
+        }
+    }
+}
+
+/// Parses a version as printed by `nixos-version`, such as `25.11.20251020.abcdef0 (Xantusia)`.
+impl FromStr for NixosVersion {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        let version = s.split_whitespace().next().unwrap_or_default();
+        let invalid = || anyhow!("Invalid NixOS version: {:?}", s);
+
+        // Release is the leading `YY.MM`
+        let (year, rest) = version.split_once('.').ok_or_else(invalid)?;
+        let monthlen = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let (month, rest) = rest.split_at(monthlen);
+        if year.is_empty() || month.is_empty() || !year.chars().all(|c| c.is_ascii_digit()) {
+            return Err(invalid());
+        }
+        let release = format!("{}.{}", year, month);
+
+        let (prerelease, suffix) = match rest.strip_prefix("pre") {
+            Some(suffix) => (true, suffix),
+            None => (false, rest.strip_prefix('.').unwrap_or(rest)),
+        };
+        let date = suffix
+            .split('.')
+            .next()
+            .filter(|part| part.len() == 8 && part.chars().all(|c| c.is_ascii_digit()))
+            .map(str::to_string);
+        let revision = suffix
+            .rsplit_once('.')
+            .map(|(_, revision)| revision)
+            .filter(|part| part.len() >= 7 && part.chars().all(|c| c.is_ascii_hexdigit()))
+            .map(str::to_string);
+
+        Ok(NixosVersion {
+            version: version.to_string(),
+            release,
+            prerelease,
+            date,
+            revision,
+            codename: codename(s),
+        })
+    }
+}
+
+impl fmt::Display for NixosVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.version)
+    }
+}
+
+/// Returns the codename in parentheses after a version, such as `Xantusia` in `25.11 (Xantusia)`.
+fn codename(version: &str) -> Option<String> {
+    let (_, rest) = version.split_once('(')?;
+    let (name, _) = rest.split_once(')')?;
+    Some(name.trim().to_string()).filter(|name| !name.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_stable_version() {
+        let version: NixosVersion = "25.11.20251020.abcdef0 (Xantusia)".parse().unwrap();
+        assert_eq!(
+            version,
+            NixosVersion {
+                version: String::from("25.11.20251020.abcdef0"),
+                release: String::from("25.11"),
+                prerelease: false,
+                date: Some(String::from("20251020")),
+                revision: Some(String::from("abcdef0")),
+                codename: Some(String::from("Xantusia")),
+            }
+        );
+        assert_eq!(version.channel(), "25.11");
+        assert_eq!(version.to_string(), "25.11.20251020.abcdef0");
+    }
+
+    #[test]
+    fn parses_prerelease_version() {
+        let version: NixosVersion = "26.05pre20251020.abcdef0".parse().unwrap();
+        assert_eq!(version.release, "26.05");
+        assert!(version.prerelease);
+        assert_eq!(version.date.as_deref(), Some("20251020"));
+        assert_eq!(version.revision.as_deref(), Some("abcdef0"));
+        assert_eq!(version.codename, None);
+        assert_eq!(version.channel(), "unstable");
+    }
+
+    #[test]
+    fn parses_counter_version() {
+        let version: NixosVersion = "21.11.335130.386234e2a61".parse().unwrap();
+        assert_eq!(version.release, "21.11");
+        assert!(!version.prerelease);
+        assert_eq!(version.date, None);
+        assert_eq!(version.revision.as_deref(), Some("386234e2a61"));
+    }
+
+    #[test]
+    fn parses_release_only() {
+        let version: NixosVersion = "25.11".parse().unwrap();
+        assert_eq!(version.release, "25.11");
+        assert_eq!(version.date, None);
+        assert_eq!(version.revision, None);
+    }
+
+    #[test]
+    fn trims_whitespace() {
+        let version: NixosVersion = "  25.11.20251020.abcdef0 (Xantusia)\n".parse().unwrap();
+        assert_eq!(version.version, "25.11.20251020.abcdef0");
+        assert_eq!(version.revision.as_deref(), Some("abcdef0"));
+        assert_eq!(version.codename.as_deref(), Some("Xantusia"));
+        let version: NixosVersion = "\t26.05pre20251020.abcdef0\n".parse().unwrap();
+        assert_eq!(version.version, "26.05pre20251020.abcdef0");
+    }
+
+    #[test]
+    fn rejects_channel_names() {
+        for invalid in ["unstable", "nixos-unstable", "", "   ", ".11", "x.11"] {
+            assert!(
+                invalid.parse::<NixosVersion>().is_err(),
+                "{:?} should not parse",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn reads_os_release() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("os-release");
+        fs::write(
+            &path,
+            "ID=nixos\nVERSION=\"25.11 (Xantusia)\"\nVERSION_CODENAME=xantusia\nBUILD_ID=\"25.11.20251020.abcdef0\"\n",
+        )?;
+        let version = NixosVersion::fromosrelease(&path)?;
+        assert_eq!(version.version, "25.11.20251020.abcdef0");
+        assert_eq!(version.codename.as_deref(), Some("Xantusia"));
+
+        fs::write(&path, "ID=debian\nVERSION_ID=\"12\"\n")?;
+        assert!(NixosVersion::fromosrelease(&path).is_err());
+        Ok(())
+    }
+}
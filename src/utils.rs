@@ -1,6 +1,11 @@
-use crate::HOME;
-use anyhow::{anyhow, Context, Result};
-use reqwest::Client;
+use crate::{
+    cache::{
+        CacheStore,
+        metadata::{self, ArtifactKind},
+    },
+    system::NixosVersion,
+};
+use anyhow::{Context, Result};
 use std::{
     fs::{self, File},
     io::{Read, Write},
@@ -8,11 +13,13 @@ use std::{
 };
 
 /// Refreshes desktop icons for applications installed with Nix
-pub fn refreshicons() -> Result<()> {
-    let desktoppath = &format!("{}/.local/share/applications", &*HOME);
-    let iconpath = &format!("{}/.local/share/icons/nixrefresh.png", &*HOME);
+/// in the home directory of the given store.
+pub fn refreshicons(store: &CacheStore) -> Result<()> {
+    let home = store.home()?.display();
+    let desktoppath = &format!("{}/.local/share/applications", home);
+    let iconpath = &format!("{}/.local/share/icons/nixrefresh.png", home);
     fs::create_dir_all(desktoppath)?;
-    fs::create_dir_all(format!("{}/.local/share/icons", &*HOME))?;
+    fs::create_dir_all(format!("{}/.local/share/icons", home))?;
 
     // Clean up old files
     for filename in (fs::read_dir(desktoppath)?).flatten() {
@@ -23,8 +30,7 @@ pub fn refreshicons() -> Result<()> {
         }
     }
 
-    for filename in (fs::read_dir(format!("{}/.nix-profile/share/applications", &*HOME))?).flatten()
-    {
+    for filename in (fs::read_dir(format!("{}/.nix-profile/share/applications", home))?).flatten() {
         let filepath = filename.path().to_str().context("file path")?.to_string();
         let localpath = format!(
             "{}/{}",
@@ -59,51 +65,173 @@ pub fn refreshicons() -> Result<()> {
     Ok(())
 }
 
-pub async fn get_full_ver() -> Result<String> {
+/// Returns the latest nixpkgs revision published for the system release, such as `25.11.1234.abcdef`.
+/// If trusted keys are configured, the revision has to match the signed manifest of its directory.
+/// In offline mode the revision recorded by the last refresh is returned instead.
+pub async fn get_full_ver(store: &CacheStore) -> Result<String> {
+    if store.offline() {
+        let metadata = metadata::read(store)?;
+        return [ArtifactKind::NixosPkgs, ArtifactKind::Nixpkgs]
+            .into_iter()
+            .find_map(|kind| metadata.artifact(kind)?.revision.clone())
+            .ok_or_else(|| store.notcached("nixpkgs version"));
+    }
+
     // returns full nixos version of system 25.11.asdasd.asd
-    let short_version = std::process::Command::new("sh")
-        .arg("-c")
-        .arg(r"nixos-version | grep -oP '^\d+\.\d+'")
-        .output()
-        .expect("failed to get nixos-version");
-    let v = String::from_utf8(short_version.stdout)?;
-    let url = format!(
-        "https://raw.githubusercontent.com/xinux-org/database/refs/heads/main/nixos-{}/nixpkgs.ver",
-        v.trim()
-    );
-
-    // Fallback url
-    let url_unstable = "https://raw.githubusercontent.com/xinux-org/database/refs/heads/main/nixos-unstable/nixpkgs.ver";
-
-    let client = Client::new();
-
-    let primary = client
-        .get(url)
-        .header("User-Agent", "rust-reqwest")
-        .send()
-        .await;
-
-    match primary {
-        Ok(resp) if resp.status().is_success() => {
-            return Ok(resp.text().await?);
-        }
-        _ => {
-            eprintln!("Primary nixpkgs.ver fetch failed, trying unstable...");
-        }
+    let version = NixosVersion::detect()?;
+    let source = store.database();
+    let client = store.http()?;
+    let keys = store.trustedkeys()?;
+
+    // A release that fails verification must not fall back to unstable
+    if let Some(ver) = source
+        .fetch_revision(
+            &client,
+            &format!("nixos-{}/nixpkgs.ver", version.release),
+            &keys,
+        )
+        .await?
+    {
+        return Ok(ver);
     }
+    eprintln!("Primary nixpkgs.ver fetch failed, trying unstable...");
 
     // Fallback: nixos-unstable
-    let fallback = client
-        .get(url_unstable)
-        .header("User-Agent", "rust-reqwest")
-        .send()
-        .await?;
-
-    if !fallback.status().is_success() {
-        return Err(anyhow!(
-            "Failed to fetch version from both release and unstable channel versions"
-        ));
+    source
+        .fetch_revision(&client, "nixos-unstable/nixpkgs.ver", &keys)
+        .await?
+        .context("Failed to fetch version from both release and unstable channel versions")
+}
+
+/// Turns free text into an FTS5 query matching every word as a prefix.
+/// Returns `None` if `query` contains no words.
+pub(crate) fn ftsquery(query: &str) -> Option<String> {
+    let terms = query
+        .split(|c: char| c.is_whitespace() || c == '.')
+        .filter(|term| !term.is_empty())
+        .map(|term| format!("\"{}\"*", term.replace('"', "\"\"")))
+        .collect::<Vec<_>>();
+    (!terms.is_empty()).then(|| terms.join(" "))
+}
+
+/// Returns the number of characters to insert, delete, replace or swap with their neighbour
+/// to turn `a` into `b`.
+pub(crate) fn editdistance(a: &str, b: &str) -> usize {
+    let a = a.chars().collect::<Vec<_>>();
+    let b = b.chars().collect::<Vec<_>>();
+    // Rows of the distance matrix for the previous two and the current character of `a`
+    let mut prev2 = vec![0; b.len() + 1];
+    let mut prev = (0..=b.len()).collect::<Vec<_>>();
+    let mut cur = vec![0; b.len() + 1];
+    for i in 1..=a.len() {
+        cur[0] = i;
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            cur[j] = (prev[j] + 1).min(cur[j - 1] + 1).min(prev[j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                cur[j] = cur[j].min(prev2[j - 2] + 1);
+            }
+        }
+        std::mem::swap(&mut prev2, &mut prev);
+        std::mem::swap(&mut prev, &mut cur);
     }
+    prev[b.len()]
+}
 
-    Ok(fallback.text().await?)
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn editdistance_counts_edits() {
+        assert_eq!(editdistance("firefox", "firefox"), 0);
+        assert_eq!(editdistance("", ""), 0);
+        assert_eq!(editdistance("", "vim"), 3);
+        assert_eq!(editdistance("vim", ""), 3);
+        assert_eq!(editdistance("vim", "nvim"), 1);
+        assert_eq!(editdistance("neovim", "nevim"), 1);
+        assert_eq!(editdistance("gimp", "gump"), 1);
+        assert_eq!(editdistance("kitten", "sitting"), 3);
+    }
+
+    #[test]
+    fn editdistance_counts_transpositions_once() {
+        assert_eq!(editdistance("firefox", "firfeox"), 1);
+        assert_eq!(editdistance("ab", "ba"), 1);
+        assert_eq!(editdistance("ca", "abc"), 3);
+    }
+
+    #[test]
+    fn editdistance_counts_characters() {
+        assert_eq!(editdistance("café", "cafe"), 1);
+        assert_eq!(editdistance("日本語", "日本"), 1);
+        assert_eq!(editdistance("äö", "öä"), 1);
+    }
+
+    #[test]
+    fn ftsquery_matches_words_as_prefixes() {
+        assert_eq!(
+            ftsquery("python3 requests").as_deref(),
+            Some("\"python3\"* \"requests\"*")
+        );
+        assert_eq!(
+            ftsquery("  python3Packages.requests\t").as_deref(),
+            Some("\"python3Packages\"* \"requests\"*")
+        );
+    }
+
+    #[test]
+    fn ftsquery_escapes_syntax() {
+        assert_eq!(
+            ftsquery("say \"hi\"").as_deref(),
+            Some("\"say\"* \"\"\"hi\"\"\"*")
+        );
+        assert_eq!(ftsquery("gtk*").as_deref(), Some("\"gtk*\"*"));
+        assert_eq!(ftsquery("-vim").as_deref(), Some("\"-vim\"*"));
+        assert_eq!(
+            ftsquery("vim NEAR emacs").as_deref(),
+            Some("\"vim\"* \"NEAR\"* \"emacs\"*")
+        );
+        assert_eq!(
+            ftsquery("a AND b OR NOT (c)").as_deref(),
+            Some("\"a\"* \"AND\"* \"b\"* \"OR\"* \"NOT\"* \"(c)\"*")
+        );
+    }
+
+    #[test]
+    fn ftsquery_rejects_empty_queries() {
+        assert_eq!(ftsquery(""), None);
+        assert_eq!(ftsquery(" \t\n"), None);
+        assert_eq!(ftsquery(" . .."), None);
+    }
+
+    #[tokio::test]
+    async fn ftsquery_is_valid_fts5() -> Result<()> {
+        use sqlx::{Connection, sqlite::SqliteConnection};
+
+        let mut conn = SqliteConnection::connect("sqlite::memory:").await?;
+        sqlx::query("CREATE VIRTUAL TABLE pkgs USING fts5(attribute, description)")
+            .execute(&mut conn)
+            .await?;
+        sqlx::query("INSERT INTO pkgs VALUES ('vim', 'Vi IMproved, NEAR \"perfect\" editor')")
+            .execute(&mut conn)
+            .await?;
+        for (query, matches) in [
+            ("vi", 1),
+            ("\"perfect", 1),
+            ("NEAR", 1),
+            ("-vim", 1),
+            ("vim*", 1),
+            ("a AND b OR NOT (c)", 0),
+            ("description:vim", 0),
+        ] {
+            let fts = ftsquery(query).unwrap();
+            let (count,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM pkgs WHERE pkgs MATCH $1")
+                .bind(&fts)
+                .fetch_one(&mut conn)
+                .await?;
+            assert_eq!(count, matches, "{:?} as {:?}", query, fts);
+        }
+        Ok(())
+    }
 }